@@ -0,0 +1,489 @@
+//! Async version of the channel for use with tokio.
+//!
+//! `AsyncChannel` speaks the same protocol as `channel::Channel` (see that
+//! module for the frame layout) over an `AsyncSerialPort`, so the controller
+//! can talk to the station while serving other tasks in the same runtime.
+//! Frames are built and checked by the same code as the blocking channel,
+//! CRC and authentication settings included. Windowed transfers are only
+//! offered by the blocking channel.
+//!
+//! Reads are bounded by a per frame timeout instead of VTIME.
+//!
+//! Only available with the `async` feature.
+use crate::async_serialport::AsyncSerialPort;
+use crate::auth;
+use crate::channel::{
+    check_body, frame_overhead, heartbeat_payload, seal_frame, ControlType, Error, ErrorKind,
+    Event, EventKind, Result, FRAME_HEADER_SIZE, FRAME_SIZE_MAX, FRAME_START, FRAME_TYPE_CTRL,
+    FRAME_TYPE_DATA, FRAME_TYPE_EVENT, FRAME_TYPE_REQUEST, FRAME_TYPE_RESPONSE, FRAME_TYPE_SEQ_ACK,
+};
+use crate::crc;
+use crate::log;
+use crate::serialport;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Instant};
+
+/// How long to wait on a frame before giving up
+const FRAME_TIMEOUT_DEFAULT: Duration = Duration::from_secs(1);
+
+pub struct AsyncChannel {
+    port: AsyncSerialPort,
+    num_attempts: u32,
+    frame_timeout: Duration,
+    crc: crc::Algorithm,
+    auth: Option<auth::Authenticator>,
+    auth_failures: u32,
+    events: Option<mpsc::UnboundedSender<Event>>,
+    next_id: u8,
+}
+
+impl AsyncChannel {
+    /// Create a new channel over an open async serial port
+    pub fn new(port: AsyncSerialPort, num_attempts: u32) -> AsyncChannel {
+        AsyncChannel {
+            port,
+            num_attempts,
+            frame_timeout: FRAME_TIMEOUT_DEFAULT,
+            crc: crc::Algorithm::default(),
+            auth: None,
+            auth_failures: 0,
+            events: None,
+            next_id: 0,
+        }
+    }
+
+    /// Set how long to wait on a frame (an ACK, a response)
+    /// before giving up.
+    pub fn set_timeout(&mut self, frame_timeout: Duration) {
+        self.frame_timeout = frame_timeout;
+    }
+
+    /// Set the CRC used to check frames. Must match the station.
+    pub fn set_crc(&mut self, crc: crc::Algorithm) {
+        self.crc = crc;
+    }
+
+    /// Authenticate frames using the pre-shared key. Stations
+    /// without the key will no longer be understood. Setting the key
+    /// already in use keeps the replay counters.
+    pub fn set_auth_key(&mut self, key: &[u8]) {
+        if !self.auth.as_ref().is_some_and(|a| a.has_key(key)) {
+            self.auth = Some(auth::Authenticator::new(key));
+        }
+    }
+
+    /// Number of frames that have failed authentication
+    pub fn auth_failures(&self) -> u32 {
+        self.auth_failures
+    }
+
+    /// Subscribe to events pushed by the station. Replaces any
+    /// previous subscriber.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.events = Some(tx);
+        rx
+    }
+
+    /// Largest amount of data that fits in a frame
+    fn data_size_max(&self) -> usize {
+        let payload_size_max = FRAME_SIZE_MAX - frame_overhead(self.crc);
+        match self.auth {
+            Some(_) => payload_size_max - auth::AUTH_OVERHEAD,
+            None => payload_size_max,
+        }
+    }
+
+    fn make_frame(&self, ftype: u8, payload: &[u8]) -> Vec<u8> {
+        seal_frame(self.crc, self.auth.as_ref(), ftype, payload)
+    }
+
+    /// Confirm that the station is up with a heartbeat.
+    pub async fn open(&mut self) -> Result<()> {
+        log::info("Attempting to establish a heartbeat..");
+        for _ in 0..self.num_attempts {
+            let heartbeat = heartbeat_payload(self.auth.as_ref());
+            self.port
+                .write(&self.make_frame(FRAME_TYPE_CTRL, &heartbeat))
+                .await?;
+            match self.read_frame(self.frame_timeout).await {
+                Ok((FRAME_TYPE_CTRL, control)) if control == heartbeat => {
+                    log::info("Heartbeat confirmed");
+                    return Ok(());
+                }
+                Ok((ftype, _)) => log::debug(&format!("Expected a heartbeat, got {:#x}", ftype)),
+                Err(e) if e.is_disconnect() => return Err(e),
+                Err(e) => log::debug(&format!("{:?}", e)),
+            }
+            self.port.flush()?;
+        }
+        log::error("Could not establish heartbeat");
+        Err(Error::new(
+            ErrorKind::NoHeartBeat,
+            "Failed to establish heartbeat",
+        ))
+    }
+
+    ///Send the payload over the channel.
+    pub async fn send(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > self.data_size_max() {
+            return Err(Error::new(
+                ErrorKind::Oversize,
+                "Payload larger than maximum payload size",
+            ));
+        }
+        for _ in 0..self.num_attempts {
+            // Resends are built fresh so they get a new replay counter
+            let frame = self.make_frame(FRAME_TYPE_DATA, payload);
+            self.port.write(&frame).await?;
+            log::debug(&format!("Sent bytes: {:?}", frame));
+            match self.read_frame(self.frame_timeout).await {
+                Ok((FRAME_TYPE_CTRL, control)) if control == [ControlType::Ack as u8] => {
+                    return Ok(())
+                }
+                Ok(_) => {
+                    self.port.flush()?;
+                    log::error("ACK not recieved");
+                }
+                Err(e) if e.is_disconnect() => return Err(e),
+                Err(e) => log::error(&format!("{:?}", e)),
+            }
+        }
+        Err(Error::new(
+            ErrorKind::MaxAttempts,
+            "Maximum number of resend attempts reached",
+        ))
+    }
+
+    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+        for _ in 0..self.num_attempts {
+            let frame = match self.read_frame(self.frame_timeout).await {
+                // Events may arrive ahead of the data
+                Ok((FRAME_TYPE_EVENT, payload)) => {
+                    self.handle_event(payload).await?;
+                    continue;
+                }
+                Ok(frame) => frame,
+                Err(e) if e.is_disconnect() => return Err(e),
+                Err(e) => {
+                    log::error(&format!("channel: {:?}", e));
+                    self.nack_error(&e).await?;
+                    continue;
+                }
+            };
+            match frame {
+                (FRAME_TYPE_DATA, payload) => {
+                    self.send_ctrl_frame(ControlType::Ack).await?;
+                    return Ok(payload);
+                }
+                _ => self.nack(ControlType::InvalidFrame).await?,
+            }
+        }
+        Err(Error::new(
+            ErrorKind::MaxAttempts,
+            "Maximum number of recieve attempts reached",
+        ))
+    }
+
+    /// Send a request and wait for the matching response.
+    ///
+    /// The timeout covers the whole exchange, including any resends
+    /// of the request.
+    pub async fn request(&mut self, payload: &[u8], within: Duration) -> Result<Vec<u8>> {
+        if payload.len() > self.data_size_max() - 1 {
+            return Err(Error::new(
+                ErrorKind::Oversize,
+                "Payload larger than maximum payload size",
+            ));
+        }
+        let deadline = Instant::now() + within;
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+
+        let mut body: Vec<u8> = Vec::with_capacity(payload.len() + 1);
+        body.push(id);
+        body.extend_from_slice(payload);
+        let frame = self.make_frame(FRAME_TYPE_REQUEST, &body);
+        self.port.write(&frame).await?;
+        log::debug(&format!("Sent request {}: {:?}", id, frame));
+
+        let mut n_attempts = 1;
+        let mut acked = false;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let wait = std::cmp::min(self.frame_timeout, deadline - now);
+            let resend = match self.read_frame(wait).await {
+                Ok((FRAME_TYPE_CTRL, ctrl)) if !acked => {
+                    acked = ctrl == [ControlType::Ack as u8];
+                    !acked
+                }
+                Ok((FRAME_TYPE_RESPONSE, response)) if !response.is_empty() => {
+                    self.send_ctrl_frame(ControlType::Ack).await?;
+                    if response[0] == id {
+                        return Ok(response[1..].to_vec());
+                    }
+                    log::warn(&format!(
+                        "Discarding stale response to request {}",
+                        response[0]
+                    ));
+                    false
+                }
+                Ok((FRAME_TYPE_EVENT, payload)) => {
+                    self.handle_event(payload).await?;
+                    false
+                }
+                Ok((ftype, _)) => {
+                    log::debug(&format!("Ignoring frame type {:#x} during request", ftype));
+                    false
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::SerialPort(serialport::ErrorKind::Timeout) => !acked,
+                    ErrorKind::SerialPort(_) => return Err(e),
+                    _ => {
+                        log::error(&format!("channel: {:?}", e));
+                        self.nack_error(&e).await?;
+                        false
+                    }
+                },
+            };
+            if resend {
+                if n_attempts >= self.num_attempts {
+                    return Err(Error::new(
+                        ErrorKind::MaxAttempts,
+                        "Maximum number of resend attempts reached",
+                    ));
+                }
+                let frame = self.make_frame(FRAME_TYPE_REQUEST, &body);
+                self.port.write(&frame).await?;
+                n_attempts += 1;
+            }
+        }
+        Err(Error::new(
+            ErrorKind::Timeout,
+            &format!("No response to request {} before the deadline", id),
+        ))
+    }
+
+    /// Listen for events pushed by the station for the given duration.
+    ///
+    /// Any other frame is ACKed so the station doesn't keep resending it,
+    /// and then dropped. Control and cumulative ACK frames are dropped
+    /// without a reply.
+    pub async fn listen(&mut self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            match self.read_frame(deadline - now).await {
+                Ok((FRAME_TYPE_EVENT, payload)) => self.handle_event(payload).await?,
+                Ok((ftype @ (FRAME_TYPE_CTRL | FRAME_TYPE_SEQ_ACK), _)) => {
+                    log::debug(&format!("Ignoring frame type {:#x} while listening", ftype));
+                }
+                Ok((ftype, _)) => {
+                    log::debug(&format!("Dropping frame type {:#x} while listening", ftype));
+                    self.send_ctrl_frame(ControlType::Ack).await?;
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::SerialPort(serialport::ErrorKind::Timeout) => (),
+                    ErrorKind::SerialPort(_) => return Err(e),
+                    _ => {
+                        log::error(&format!("channel: {:?}", e));
+                        self.nack_error(&e).await?;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Acknowledge an event frame and hand it off to the subscriber.
+    async fn handle_event(&mut self, payload: Vec<u8>) -> Result<()> {
+        if payload.is_empty() {
+            self.nack(ControlType::InvalidFrame).await?;
+            return Err(Error::new(
+                ErrorKind::InvalidFrame,
+                "Recieved event without a kind",
+            ));
+        }
+        self.send_ctrl_frame(ControlType::Ack).await?;
+        let event = Event {
+            kind: EventKind::from(payload[0]),
+            data: payload[1..].to_vec(),
+        };
+        log::debug(&format!("Recieved event: {:?}", event));
+        match &self.events {
+            Some(tx) => {
+                if tx.send(event).is_err() {
+                    log::warn("Event subscriber has gone away");
+                }
+            }
+            None => log::info(&format!("Unhandled event: {:?}", event.kind)),
+        }
+        Ok(())
+    }
+
+    async fn send_ctrl_frame(&self, ctype: ControlType) -> Result<()> {
+        self.port
+            .write(&self.make_frame(FRAME_TYPE_CTRL, &[ctype as u8]))
+            .await?;
+        Ok(())
+    }
+
+    /// Send a NACK and clear out whatever is left of the bad frame.
+    async fn nack(&self, ctype: ControlType) -> Result<()> {
+        // Input is cleared first so nothing sent in reply to the NACK is lost
+        self.port.flush_input()?;
+        self.send_ctrl_frame(ctype).await?;
+        Ok(())
+    }
+
+    /// Send the NACK matching a frame error, if there is one.
+    async fn nack_error(&self, e: &Error) -> Result<()> {
+        match e.kind() {
+            ErrorKind::Oversize => self.nack(ControlType::Oversize).await,
+            ErrorKind::InvalidFrame => self.nack(ControlType::InvalidFrame).await,
+            ErrorKind::CRCFail => self.nack(ControlType::CRCFail).await,
+            ErrorKind::AuthFail(_) => self.nack(ControlType::AuthFail).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a single frame off the port, giving up once `wait`
+    /// has passed.
+    async fn read_frame(&mut self, wait: Duration) -> Result<(u8, Vec<u8>)> {
+        let frame = match timeout(wait, self.read_raw_frame()).await {
+            Ok(frame) => frame?,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::SerialPort(serialport::ErrorKind::Timeout),
+                    "Timeout reached. No frame read",
+                ))
+            }
+        };
+        let (ftype, body) = frame;
+        match check_body(self.crc, self.auth.as_ref(), ftype, body) {
+            Ok(data) => Ok((ftype, data)),
+            Err(e) => {
+                if let ErrorKind::AuthFail(_) = e.kind() {
+                    self.auth_failures += 1;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Read the header and body of a frame. Any bytes before the
+    /// start byte are skipped.
+    async fn read_raw_frame(&self) -> Result<(u8, Vec<u8>)> {
+        let mut header: [u8; FRAME_HEADER_SIZE] = [0; FRAME_HEADER_SIZE];
+        while header[0] != FRAME_START {
+            self.port.read_exact(&mut header[..1]).await?;
+        }
+        self.port.read_exact(&mut header[1..]).await?;
+        let payload_size = header[2] as usize;
+        if payload_size > FRAME_SIZE_MAX - frame_overhead(self.crc) {
+            return Err(Error::new(ErrorKind::Oversize, "Frame oversize"));
+        }
+        // payload followed by the trailer
+        let mut body: Vec<u8> = vec![0; payload_size + self.crc.width() + 1];
+        self.port.read_exact(&mut body).await?;
+        Ok((header[1], body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{make_control_frame, make_frame};
+    use crate::serialport::{open_pty, SerialPortBuilder};
+    use nix::pty::PtyMaster;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    const CRC: crc::Algorithm = crc::Algorithm::Crc16Xmodem;
+
+    fn open_pty_channel() -> (PtyMaster, AsyncChannel) {
+        let (master, path) = open_pty();
+        // Not exclusive so nothing is written to the real lock directory
+        let port = SerialPortBuilder::new(&path).exclusive(false).build();
+        let mut channel = AsyncChannel::new(AsyncSerialPort::open(port).unwrap(), 5);
+        channel.set_timeout(Duration::from_millis(200));
+        (master, channel)
+    }
+
+    fn station_read(master: &mut PtyMaster) -> (u8, Vec<u8>) {
+        let mut header: [u8; 3] = [0; 3];
+        master.read_exact(&mut header).unwrap();
+        let mut body = vec![0; header[2] as usize + CRC.width() + 1];
+        master.read_exact(&mut body).unwrap();
+        body.truncate(header[2] as usize);
+        (header[1], body)
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    // The station pushes an event before answering
+    #[tokio::test]
+    async fn test_request_with_event() {
+        let (mut master, mut channel) = open_pty_channel();
+        let mut events = channel.subscribe();
+        let station = thread::spawn(move || {
+            let (ftype, request) = station_read(&mut master);
+            master
+                .write_all(&make_control_frame(CRC, ControlType::Ack))
+                .unwrap();
+            master
+                .write_all(&make_frame(CRC, FRAME_TYPE_EVENT, &[0x02]))
+                .unwrap();
+            let event_ack = station_read(&mut master);
+            let mut response = vec![request[0]];
+            response.extend_from_slice(&[7, 8, 9]);
+            master
+                .write_all(&make_frame(CRC, FRAME_TYPE_RESPONSE, &response))
+                .unwrap();
+            let response_ack = station_read(&mut master);
+            ((ftype, request), event_ack, response_ack, master)
+        });
+
+        let response = channel.request(&[0x02], Duration::from_secs(2)).await;
+        let (request, event_ack, response_ack, _master) = station.join().unwrap();
+        assert_eq!(vec![7, 8, 9], response.unwrap());
+        assert_eq!((FRAME_TYPE_REQUEST, vec![0, 0x02]), request);
+        assert_eq!(vec![ControlType::Ack as u8], event_ack.1);
+        assert_eq!(vec![ControlType::Ack as u8], response_ack.1);
+        assert_eq!(EventKind::Boot, events.recv().await.unwrap().kind);
+    }
+
+    #[tokio::test]
+    async fn test_open_heartbeat() {
+        let (mut master, mut channel) = open_pty_channel();
+        let station = thread::spawn(move || {
+            let heartbeat = station_read(&mut master);
+            master
+                .write_all(&make_control_frame(CRC, ControlType::Heartbeat))
+                .unwrap();
+            (heartbeat, master)
+        });
+        let opened = channel.open();
+        assert_send(&opened);
+        opened.await.unwrap();
+        let (heartbeat, _master) = station.join().unwrap();
+        assert_eq!(
+            (FRAME_TYPE_CTRL, vec![ControlType::Heartbeat as u8]),
+            heartbeat
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listen_timeout() {
+        let (_master, mut channel) = open_pty_channel();
+        let start = Instant::now();
+        channel.listen(Duration::from_millis(100)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
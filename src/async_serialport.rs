@@ -0,0 +1,144 @@
+//! Module providing a serial port for use with tokio.
+//!
+//! The port is configured and opened the same way as the blocking
+//! `SerialPort`, then switched to non-blocking IO and registered with
+//! the tokio reactor (epoll) through `AsyncFd`. Reads wait for the
+//! device to become readable instead of blocking on VTIME, so timeouts
+//! are left to the caller (e.g. `tokio::time::timeout`).
+//!
+//! Only available with the `async` feature.
+use crate::serialport::{Error, ErrorKind, Result, SerialPort};
+use nix::errno::Errno;
+use std::io;
+use std::os::unix::io::RawFd;
+use tokio::io::unix::AsyncFd;
+
+pub struct AsyncSerialPort {
+    // Declared first so it is deregistered before the port closes the fd
+    io: AsyncFd<RawFd>,
+    port: SerialPort,
+}
+
+fn from_io(e: io::Error) -> Error {
+    match e.raw_os_error() {
+        Some(n) => Errno::from_i32(n).into(),
+        None => Error::new(ErrorKind::Unknown, &e.to_string()),
+    }
+}
+
+fn to_io(e: Errno) -> io::Error {
+    io::Error::from_raw_os_error(e as i32)
+}
+
+impl AsyncSerialPort {
+    /// Open the serial port and register it with the tokio reactor.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn open(mut port: SerialPort) -> Result<AsyncSerialPort> {
+        port.open()?;
+        port.set_nonblocking()?;
+        let fd = match port.raw_fd() {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        let io = AsyncFd::new(fd).map_err(from_io)?;
+        Ok(AsyncSerialPort { io, port })
+    }
+
+    /// Read bytes from the serial port into the supplied array,
+    /// waiting until at least one is available.
+    pub async fn read(&self, arr: &mut [u8]) -> Result<usize> {
+        loop {
+            let mut guard = self.io.readable().await.map_err(from_io)?;
+            match guard.try_io(|fd| nix::unistd::read(*fd.get_ref(), arr).map_err(to_io)) {
+                // Readable with nothing to read means the other end hung up
+                Ok(Ok(0)) => return Err(Error::new(ErrorKind::Disconnected, "Device hung up")),
+                Ok(Ok(n)) => return Ok(n),
+                Ok(Err(e)) => return Err(from_io(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Fill the supplied array from the serial port.
+    pub async fn read_exact(&self, arr: &mut [u8]) -> Result<()> {
+        let mut nbytes = 0;
+        while nbytes < arr.len() {
+            nbytes += self.read(&mut arr[nbytes..]).await?;
+        }
+        Ok(())
+    }
+
+    /// Write all of the supplied bytes to the serial port.
+    pub async fn write(&self, arr: &[u8]) -> Result<usize> {
+        let mut nbytes = 0;
+        while nbytes < arr.len() {
+            let mut guard = self.io.writable().await.map_err(from_io)?;
+            match guard
+                .try_io(|fd| nix::unistd::write(*fd.get_ref(), &arr[nbytes..]).map_err(to_io))
+            {
+                Ok(Ok(n)) => nbytes += n,
+                Ok(Err(e)) => return Err(from_io(e)),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(nbytes)
+    }
+
+    /// Discard anything in the IO queues.
+    pub fn flush(&self) -> Result<()> {
+        self.port.flush()
+    }
+
+    /// Discard anything recieved but not yet read, leaving queued
+    /// output to go out.
+    pub fn flush_input(&self) -> Result<()> {
+        use nix::sys::termios::{tcflush, FlushArg};
+        tcflush(*self.io.get_ref(), FlushArg::TCIFLUSH)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        self.port.path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialport::{open_pty, SerialPortBuilder};
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    fn open_pty_port() -> (nix::pty::PtyMaster, AsyncSerialPort) {
+        let (master, path) = open_pty();
+        let port = SerialPortBuilder::new(&path).exclusive(false).build();
+        (master, AsyncSerialPort::open(port).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_read_write() {
+        let (mut master, port) = open_pty_port();
+        port.write(&[1, 2, 3]).await.unwrap();
+        let mut sent = [0; 3];
+        master.read_exact(&mut sent).unwrap();
+        assert_eq!([1, 2, 3], sent);
+
+        // Nothing there yet, the read has to wait on the reactor
+        let station = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            master.write_all(&[4, 5, 6, 7]).unwrap();
+            master
+        });
+        let mut buf = [0; 4];
+        port.read_exact(&mut buf).await.unwrap();
+        assert_eq!([4, 5, 6, 7], buf);
+        drop(station.join().unwrap());
+
+        let mut buf = [0; 1];
+        assert!(matches!(
+            port.read(&mut buf).await.unwrap_err().kind(),
+            ErrorKind::Disconnected
+        ));
+    }
+}
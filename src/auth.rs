@@ -0,0 +1,205 @@
+//! This module provides optional authentication of frames sent over the
+//! channel using a pre-shared key.
+//!
+//! Authenticated payloads are wrapped as:
+//!
+//! [ Counter (8 bytes) ][ Data ... ][ Tag (8 bytes) ]
+//!
+//! Counter - a little endian replay counter. The sender bumps it for every
+//!           frame it seals, resends included, and the receiver only
+//!           accepts counters larger than the last one it accepted. The
+//!           counter is seeded from the clock in microseconds so it keeps
+//!           increasing across restarts.
+//!
+//! Tag - HMAC-SHA256 over the frame type, the counter and the data,
+//!       truncated to the first 8 bytes.
+//!
+//! The receiving side starts out accepting any counter, so on its own a
+//! restart would let frames captured earlier be replayed. To close that
+//! window the heartbeat opening a channel carries a random challenge that
+//! the station has to echo back in a sealed reply. Only a fresh reply can
+//! echo it, and accepting it moves the receive counter up to the
+//! station's current one.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 8;
+/// Bytes added to every authenticated payload
+pub const AUTH_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+/// Size of the heartbeat challenge
+pub const CHALLENGE_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AuthError {
+    /// Payload is too short to hold the counter and tag
+    Truncated,
+    /// Tag does not match the frame contents
+    BadTag,
+    /// Counter was not larger than the last accepted counter
+    Replay,
+}
+
+pub struct Authenticator {
+    key: Vec<u8>,
+    tx_counter: AtomicU64,
+    rx_counter: AtomicU64,
+}
+
+impl Authenticator {
+    /// Create a new authenticator using the pre-shared key
+    pub fn new(key: &[u8]) -> Authenticator {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        Authenticator {
+            key: key.to_vec(),
+            tx_counter: AtomicU64::new(now),
+            rx_counter: AtomicU64::new(0),
+        }
+    }
+
+    /// True if this authenticator uses key
+    pub fn has_key(&self, key: &[u8]) -> bool {
+        self.key == key
+    }
+
+    fn mac(&self, ftype: u8, counter: &[u8], data: &[u8]) -> HmacSha256 {
+        // HMAC accepts keys of any length
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC key");
+        mac.update(&[ftype]);
+        mac.update(counter);
+        mac.update(data);
+        mac
+    }
+
+    /// Wrap the data with a fresh counter and tag.
+    pub fn seal(&self, ftype: u8, data: &[u8]) -> Vec<u8> {
+        let counter = self
+            .tx_counter
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+
+        let counter = counter.to_le_bytes();
+        let tag = self.mac(ftype, &counter, data).finalize().into_bytes();
+        let mut payload: Vec<u8> = Vec::with_capacity(data.len() + AUTH_OVERHEAD);
+        payload.extend_from_slice(&counter);
+        payload.extend_from_slice(data);
+        payload.extend_from_slice(&tag[..TAG_SIZE]);
+        payload
+    }
+
+    /// Check the counter and tag of an authenticated payload,
+    /// returning the data.
+    pub fn open(&self, ftype: u8, payload: &[u8]) -> Result<Vec<u8>, AuthError> {
+        if payload.len() < AUTH_OVERHEAD {
+            return Err(AuthError::Truncated);
+        }
+        let (counter, rest) = payload.split_at(COUNTER_SIZE);
+        let (data, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        if self
+            .mac(ftype, counter, data)
+            .verify_truncated_left(tag)
+            .is_err()
+        {
+            return Err(AuthError::BadTag);
+        }
+
+        let mut bytes: [u8; COUNTER_SIZE] = [0; COUNTER_SIZE];
+        bytes.copy_from_slice(counter);
+        let counter = u64::from_le_bytes(bytes);
+        if counter <= self.rx_counter.load(Ordering::Relaxed) {
+            return Err(AuthError::Replay);
+        }
+        self.rx_counter.store(counter, Ordering::Relaxed);
+        Ok(data.to_vec())
+    }
+}
+
+/// A random challenge for the station to echo back. Falls back to the
+/// clock if /dev/urandom can't be read, which still never repeats.
+pub fn challenge() -> [u8; CHALLENGE_SIZE] {
+    let mut bytes: [u8; CHALLENGE_SIZE] = [0; CHALLENGE_SIZE];
+    let random = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if random.is_err() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        bytes = now.to_le_bytes();
+    }
+    bytes
+}
+
+/// Parse a key written as a string of hex digits
+pub fn parse_key(s: &str) -> Result<Vec<u8>, String> {
+    let s = s.trim();
+    if s.is_empty() || s.len() % 2 != 0 {
+        return Err("Key must be an even number of hex digits".to_string());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| "Key is not valid hex".to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let tx = Authenticator::new(b"secret");
+        let rx = Authenticator::new(b"secret");
+        let sealed = tx.seal(0x44, &[1, 2, 3]);
+        assert_eq!(3 + AUTH_OVERHEAD, sealed.len());
+        assert_eq!(Ok(vec![1, 2, 3]), rx.open(0x44, &sealed));
+    }
+
+    // Changing any part of the frame invalidates the tag
+    #[test]
+    fn test_tampering() {
+        let tx = Authenticator::new(b"secret");
+        let rx = Authenticator::new(b"secret");
+        let sealed = tx.seal(0x44, &[1, 2, 3]);
+        let mut altered = sealed.clone();
+        altered[COUNTER_SIZE] ^= 0x01;
+        assert_eq!(Err(AuthError::BadTag), rx.open(0x44, &altered));
+        assert_eq!(Err(AuthError::BadTag), rx.open(0x51, &sealed));
+        assert_eq!(
+            Err(AuthError::BadTag),
+            Authenticator::new(b"other").open(0x44, &sealed)
+        );
+        assert_eq!(Err(AuthError::Truncated), rx.open(0x44, &sealed[..10]));
+    }
+
+    #[test]
+    fn test_replay() {
+        let tx = Authenticator::new(b"secret");
+        let rx = Authenticator::new(b"secret");
+        let first = tx.seal(0x44, &[1]);
+        let second = tx.seal(0x44, &[2]);
+        assert!(rx.open(0x44, &second).is_ok());
+        assert_eq!(Err(AuthError::Replay), rx.open(0x44, &second));
+        assert_eq!(Err(AuthError::Replay), rx.open(0x44, &first));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(Ok(vec![0x01, 0xab, 0xff]), parse_key("01abFF"));
+        assert!(parse_key("abc").is_err());
+        assert!(parse_key("zz").is_err());
+        assert!(parse_key("").is_err());
+    }
+}
@@ -18,7 +18,6 @@
 //! Frame Type - 0x44: Indicates that the frame is a data frame.
 //!              This is the frame type used when commands are
 //!              being sent to the station and data is sent back.
-//!
 //!              Ox43: Indicates that the frame is a control frame.
 //!              Control frames are only used by the trasport layer
 //!              to signal whether a frame was successfully recieved.
@@ -39,7 +38,11 @@
 //!
 //! CRC - a 16 bit CRC check value used by the transport layer
 //!       to verify clean transmission of data. The value is calculated
-//!       over the payload portion only.
+//!       over the payload portion only and sent low byte first.
+//!       CRC16 XMODEM is used by default. The algorithm can be
+//!       changed per channel (see `crc::Algorithm`) as long as the
+//!       station is set to match. With CRC32 the CRC takes up four
+//!       bytes and the trailer grows to five.
 //!
 //! End - 0xfe
 //!
@@ -55,7 +58,8 @@
 //! that is used to confirm that the recieving device is up and ready.
 //! Each control frame uses the control frame identifier and utilizes
 //! the payload portion of a frame to indicate which kind it is.
-//! All control frames are 7 bytes long and have the following layout:
+//! With the default CRC all control frames are 7 bytes long and have the
+//! following layout:
 //!
 //! [ 0x7f ][ 0x43 ][ length 1][Control frame identifier][ CRC ][ 0xfe ]
 //!
@@ -73,46 +77,181 @@
 //!
 //! Heartbeat - 0x05: Used to confirm that a connection has been established.
 //!
+//! AuthFail - 0x06: The frame failed authentication (see below).
+//!
 //! *Communication*
 //!
 //! When one end of the channel receives a data frame the frame is checked for
 //! against the layout and the checksum is calculated. The receiver sends an
 //! ACK frame if it passes, otherwise it sends a NACK frame. Depending on how
 //! the sender's channel is configured, the sender may re-attempt transmission
-//! if a NACK is received.
+//! if a NACK is received. A frame has to arrive whole within the frame timeout
+//! (see `Channel::set_timeout`), otherwise it is treated as lost.
 //!
 //!
+//! *Events*
 //!
+//! Besides answering commands the station may push unsolicited messages
+//! (alarms, boot notifications, buffered readings) at any time. These are
+//! carried in their own frame type so they are never confused with the
+//! response to a command:
 //!
+//! Frame Type - 0x45: Event frame. The first byte of the payload is the
+//!              event kind, the rest is event data: [ Kind ][ Data ... ]
+//!
+//! Event frames are acknowledged like data frames. Events that arrive while
+//! the channel is waiting on a response are handed off to the subscriber and
+//! the channel keeps waiting. Between commands `listen` can be used to pick
+//! up events.
+//!
+//! The set of event kinds are:
+//! ThresholdAlarm - 0x01: A reading crossed a threshold set on the station.
+//!
+//! Boot - 0x02: The station has (re)booted.
+//!
+//! BufferedReading - 0x03: A reading taken while the controller was not
+//!                   connected. The data has the same layout as the
+//!                   response to a ReqTPH command.
+//!
+//!
+//! *Requests*
+//!
+//! Plain data frames carry nothing to tie a response to the command that
+//! caused it, so a late response to a command that already timed out would
+//! be taken as the response to the next one. Requests fix this by tagging
+//! each command with an ID that the station echoes back:
+//!
+//! Frame Type - 0x51: Request frame. [ ID ][ Command ... ]
+//!              0x52: Response frame. [ ID ][ Data ... ]
+//!
+//! Both are acknowledged like data frames. Each request has its own deadline
+//! covering the whole exchange. Responses carrying any other ID are stale,
+//! they are acknowledged so the station stops resending them and are then
+//! discarded. Stations whose firmware predates requests can still be sent
+//! commands as data frames with `send` and answer them with a data frame
+//! picked up by `recv`.
+//!
+//!
+//! *Authentication*
+//!
+//! A CRC only protects against noise. Since anyone in range of the Bluetooth
+//! link can pair and send frames, the channel can optionally authenticate
+//! frames with a pre-shared key. When a key is set the payload of every
+//! frame, control and cumulative ACK frames included so ACKs can't be forged,
+//! is wrapped with a replay counter and a truncated HMAC-SHA256 tag, see the
+//! `auth` module for the layout. Frames failing authentication are NACKed
+//! with AuthFail, dropped and counted. Without a key frames are sent as
+//! described above so stations without a key keep working.
+//!
+//! With a key the heartbeat also carries an 8 byte random challenge after
+//! the Heartbeat identifier, and the station must reply with a heartbeat
+//! echoing it. Older replies are ignored, so frames recorded before the
+//! channel was opened can't be replayed afterwards.
+//!
+//!
+//! *Windowed Mode*
+//!
+//! Stop-and-wait costs a full round trip per frame, which is slow for bulk
+//! transfers such as pulling buffered history off the station. For those the
+//! channel offers a sliding window (Go-Back-N) mode. Commands still use
+//! stop-and-wait. Windowed transfers use two additional frame types:
+//!
+//! Frame Type - 0x53: Sequenced data frame. The first two bytes of the
+//!              payload are the sequence number and a flags byte, the rest
+//!              is data: [ Seq ][ Flags ][ Data ... ]
+//!              Flags 0x01 marks the last frame of the transfer.
+//!              0x41: Cumulative ACK. The payload is a single byte holding
+//!              the sequence number of the last frame received in order.
+//!
+//! The sender keeps up to `window` unacknowledged frames in flight. An ACK
+//! for sequence number n acknowledges every frame up to and including n.
+//! The receiver discards frames that arrive out of order and re-sends its
+//! last cumulative ACK. If the sender times out waiting on an ACK it goes
+//! back and re-sends every unacknowledged frame. Sequence numbers wrap at
+//! 256 so the window can be at most 255 frames. Once the last frame is in
+//! the receiver keeps acknowledging it for a frame timeout in case its ACK
+//! was lost.
 //!
 //!
 //!
 
-use std::usize;
-
-use crate::crc16;
+use crate::auth;
+use crate::crc;
 use crate::log;
 use crate::serialport;
+use crate::transport::Transport;
+use std::cell::Cell;
+use std::fmt;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 /// Frame constants
-const FRAME_START: u8 = 0x7f;
-const FRAME_END: u8 = 0xfe;
-const FRAME_TYPE_DATA: u8 = 0x44;
-const FRAME_TYPE_CTRL: u8 = 0x43;
-const FRAME_SIZE_MAX: usize = 86;
-const FRAME_CTRL_SIZE: usize = 7;
-
-enum ControlType {
+pub(crate) const FRAME_START: u8 = 0x7f;
+pub(crate) const FRAME_END: u8 = 0xfe;
+pub(crate) const FRAME_TYPE_DATA: u8 = 0x44;
+pub(crate) const FRAME_TYPE_CTRL: u8 = 0x43;
+pub(crate) const FRAME_TYPE_EVENT: u8 = 0x45;
+pub(crate) const FRAME_TYPE_REQUEST: u8 = 0x51;
+pub(crate) const FRAME_TYPE_RESPONSE: u8 = 0x52;
+const FRAME_TYPE_SEQ: u8 = 0x53;
+pub(crate) const FRAME_TYPE_SEQ_ACK: u8 = 0x41;
+pub(crate) const FRAME_SIZE_MAX: usize = 86;
+pub(crate) const FRAME_HEADER_SIZE: usize = 3;
+
+/// Sequenced frame constants
+const SEQ_HEADER_SIZE: usize = 2;
+const SEQ_FLAG_LAST: u8 = 0x01;
+pub(crate) const WINDOW_SIZE_DEFAULT: u8 = 4;
+
+/// How long a frame is given to arrive by default
+pub(crate) const FRAME_TIMEOUT_DEFAULT: Duration = Duration::from_secs(1);
+
+pub(crate) enum ControlType {
     Ack = 0x01,
     CRCFail = 0x02,
     Oversize = 0x03,
     InvalidFrame = 0x04,
     Heartbeat = 0x05,
+    AuthFail = 0x06,
+}
+
+/// Kinds of unsolicited events pushed by the station
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EventKind {
+    ThresholdAlarm,
+    Boot,
+    BufferedReading,
+    Unknown(u8),
+}
+
+impl From<u8> for EventKind {
+    fn from(b: u8) -> EventKind {
+        match b {
+            0x01 => EventKind::ThresholdAlarm,
+            0x02 => EventKind::Boot,
+            0x03 => EventKind::BufferedReading,
+            _ => EventKind::Unknown(b),
+        }
+    }
+}
+
+/// An unsolicited message from the station
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub data: Vec<u8>,
 }
 
 pub struct Channel {
-    port: serialport::SerialPort,
+    port: Box<dyn Transport>,
     num_attempts: u32,
+    window: u8,
+    crc: crc::Algorithm,
+    auth: Option<auth::Authenticator>,
+    auth_failures: Cell<u32>,
+    events: Option<mpsc::Sender<Event>>,
+    next_id: Cell<u8>,
+    frame_timeout: Duration,
 }
 
 #[derive(Debug)]
@@ -122,12 +261,31 @@ pub struct Error {
 }
 
 impl Error {
-    fn new(kind: ErrorKind, description: &str) -> Error {
+    pub(crate) fn new(kind: ErrorKind, description: &str) -> Error {
         Error {
             kind,
             description: description.to_string(),
         }
     }
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+    /// True if the device went away (unplugged, link dropped) and
+    /// the channel has to be reopened.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self.kind,
+            ErrorKind::SerialPort(serialport::ErrorKind::Disconnected)
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> std::result::Result<(), fmt::Error> {
+        fmt.write_str(&self.description)
+    }
 }
 
 impl From<serialport::Error> for Error {
@@ -148,125 +306,342 @@ pub enum ErrorKind {
     SerialPort(serialport::ErrorKind),
     InvalidFrame,
     CRCFail,
+    InvalidWindow,
+    Timeout,
+    AuthFail(auth::AuthError),
 }
 
-fn make_control_frame(ctype: ControlType) -> [u8; FRAME_CTRL_SIZE] {
-    let mut frame: [u8; FRAME_CTRL_SIZE] = [0; FRAME_CTRL_SIZE];
-    frame[0] = FRAME_START;
-    frame[1] = FRAME_TYPE_CTRL;
-    frame[2] = 0x01; // length of control frame payloads are always 1
-    frame[3] = ctype as u8;
-
-    let crc = crc16::crc16(&frame[3..4]);
-    frame[4] = (crc & 0xff as u16) as u8;
-    frame[5] = (crc >> 8) as u8;
-    frame[6] = FRAME_END;
-    frame
+/// Header and trailer bytes surrounding every payload
+pub(crate) fn frame_overhead(crc: crc::Algorithm) -> usize {
+    FRAME_HEADER_SIZE + crc.width() + 1
+}
+
+/// An unauthenticated control frame, as sent by a station without a key
+#[cfg(test)]
+pub(crate) fn make_control_frame(crc: crc::Algorithm, ctype: ControlType) -> Vec<u8> {
+    // length of control frame payloads are always 1
+    make_frame(crc, FRAME_TYPE_CTRL, &[ctype as u8])
 }
 
-fn make_data_frame(payload: &[u8]) -> Vec<u8> {
-    let mut frame: Vec<u8> = Vec::new();
+pub(crate) fn make_frame(crc: crc::Algorithm, ftype: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::with_capacity(payload.len() + frame_overhead(crc));
     frame.push(FRAME_START);
-    frame.push(FRAME_TYPE_DATA);
+    frame.push(ftype);
     frame.push(payload.len() as u8);
-    for i in payload.iter() {
-        frame.push(*i);
-    }
-    let frame_crc = crc16::crc16(&frame[3..3 + payload.len()]);
-    frame.push((frame_crc & 0xff as u16) as u8);
-    frame.push((frame_crc >> 8) as u8);
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&crc.checksum_bytes(payload));
     frame.push(FRAME_END);
     frame
 }
 
+fn make_seq_payload(seq: u8, last: bool, data: &[u8]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::with_capacity(data.len() + SEQ_HEADER_SIZE);
+    payload.push(seq);
+    payload.push(if last { SEQ_FLAG_LAST } else { 0 });
+    payload.extend_from_slice(data);
+    payload
+}
+
+/// Build a frame, sealing the payload first when
+/// authentication is on.
+pub(crate) fn seal_frame(
+    crc: crc::Algorithm,
+    auth: Option<&auth::Authenticator>,
+    ftype: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    match auth {
+        Some(auth) => make_frame(crc, ftype, &auth.seal(ftype, payload)),
+        None => make_frame(crc, ftype, payload),
+    }
+}
+
+/// Payload of the heartbeat opening a channel, with a fresh challenge
+/// when authentication is on. The station replies with the same payload.
+pub(crate) fn heartbeat_payload(auth: Option<&auth::Authenticator>) -> Vec<u8> {
+    let mut payload = vec![ControlType::Heartbeat as u8];
+    if auth.is_some() {
+        payload.extend_from_slice(&auth::challenge());
+    }
+    payload
+}
+
+/// Check the trailer, CRC and, when authentication is on, the tag of
+/// a frame body (the payload followed by the trailer). Returns the data.
+pub(crate) fn check_body(
+    crc: crc::Algorithm,
+    auth: Option<&auth::Authenticator>,
+    ftype: u8,
+    mut body: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let crc_size = crc.width();
+    let payload_size = body.len() - crc_size - 1;
+    if body[payload_size + crc_size] != FRAME_END {
+        return Err(Error::new(
+            ErrorKind::InvalidFrame,
+            "Recieved frame is invalid",
+        ));
+    }
+
+    let check = crc.checksum_bytes(&body[..payload_size]);
+    if check[..] != body[payload_size..payload_size + crc_size] {
+        return Err(Error::new(ErrorKind::CRCFail, "CRC check did not pass"));
+    }
+    body.truncate(payload_size);
+
+    match auth {
+        Some(auth) => match auth.open(ftype, &body) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                log::warn(&format!("Frame failed authentication: {:?}", e));
+                Err(Error::new(
+                    ErrorKind::AuthFail(e),
+                    "Frame failed authentication",
+                ))
+            }
+        },
+        None => Ok(body),
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl Channel {
-    /// Create a new channel to the serial device
-    pub fn new(port: serialport::SerialPort, num_attempts: u32) -> Channel {
-        Channel { port, num_attempts }
+    /// Create a new channel over a serial port or any other transport
+    pub fn new(port: impl Transport + 'static, num_attempts: u32) -> Channel {
+        Channel {
+            port: Box::new(port),
+            num_attempts,
+            window: WINDOW_SIZE_DEFAULT,
+            crc: crc::Algorithm::default(),
+            auth: None,
+            auth_failures: Cell::new(0),
+            events: None,
+            next_id: Cell::new(0),
+            frame_timeout: FRAME_TIMEOUT_DEFAULT,
+        }
+    }
+
+    /// Set how long a whole frame (an ACK, a response) is given to
+    /// arrive. The deadline covers the frame as a whole so a station
+    /// trickling bytes can't hold the channel up.
+    pub fn set_timeout(&mut self, frame_timeout: Duration) {
+        self.frame_timeout = frame_timeout;
+    }
+
+    /// Subscribe to events pushed by the station.
+    ///
+    /// Events are only queued while the channel is reading, i.e. during
+    /// `recv` or `listen`. Subscribing again replaces the previous
+    /// subscriber. Without a subscriber events are logged and dropped.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.events = Some(tx);
+        rx
+    }
+
+    /// Set the number of unacknowledged frames allowed in flight
+    /// during windowed transfers.
+    pub fn set_window(&mut self, window: u8) -> Result<()> {
+        if window == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidWindow,
+                "Window size must be at least one frame",
+            ));
+        }
+        self.window = window;
+        Ok(())
+    }
+
+    /// Set the CRC algorithm used to check frames. The station
+    /// must be using the same algorithm.
+    pub fn set_crc(&mut self, crc: crc::Algorithm) {
+        self.crc = crc;
+    }
+
+    /// Authenticate frames using the pre-shared key. Stations
+    /// without the key will no longer be understood. Setting the key
+    /// already in use keeps the replay counters.
+    pub fn set_auth_key(&mut self, key: &[u8]) {
+        if !self.auth.as_ref().is_some_and(|a| a.has_key(key)) {
+            self.auth = Some(auth::Authenticator::new(key));
+        }
+    }
+
+    /// Stop authenticating frames.
+    pub fn clear_auth_key(&mut self) {
+        self.auth = None;
+    }
+
+    /// Set the device path used the next time the channel is opened,
+    /// e.g. when the device comes back under another name after a replug.
+    pub fn set_path(&mut self, path: &str) {
+        self.port.set_path(path);
+    }
+
+    /// Replace the transport, e.g. when the serial settings change. The
+    /// old transport is closed.
+    pub fn set_transport(&mut self, port: impl Transport + 'static) {
+        self.port = Box::new(port);
+    }
+
+    /// Close the underlying serial port. The channel can be opened
+    /// again with `open` or `open_auto_baud`.
+    pub fn close(&mut self) -> Result<()> {
+        self.port.close()?;
+        Ok(())
+    }
+
+    /// The underlying transport, e.g. for the modem lines
+    pub fn port(&self) -> &dyn Transport {
+        self.port.as_ref()
+    }
+
+    /// Number of frames that have failed authentication
+    pub fn auth_failures(&self) -> u32 {
+        self.auth_failures.get()
+    }
+
+    /// Largest payload that fits in a frame with the current CRC
+    fn payload_size_max(&self) -> usize {
+        FRAME_SIZE_MAX - frame_overhead(self.crc)
+    }
+
+    /// Largest amount of data that fits in a frame once
+    /// authentication is accounted for.
+    fn data_size_max(&self) -> usize {
+        match self.auth {
+            Some(_) => self.payload_size_max() - auth::AUTH_OVERHEAD,
+            None => self.payload_size_max(),
+        }
+    }
+
+    /// Build a frame, sealing the payload first when
+    /// authentication is on.
+    fn make_frame(&self, ftype: u8, payload: &[u8]) -> Vec<u8> {
+        seal_frame(self.crc, self.auth.as_ref(), ftype, payload)
     }
 
     /// Open the channel for communication
     pub fn open(&mut self) -> Result<()> {
-        if let Err(e) = self.port.open() {
-            return Err(Error::new(ErrorKind::SerialPort(*e.kind()), &e.to_string()));
+        self.open_port()?;
+        if let Err(e) = self.heartbeat() {
+            self.port.close()?;
+            return Err(e);
         }
-        // A heartbeat is used to confirm that the station is up.
-        let mut n_attempts = 0;
-        let mut n_bytes = 0;
-        let mut frame: [u8; FRAME_CTRL_SIZE] = [0; FRAME_CTRL_SIZE];
-        log::info("Attempting to establish a heartbeat..");
-        while n_attempts < self.num_attempts && n_bytes < 7 {
-            self.send_ctrl_frame(ControlType::Heartbeat)?;
-            match self.port.read(&mut frame[n_bytes..7]) {
-                Ok(n) => {
-                    n_bytes += n;
+        Ok(())
+    }
+
+    /// Open the channel without knowing the station's baud rate.
+    ///
+    /// A heartbeat is attempted at each of the candidate rates in turn
+    /// and the channel stays at the first one that gets a response. The
+    /// port is closed again on any error.
+    pub fn open_auto_baud(&mut self, rates: &[serialport::Rate]) -> Result<serialport::Rate> {
+        self.open_port()?;
+        let res = self.find_rate(rates);
+        if res.is_err() {
+            // Otherwise the next open would find the device still open
+            // and locked by us
+            let _ = self.port.close();
+        }
+        res
+    }
+
+    fn find_rate(&mut self, rates: &[serialport::Rate]) -> Result<serialport::Rate> {
+        for rate in rates {
+            log::info(&format!("Trying {} baud", rate));
+            self.port.set_baud(*rate)?;
+            // Anything left over was recieved at the previous rate
+            self.port.flush()?;
+            match self.heartbeat() {
+                Ok(()) => {
+                    log::info(&format!("Locked onto {} baud", rate));
+                    return Ok(*rate);
                 }
-                Err(e) => {
-                    log::debug(&format!("{:?}", e));
+                // Only silence says the rate is wrong
+                Err(e) if matches!(e.kind, ErrorKind::NoHeartBeat) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NoHeartBeat,
+            "No candidate rate yielded a heartbeat",
+        ))
+    }
+
+    fn open_port(&mut self) -> Result<()> {
+        match self.port.open() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::new(ErrorKind::SerialPort(*e.kind()), &e.to_string())),
+        }
+    }
+
+    /// Send heartbeats until the station answers with one.
+    fn heartbeat(&self) -> Result<()> {
+        log::info("Attempting to establish a heartbeat..");
+        for _ in 0..self.num_attempts {
+            let heartbeat = heartbeat_payload(self.auth.as_ref());
+            let frame = self.make_frame(FRAME_TYPE_CTRL, &heartbeat);
+            if let Err(e) = self.port.write(&frame) {
+                return Err(Error::new(ErrorKind::SerialPort(*e.kind()), e.desc()));
+            }
+            match self.read_frame() {
+                Ok((FRAME_TYPE_CTRL, control)) if control == heartbeat => {
+                    log::info("Heartbeat confirmed");
+                    return Ok(());
                 }
+                Ok((ftype, payload)) => log::debug(&format!(
+                    "Expected a heartbeat, got frame {:#x}: {:?}",
+                    ftype, payload
+                )),
+                Err(e) if e.is_disconnect() => return Err(e),
+                Err(e) => log::debug(&format!("{:?}", e)),
             }
-            n_attempts += 1;
             // Clear the IO queues on each attempt.
             self.port.flush()?;
         }
-        if frame[1] != FRAME_TYPE_CTRL && frame[3] != ControlType::Heartbeat as u8 {
-            self.port.close()?;
-            log::error("Could not establish heartbeat");
-            return Err(Error::new(
-                ErrorKind::NoHeartBeat,
-                "Failed to establish heartbeat",
-            ));
-        }
-        log::info("Heartbeat confirmed");
-        Ok(())
+        log::error("Could not establish heartbeat");
+        Err(Error::new(
+            ErrorKind::NoHeartBeat,
+            "Failed to establish heartbeat",
+        ))
     }
 
     fn try_send(&self, frame: &[u8]) -> Result<()> {
-        match self.port.write(&frame) {
-            Ok(n) => log::debug(&format!("Sent bytes: {:?}", frame)),
+        match self.port.write(frame) {
+            Ok(_) => log::debug(&format!("Sent bytes: {:?}", frame)),
             Err(e) => {
                 log::error(&format!("{:?}", e));
                 return Err(Error::new(ErrorKind::SerialPort(*e.kind()), &e.to_string()));
             }
         }
-        let mut control: [u8; FRAME_CTRL_SIZE] = [0; FRAME_CTRL_SIZE];
-        let mut nbytes = 0;
-        while nbytes < FRAME_CTRL_SIZE {
-            match self.port.read(&mut control[nbytes..FRAME_CTRL_SIZE]) {
-                Ok(n) => {
-                    nbytes = nbytes + n;
-                }
-                Err(e) => {
-                    log::error(&format!("{:?}", e));
-                    return Err(Error::new(ErrorKind::SerialPort(*e.kind()), &e.to_string()));
-                }
+        match self.read_frame() {
+            Ok((FRAME_TYPE_CTRL, control)) if control == [ControlType::Ack as u8] => Ok(()),
+            Ok(_) => {
+                self.port.flush()?;
+                Err(Error::new(ErrorKind::NoAck, "ACK not recieved"))
+            }
+            Err(e) => {
+                log::error(&format!("{:?}", e));
+                Err(e)
             }
         }
-        if control[0] != FRAME_START
-            || control[1] != FRAME_TYPE_CTRL
-            || control[3] != ControlType::Ack as u8
-        {
-            self.port.flush()?;
-            return Err(Error::new(ErrorKind::NoAck, "ACK not recieved"));
-        }
-        Ok(())
     }
     ///Send the payload over the channel.
     pub fn send(&self, payload: &[u8]) -> Result<()> {
-        if payload.len() > FRAME_SIZE_MAX - 6 {
+        if payload.len() > self.data_size_max() {
             return Err(Error::new(
                 ErrorKind::Oversize,
                 "Payload larger than maximum payload size",
             ));
         }
 
-        let frame = make_data_frame(payload);
-
         // send and listen for ACK or NACK
         let mut n_attempts = 0;
         while n_attempts < self.num_attempts {
+            // Resends are built fresh so they get a new replay counter
+            let frame = self.make_frame(FRAME_TYPE_DATA, payload);
             match self.try_send(&frame) {
                 Ok(_) => return Ok(()),
                 Err(e) => log::error(&format!("{:?}", e)),
@@ -280,62 +655,163 @@ impl Channel {
     }
 
     fn try_recv(&self) -> Result<Vec<u8>> {
-        let mut frame: Vec<u8> = vec![0; FRAME_SIZE_MAX];
-        let mut nbytes = 0;
-
-        // pull in header
-        while nbytes < 3 {
-            match self.port.read(&mut frame[nbytes..3]) {
-                Ok(n) => {
-                    nbytes = nbytes + n;
+        let mut frame = self.read_frame();
+        // Events may arrive ahead of the response
+        while let Ok((FRAME_TYPE_EVENT, payload)) = frame {
+            self.handle_event(payload)?;
+            frame = self.read_frame();
+        }
+        match frame {
+            Ok((FRAME_TYPE_DATA, payload)) => {
+                self.send_ctrl_frame(ControlType::Ack)?;
+                Ok(payload)
+            }
+            Ok(_) => {
+                self.nack(ControlType::InvalidFrame)?;
+                Err(Error::new(
+                    ErrorKind::InvalidFrame,
+                    "Recieved frame is invalid",
+                ))
+            }
+            Err(e) => {
+                self.nack_error(&e)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Send a request and wait for the matching response.
+    ///
+    /// The timeout covers the whole exchange, including any resends
+    /// of the request.
+    pub fn request(&self, payload: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+        if payload.len() > self.data_size_max() - 1 {
+            return Err(Error::new(
+                ErrorKind::Oversize,
+                "Payload larger than maximum payload size",
+            ));
+        }
+        let deadline = Instant::now() + timeout;
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+
+        let mut body: Vec<u8> = Vec::with_capacity(payload.len() + 1);
+        body.push(id);
+        body.extend_from_slice(payload);
+        let frame = self.make_frame(FRAME_TYPE_REQUEST, &body);
+
+        self.port.write(&frame)?;
+        log::debug(&format!("Sent request {}: {:?}", id, frame));
+        let mut n_attempts = 1;
+        let mut acked = false;
+        while Instant::now() < deadline {
+            let frame_deadline = std::cmp::min(deadline, Instant::now() + self.frame_timeout);
+            let resend = match self.read_frame_until(frame_deadline) {
+                Ok((FRAME_TYPE_CTRL, ctrl)) if !acked => {
+                    acked = ctrl == [ControlType::Ack as u8];
+                    !acked
                 }
-                Err(e) => {
-                    log::error(&format!("{:?}", e));
-                    break;
+                Ok((FRAME_TYPE_RESPONSE, response)) if !response.is_empty() => {
+                    self.send_ctrl_frame(ControlType::Ack)?;
+                    if response[0] == id {
+                        return Ok(response[1..].to_vec());
+                    }
+                    log::warn(&format!(
+                        "Discarding stale response to request {}",
+                        response[0]
+                    ));
+                    false
+                }
+                Ok((FRAME_TYPE_EVENT, payload)) => {
+                    self.handle_event(payload)?;
+                    false
+                }
+                Ok((ftype, _)) => {
+                    log::debug(&format!("Ignoring frame type {:#x} during request", ftype));
+                    false
                 }
+                Err(e) => match e.kind {
+                    ErrorKind::SerialPort(serialport::ErrorKind::Timeout) => !acked,
+                    ErrorKind::SerialPort(_) => return Err(e),
+                    _ => {
+                        log::error(&format!("channel: {:?}", e));
+                        self.nack_error(&e)?;
+                        false
+                    }
+                },
+            };
+            if resend {
+                if n_attempts >= self.num_attempts {
+                    return Err(Error::new(
+                        ErrorKind::MaxAttempts,
+                        "Maximum number of resend attempts reached",
+                    ));
+                }
+                self.port
+                    .write(&self.make_frame(FRAME_TYPE_REQUEST, &body))?;
+                n_attempts += 1;
             }
         }
-        let payload_size: usize = {
-            if frame[2] as usize > FRAME_SIZE_MAX - 6 {
-                self.send_ctrl_frame(ControlType::Oversize);
-                self.port.flush();
-                return Err(Error::new(ErrorKind::Oversize, "Frame oversize"));
-            } else {
-                frame[2] as usize
-            }
-        };
+        Err(Error::new(
+            ErrorKind::Timeout,
+            &format!("No response to request {} before the deadline", id),
+        ))
+    }
 
-        while nbytes < payload_size {
-            match self.port.read(&mut frame[nbytes..payload_size + 6]) {
-                Ok(n) => {
-                    nbytes = nbytes + n;
-                    log::debug(&format!("Recieved {} bytes", n));
+    /// Listen for events pushed by the station for the given duration.
+    ///
+    /// Any other frame is ACKed so the station doesn't keep resending it,
+    /// and then dropped. Control and cumulative ACK frames are dropped
+    /// without a reply.
+    pub fn listen(&self, duration: Duration) -> Result<()> {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            match self.read_frame_until(deadline) {
+                Ok((FRAME_TYPE_EVENT, payload)) => self.handle_event(payload)?,
+                Ok((ftype @ (FRAME_TYPE_CTRL | FRAME_TYPE_SEQ_ACK), _)) => {
+                    log::debug(&format!("Ignoring frame type {:#x} while listening", ftype));
+                }
+                Ok((ftype, _)) => {
+                    log::debug(&format!("Dropping frame type {:#x} while listening", ftype));
+                    self.send_ctrl_frame(ControlType::Ack)?;
                 }
-                Err(e) => log::error(&format!("Error {:?}", e)),
+                Err(e) => match e.kind {
+                    ErrorKind::SerialPort(serialport::ErrorKind::Timeout) => (),
+                    ErrorKind::SerialPort(_) => return Err(e),
+                    _ => {
+                        log::error(&format!("channel: {:?}", e));
+                        self.nack_error(&e)?;
+                    }
+                },
             }
         }
-        if frame[0] != FRAME_START
-            || frame[1] != FRAME_TYPE_DATA
-            || frame[payload_size + 6 - 1] != FRAME_END
-        {
-            self.send_ctrl_frame(ControlType::InvalidFrame)?;
-            self.port.flush();
+        Ok(())
+    }
+
+    /// Acknowledge an event frame and hand it off to the subscriber.
+    fn handle_event(&self, payload: Vec<u8>) -> Result<()> {
+        if payload.is_empty() {
+            self.nack(ControlType::InvalidFrame)?;
             return Err(Error::new(
                 ErrorKind::InvalidFrame,
-                "Recieved frame is invalid",
+                "Recieved event without a kind",
             ));
         }
-
-        let check: u16 = crc16::crc16(&frame[3..3 + payload_size]);
-        let mut frame_crc: u16 = frame[payload_size + 6 - 3] as u16 & 0xff;
-        frame_crc |= (frame[payload_size + 6 - 2] as u16) << 8;
-        if check != frame_crc {
-            self.send_ctrl_frame(ControlType::CRCFail);
-            self.port.flush();
-            return Err(Error::new(ErrorKind::CRCFail, "CRC check did not pass"));
+        self.send_ctrl_frame(ControlType::Ack)?;
+        let event = Event {
+            kind: EventKind::from(payload[0]),
+            data: payload[1..].to_vec(),
+        };
+        log::debug(&format!("Recieved event: {:?}", event));
+        match &self.events {
+            Some(tx) => {
+                if tx.send(event).is_err() {
+                    log::warn("Event subscriber has gone away");
+                }
+            }
+            None => log::info(&format!("Unhandled event: {:?}", event.kind)),
         }
-        self.send_ctrl_frame(ControlType::Ack);
-        Ok(frame[3..3 + payload_size].to_vec())
+        Ok(())
     }
 
     pub fn recv(&self) -> Result<Vec<u8>> {
@@ -352,14 +828,695 @@ impl Channel {
             "Maximum number of recieve attempts reached",
         ))
     }
+
+    /// Send a batch of payloads using the windowed (Go-Back-N) mode.
+    ///
+    /// Returns once the station has acknowledged every payload.
+    pub fn send_windowed(&self, payloads: &[Vec<u8>]) -> Result<()> {
+        if payloads
+            .iter()
+            .any(|p| p.len() > self.data_size_max() - SEQ_HEADER_SIZE)
+        {
+            return Err(Error::new(
+                ErrorKind::Oversize,
+                "Payload larger than maximum payload size",
+            ));
+        }
+        let frames: Vec<Vec<u8>> = payloads
+            .iter()
+            .enumerate()
+            .map(|(i, p)| make_seq_payload(i as u8, i + 1 == payloads.len(), p))
+            .collect();
+
+        // base is the oldest unacknowledged frame, next is the
+        // next frame to be put on the wire.
+        let mut base: usize = 0;
+        let mut next: usize = 0;
+        let mut n_attempts = 0;
+        while base < frames.len() {
+            while next < frames.len() && next < base + self.window as usize {
+                self.port
+                    .write(&self.make_frame(FRAME_TYPE_SEQ, &frames[next]))?;
+                log::debug(&format!("Sent sequenced frame {}", next as u8));
+                next += 1;
+            }
+            match self.read_frame() {
+                Ok((FRAME_TYPE_SEQ_ACK, ack)) if ack.len() == 1 => {
+                    // Acks older than base wrap around to a large offset
+                    // and are ignored.
+                    let offset = ack[0].wrapping_sub(base as u8) as usize;
+                    if offset < next - base {
+                        base += offset + 1;
+                        n_attempts = 0;
+                    }
+                }
+                Ok((ftype, _)) => {
+                    log::debug(&format!("Ignoring frame type {:#x} while sending", ftype));
+                }
+                Err(e) => {
+                    n_attempts += 1;
+                    if n_attempts >= self.num_attempts {
+                        return Err(Error::new(
+                            ErrorKind::MaxAttempts,
+                            "Maximum number of resend attempts reached",
+                        ));
+                    }
+                    log::error(&format!("{:?}", e));
+                    // Go back and resend everything unacknowledged
+                    next = base;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive a batch of payloads sent using the windowed (Go-Back-N) mode.
+    ///
+    /// Returns once the frame marked as last has been received and a
+    /// frame timeout has passed without it being resent.
+    pub fn recv_windowed(&self) -> Result<Vec<Vec<u8>>> {
+        let mut payloads: Vec<Vec<u8>> = Vec::new();
+        let mut expected: u8 = 0;
+        let mut n_attempts = 0;
+        loop {
+            match self.read_frame() {
+                Ok((FRAME_TYPE_SEQ, payload)) if payload.len() >= SEQ_HEADER_SIZE => {
+                    n_attempts = 0;
+                    if payload[0] != expected {
+                        // Out of order, re-acknowledge the last
+                        // frame recieved in order.
+                        self.send_seq_ack(expected.wrapping_sub(1))?;
+                        continue;
+                    }
+                    self.send_seq_ack(expected)?;
+                    expected = expected.wrapping_add(1);
+                    payloads.push(payload[SEQ_HEADER_SIZE..].to_vec());
+                    if payload[1] & SEQ_FLAG_LAST != 0 {
+                        self.linger(payload[0]);
+                        return Ok(payloads);
+                    }
+                }
+                Ok((ftype, _)) => {
+                    log::debug(&format!("Ignoring frame type {:#x} while recieving", ftype));
+                }
+                Err(e) => {
+                    n_attempts += 1;
+                    if n_attempts >= self.num_attempts {
+                        return Err(Error::new(
+                            ErrorKind::MaxAttempts,
+                            "Maximum number of recieve attempts reached",
+                        ));
+                    }
+                    log::error(&format!("channel: {:?}", e));
+                }
+            }
+        }
+    }
+
+    /// Acknowledge the last frame again each time it is resent. The
+    /// sender resends it if the first ACK was lost, and would give up
+    /// if nobody was listening any more.
+    fn linger(&self, last: u8) {
+        for _ in 0..self.num_attempts {
+            match self.read_frame() {
+                Ok((FRAME_TYPE_SEQ, payload)) if payload.first() == Some(&last) => {
+                    if self.send_seq_ack(last).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => match e.kind {
+                    ErrorKind::Oversize
+                    | ErrorKind::InvalidFrame
+                    | ErrorKind::CRCFail
+                    | ErrorKind::AuthFail(_) => (),
+                    // Quiet for a whole frame timeout
+                    _ => return,
+                },
+            }
+        }
+    }
+
     pub fn send_heartbeat(&self) -> Result<()> {
         self.send_ctrl_frame(ControlType::Heartbeat)
     }
     fn send_ctrl_frame(&self, ctype: ControlType) -> Result<()> {
-        let frame = make_control_frame(ctype);
+        let frame = self.make_frame(FRAME_TYPE_CTRL, &[ctype as u8]);
         match self.port.write(&frame) {
             Ok(_) => Ok(()),
             Err(e) => Err(Error::new(ErrorKind::SerialPort(*e.kind()), e.desc())),
         }
     }
+    fn send_seq_ack(&self, seq: u8) -> Result<()> {
+        self.port
+            .write(&self.make_frame(FRAME_TYPE_SEQ_ACK, &[seq]))?;
+        Ok(())
+    }
+    /// Send a NACK and clear out whatever is left of the bad frame.
+    fn nack(&self, ctype: ControlType) -> Result<()> {
+        // Input is cleared first so nothing sent in reply to the NACK is lost
+        self.port.flush_input()?;
+        self.send_ctrl_frame(ctype)?;
+        self.port.drain()?;
+        Ok(())
+    }
+    /// Send the NACK matching a frame error, if there is one.
+    fn nack_error(&self, e: &Error) -> Result<()> {
+        match e.kind {
+            ErrorKind::Oversize => self.nack(ControlType::Oversize),
+            ErrorKind::InvalidFrame => self.nack(ControlType::InvalidFrame),
+            ErrorKind::CRCFail => self.nack(ControlType::CRCFail),
+            ErrorKind::AuthFail(_) => self.nack(ControlType::AuthFail),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a single frame off the port within the frame timeout.
+    fn read_frame(&self) -> Result<(u8, Vec<u8>)> {
+        self.read_frame_until(Instant::now() + self.frame_timeout)
+    }
+
+    /// Read a single frame off the port, returning the frame
+    /// type and payload. Any bytes before the start byte are
+    /// skipped. The whole frame has to arrive by the deadline.
+    fn read_frame_until(&self, deadline: Instant) -> Result<(u8, Vec<u8>)> {
+        let mut header: [u8; FRAME_HEADER_SIZE] = [0; FRAME_HEADER_SIZE];
+        while header[0] != FRAME_START {
+            self.port
+                .read_exact_until_deadline(&mut header[..1], deadline)?;
+        }
+        self.port
+            .read_exact_until_deadline(&mut header[1..], deadline)?;
+        let payload_size = header[2] as usize;
+        if payload_size > self.payload_size_max() {
+            return Err(Error::new(ErrorKind::Oversize, "Frame oversize"));
+        }
+
+        // payload followed by the trailer
+        let mut body: Vec<u8> = vec![0; payload_size + self.crc.width() + 1];
+        self.port.read_exact_until_deadline(&mut body, deadline)?;
+        log::debug(&format!(
+            "Recieved {} bytes",
+            body.len() + FRAME_HEADER_SIZE
+        ));
+        match check_body(self.crc, self.auth.as_ref(), header[1], body) {
+            Ok(data) => Ok((header[1], data)),
+            Err(e) => {
+                if let ErrorKind::AuthFail(_) = e.kind {
+                    self.auth_failures.set(self.auth_failures.get() + 1);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::PtyMaster;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    const CRC: crc::Algorithm = crc::Algorithm::Crc16Xmodem;
+
+    fn make_data_frame(crc: crc::Algorithm, payload: &[u8]) -> Vec<u8> {
+        make_frame(crc, FRAME_TYPE_DATA, payload)
+    }
+
+    fn make_seq_frame(crc: crc::Algorithm, seq: u8, last: bool, data: &[u8]) -> Vec<u8> {
+        make_frame(crc, FRAME_TYPE_SEQ, &make_seq_payload(seq, last, data))
+    }
+
+    // Opens a pty pair. The master end plays the part of the station
+    // and the slave end is handed to the channel.
+    fn open_pty_channel() -> (PtyMaster, Channel) {
+        let (master, path) = serialport::open_pty();
+        // Not exclusive so nothing is written to the real lock directory
+        let port = serialport::SerialPortBuilder::new(&path)
+            .timeout(Duration::from_millis(200))
+            .exclusive(false)
+            .open()
+            .unwrap();
+        let mut channel = Channel::new(port, 5);
+        channel.set_timeout(Duration::from_millis(200));
+        (master, channel)
+    }
+
+    fn station_read(master: &mut PtyMaster) -> (u8, Vec<u8>) {
+        let mut header: [u8; 3] = [0; 3];
+        master.read_exact(&mut header).unwrap();
+        let mut body = vec![0; header[2] as usize + CRC.width() + 1];
+        master.read_exact(&mut body).unwrap();
+        body.truncate(header[2] as usize);
+        (header[1], body)
+    }
+
+    fn station_ack(master: &mut PtyMaster, seq: u8) {
+        master
+            .write_all(&make_frame(CRC, FRAME_TYPE_SEQ_ACK, &[seq]))
+            .unwrap();
+    }
+
+    fn payloads(n: u8) -> Vec<Vec<u8>> {
+        (0..n).map(|i| vec![i; 3]).collect()
+    }
+
+    // The station only answers once the line is at its rate
+    #[test]
+    fn test_open_auto_baud() {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use nix::sys::termios::{cfgetospeed, tcgetattr};
+        use std::os::unix::io::AsRawFd;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let (mut master, path) = serialport::open_pty();
+        let port = serialport::SerialPortBuilder::new(&path)
+            .timeout(Duration::from_millis(100))
+            .exclusive(false)
+            .build();
+        let mut channel = Channel::new(port, 2);
+        channel.set_timeout(Duration::from_millis(100));
+        let done = Arc::new(AtomicBool::new(false));
+        let station_done = done.clone();
+        let station = thread::spawn(move || {
+            let mut answered = 0;
+            while !station_done.load(Ordering::SeqCst) {
+                let mut fds = [PollFd::new(master.as_raw_fd(), PollFlags::POLLIN)];
+                if poll(&mut fds, 20).unwrap() == 0 {
+                    continue;
+                }
+                let (ftype, payload) = station_read(&mut master);
+                assert_eq!(FRAME_TYPE_CTRL, ftype);
+                assert_eq!(vec![ControlType::Heartbeat as u8], payload);
+                // The master reports the settings of the slave end
+                let settings = tcgetattr(master.as_raw_fd()).unwrap();
+                if cfgetospeed(&settings) == serialport::BaudRate::B57600 {
+                    master
+                        .write_all(&make_control_frame(CRC, ControlType::Heartbeat))
+                        .unwrap();
+                    answered += 1;
+                }
+            }
+            (answered, master)
+        });
+        let rates: Vec<serialport::Rate> =
+            vec![9600.into(), 19200.into(), 57600.into(), 115200.into()];
+        let rate = channel.open_auto_baud(&rates);
+        done.store(true, Ordering::SeqCst);
+        let (answered, _master) = station.join().unwrap();
+        assert_eq!(serialport::Rate::from(57600), rate.unwrap());
+        assert!(answered > 0);
+    }
+
+    // Unplugging the adapter mid-scan ends the scan with the port
+    // closed and unlocked, so it can be opened again
+    #[test]
+    fn test_open_auto_baud_disconnect() {
+        let (mut master, path) = serialport::open_pty();
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_auto_baud_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let port = serialport::SerialPortBuilder::new(&path)
+            .lock_dir(dir.to_str().unwrap())
+            .build();
+        let mut channel = Channel::new(port, 2);
+        channel.set_timeout(Duration::from_millis(100));
+        let station = thread::spawn(move || {
+            station_read(&mut master);
+        });
+        let rates: Vec<serialport::Rate> = vec![9600.into(), 19200.into()];
+        let e = channel.open_auto_baud(&rates).unwrap_err();
+        station.join().unwrap();
+        assert!(e.is_disconnect(), "{:?}", e);
+        assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // The deadline covers the whole frame, not each read, so a station
+    // trickling in bytes can't hold the channel up
+    #[test]
+    fn test_frame_deadline() {
+        let (mut master, channel) = open_pty_channel();
+        let frame = make_data_frame(CRC, &[1, 2, 3, 4]);
+        let station = thread::spawn(move || {
+            for b in frame.iter() {
+                master.write_all(&[*b]).unwrap();
+                thread::sleep(Duration::from_millis(60));
+            }
+            master
+        });
+        let start = Instant::now();
+        let e = channel.read_frame().unwrap_err();
+        let elapsed = start.elapsed();
+        let _master = station.join().unwrap();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::SerialPort(serialport::ErrorKind::Timeout)
+        ));
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(300));
+    }
+
+    // A single cumulative ACK covers every frame in the window
+    #[test]
+    fn test_send_windowed_cumulative_ack() {
+        let (mut master, mut channel) = open_pty_channel();
+        channel.set_window(4).unwrap();
+        let station = thread::spawn(move || {
+            let mut recieved = Vec::new();
+            for _ in 0..4 {
+                recieved.push(station_read(&mut master));
+            }
+            station_ack(&mut master, 3);
+            recieved.push(station_read(&mut master));
+            station_ack(&mut master, 4);
+            (recieved, master)
+        });
+        channel.send_windowed(&payloads(5)).unwrap();
+        let (recieved, _master) = station.join().unwrap();
+        for (i, (ftype, payload)) in recieved.iter().enumerate() {
+            assert_eq!(FRAME_TYPE_SEQ, *ftype);
+            assert_eq!(i as u8, payload[0]);
+            assert_eq!(i == 4, payload[1] & SEQ_FLAG_LAST != 0);
+            assert_eq!(&vec![i as u8; 3], &payload[2..].to_vec());
+        }
+    }
+
+    // A lost frame causes everything after it to be resent
+    #[test]
+    fn test_send_windowed_go_back() {
+        let (mut master, mut channel) = open_pty_channel();
+        channel.set_window(3).unwrap();
+        let station = thread::spawn(move || {
+            let mut expected: u8 = 0;
+            let mut dropped = false;
+            let mut seen = Vec::new();
+            while expected < 4 {
+                let (_, payload) = station_read(&mut master);
+                seen.push(payload[0]);
+                if payload[0] == 1 && !dropped {
+                    dropped = true;
+                    continue;
+                }
+                if payload[0] == expected {
+                    station_ack(&mut master, expected);
+                    expected += 1;
+                } else {
+                    station_ack(&mut master, expected.wrapping_sub(1));
+                }
+            }
+            (seen, master)
+        });
+        channel.send_windowed(&payloads(4)).unwrap();
+        let (seen, _master) = station.join().unwrap();
+        assert_eq!(&[0, 1, 2], &seen[..3]);
+        assert!(seen.iter().filter(|seq| **seq == 1).count() > 1);
+    }
+
+    // Out of order frames are discarded and the last in order
+    // frame is acknowledged again
+    #[test]
+    fn test_recv_windowed_out_of_order() {
+        let (mut master, channel) = open_pty_channel();
+        let station = thread::spawn(move || {
+            for (seq, last) in [(0, false), (2, true), (1, false), (2, true)].iter() {
+                master
+                    .write_all(&make_seq_frame(CRC, *seq, *last, &[*seq; 2]))
+                    .unwrap();
+            }
+            (0..4)
+                .map(|_| station_read(&mut master).1[0])
+                .collect::<Vec<u8>>()
+        });
+        let recieved = channel.recv_windowed().unwrap();
+        assert_eq!(vec![vec![0, 0], vec![1, 1], vec![2, 2]], recieved);
+        assert_eq!(vec![0, 0, 1, 2], station.join().unwrap());
+    }
+
+    // The sender resends the last frame when its ACK is lost, and
+    // gets it acknowledged again
+    #[test]
+    fn test_recv_windowed_lost_last_ack() {
+        let (mut master, channel) = open_pty_channel();
+        let station = thread::spawn(move || {
+            master
+                .write_all(&make_seq_frame(CRC, 0, false, &[0]))
+                .unwrap();
+            master
+                .write_all(&make_seq_frame(CRC, 1, true, &[1]))
+                .unwrap();
+            let mut acks = vec![station_read(&mut master), station_read(&mut master)];
+            master
+                .write_all(&make_seq_frame(CRC, 1, true, &[1]))
+                .unwrap();
+            acks.push(station_read(&mut master));
+            acks.into_iter().map(|(_, p)| p[0]).collect::<Vec<u8>>()
+        });
+        let recieved = channel.recv_windowed().unwrap();
+        assert_eq!(vec![vec![0], vec![1]], recieved);
+        assert_eq!(vec![0, 1, 1], station.join().unwrap());
+    }
+
+    fn station_event(master: &mut PtyMaster, kind: u8, data: &[u8]) {
+        let mut payload = vec![kind];
+        payload.extend_from_slice(data);
+        master
+            .write_all(&make_frame(CRC, FRAME_TYPE_EVENT, &payload))
+            .unwrap();
+    }
+
+    // Events arriving ahead of a response go to the subscriber
+    // and the response is still returned
+    #[test]
+    fn test_recv_routes_events() {
+        let (mut master, mut channel) = open_pty_channel();
+        let events = channel.subscribe();
+        let station = thread::spawn(move || {
+            station_event(&mut master, 0x01, &[0xaa]);
+            master.write_all(&make_data_frame(CRC, &[1, 2, 3])).unwrap();
+            let acks = [station_read(&mut master), station_read(&mut master)];
+            (acks, master)
+        });
+        assert_eq!(vec![1, 2, 3], channel.recv().unwrap());
+        let (acks, _master) = station.join().unwrap();
+        for (ftype, payload) in acks.iter() {
+            assert_eq!(FRAME_TYPE_CTRL, *ftype);
+            assert_eq!(vec![ControlType::Ack as u8], *payload);
+        }
+        assert_eq!(
+            Event {
+                kind: EventKind::ThresholdAlarm,
+                data: vec![0xaa]
+            },
+            events.try_recv().unwrap()
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_listen_for_events() {
+        let (mut master, mut channel) = open_pty_channel();
+        let events = channel.subscribe();
+        station_event(&mut master, 0x02, &[]);
+        station_event(&mut master, 0x03, &[1, 2]);
+        channel.listen(Duration::from_millis(300)).unwrap();
+        let kinds: Vec<EventKind> = events.try_iter().map(|e| e.kind).collect();
+        assert_eq!(vec![EventKind::Boot, EventKind::BufferedReading], kinds);
+    }
+
+    // Frames other than events are ACKed while listening so the station
+    // stops resending them, control frames are left unanswered
+    #[test]
+    fn test_listen_acks_other_frames() {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use std::os::unix::io::AsRawFd;
+
+        let (mut master, channel) = open_pty_channel();
+        master
+            .write_all(&make_frame(CRC, FRAME_TYPE_RESPONSE, &[0, 1]))
+            .unwrap();
+        master
+            .write_all(&make_control_frame(CRC, ControlType::Ack))
+            .unwrap();
+        station_event(&mut master, 0x02, &[]);
+        channel.listen(Duration::from_millis(300)).unwrap();
+        let ack = (FRAME_TYPE_CTRL, vec![ControlType::Ack as u8]);
+        // One for the response and one for the event, none for the ACK
+        assert_eq!(ack, station_read(&mut master));
+        assert_eq!(ack, station_read(&mut master));
+        let mut fds = [PollFd::new(master.as_raw_fd(), PollFlags::POLLIN)];
+        assert_eq!(0, poll(&mut fds, 50).unwrap());
+    }
+
+    // A late response to an earlier request is not taken as the
+    // response to the current one
+    #[test]
+    fn test_request_discards_stale_response() {
+        let (mut master, channel) = open_pty_channel();
+        let station = thread::spawn(move || {
+            loop {
+                let (ftype, payload) = station_read(&mut master);
+                if ftype != FRAME_TYPE_REQUEST {
+                    continue;
+                }
+                master
+                    .write_all(&make_control_frame(CRC, ControlType::Ack))
+                    .unwrap();
+                if payload[0] == 1 {
+                    master
+                        .write_all(&make_frame(CRC, FRAME_TYPE_RESPONSE, &[0, 9, 9]))
+                        .unwrap();
+                    master
+                        .write_all(&make_frame(CRC, FRAME_TYPE_RESPONSE, &[1, 1, 2]))
+                        .unwrap();
+                    break;
+                }
+            }
+            master
+        });
+        let e = channel
+            .request(&[0x02], Duration::from_millis(300))
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Timeout));
+        assert_eq!(
+            vec![1, 2],
+            channel.request(&[0x02], Duration::from_secs(2)).unwrap()
+        );
+        let _master = station.join().unwrap();
+    }
+
+    // Frames use the wider trailer when CRC32 is selected
+    #[test]
+    fn test_recv_crc32() {
+        let (mut master, mut channel) = open_pty_channel();
+        channel.set_crc(crc::Algorithm::Crc32);
+        master
+            .write_all(&make_data_frame(crc::Algorithm::Crc32, &[4, 5]))
+            .unwrap();
+        assert_eq!(vec![4, 5], channel.recv().unwrap());
+        let mut ack = [0; 9];
+        master.read_exact(&mut ack).unwrap();
+        assert_eq!(
+            make_control_frame(crc::Algorithm::Crc32, ControlType::Ack),
+            ack.to_vec()
+        );
+    }
+
+    // Frames with a bad tag are NACKed and counted
+    #[test]
+    fn test_recv_authenticated() {
+        let (mut master, mut channel) = open_pty_channel();
+        channel.set_auth_key(b"secret");
+        let station = thread::spawn(move || {
+            let station = auth::Authenticator::new(b"secret");
+            let forged = auth::Authenticator::new(b"guess").seal(FRAME_TYPE_DATA, &[0x01]);
+            master.write_all(&make_data_frame(CRC, &forged)).unwrap();
+            // The channel has cleared its input by the time the NACK
+            // arrives, so the next frame can follow straight away
+            let (_, nack) = station_read(&mut master);
+            let sealed = station.seal(FRAME_TYPE_DATA, &[7, 8]);
+            master.write_all(&make_data_frame(CRC, &sealed)).unwrap();
+            let (_, ack) = station_read(&mut master);
+            // Control frames are authenticated too
+            let nack = station.open(FRAME_TYPE_CTRL, &nack).unwrap();
+            let ack = station.open(FRAME_TYPE_CTRL, &ack).unwrap();
+            (nack, ack, master)
+        });
+        assert_eq!(vec![7, 8], channel.recv().unwrap());
+        let (nack, ack, _master) = station.join().unwrap();
+        assert_eq!(vec![ControlType::AuthFail as u8], nack);
+        assert_eq!(vec![ControlType::Ack as u8], ack);
+        assert_eq!(1, channel.auth_failures());
+    }
+
+    // With a key set an ACK without a valid tag doesn't count, so the
+    // frame is sent again until a real one arrives
+    #[test]
+    fn test_send_forged_ack() {
+        let (mut master, mut channel) = open_pty_channel();
+        channel.set_auth_key(b"secret");
+        let station = thread::spawn(move || {
+            let station = auth::Authenticator::new(b"secret");
+            station_read(&mut master);
+            master
+                .write_all(&make_control_frame(CRC, ControlType::Ack))
+                .unwrap();
+            station_read(&mut master);
+            let ack = station.seal(FRAME_TYPE_CTRL, &[ControlType::Ack as u8]);
+            master
+                .write_all(&make_frame(CRC, FRAME_TYPE_CTRL, &ack))
+                .unwrap();
+            master
+        });
+        channel.send(&[1]).unwrap();
+        let _master = station.join().unwrap();
+        assert_eq!(1, channel.auth_failures());
+    }
+
+    // Frames recorded before the channel was opened, the heartbeat reply
+    // included, are rejected once the station has echoed the challenge
+    #[test]
+    fn test_heartbeat_challenge() {
+        let (mut master, mut channel) = open_pty_channel();
+        channel.set_auth_key(b"secret");
+        let station = thread::spawn(move || {
+            let station = auth::Authenticator::new(b"secret");
+            let mut heartbeat = vec![ControlType::Heartbeat as u8];
+            heartbeat.extend_from_slice(&[0; auth::CHALLENGE_SIZE]);
+            let old_reply = station.seal(FRAME_TYPE_CTRL, &heartbeat);
+            let old_data = station.seal(FRAME_TYPE_DATA, &[9]);
+
+            station_read(&mut master);
+            master
+                .write_all(&make_frame(CRC, FRAME_TYPE_CTRL, &old_reply))
+                .unwrap();
+            let (_, sealed) = station_read(&mut master);
+            let heartbeat = station.open(FRAME_TYPE_CTRL, &sealed).unwrap();
+            assert_eq!(1 + auth::CHALLENGE_SIZE, heartbeat.len());
+            let reply = station.seal(FRAME_TYPE_CTRL, &heartbeat);
+            master
+                .write_all(&make_frame(CRC, FRAME_TYPE_CTRL, &reply))
+                .unwrap();
+
+            master.write_all(&make_data_frame(CRC, &old_data)).unwrap();
+            let (_, nack) = station_read(&mut master);
+            let data = station.seal(FRAME_TYPE_DATA, &[7]);
+            master.write_all(&make_data_frame(CRC, &data)).unwrap();
+            station_read(&mut master);
+            (station.open(FRAME_TYPE_CTRL, &nack).unwrap(), master)
+        });
+        channel.heartbeat().unwrap();
+        assert_eq!(vec![7], channel.recv().unwrap());
+        let (nack, _master) = station.join().unwrap();
+        assert_eq!(vec![ControlType::AuthFail as u8], nack);
+        assert_eq!(1, channel.auth_failures());
+    }
+
+    #[test]
+    fn test_same_auth_key_keeps_counters() {
+        let (_master, mut channel) = open_pty_channel();
+        channel.set_auth_key(b"secret");
+        let sealed = auth::Authenticator::new(b"secret").seal(FRAME_TYPE_DATA, &[1]);
+        let open = |channel: &Channel| {
+            channel
+                .auth
+                .as_ref()
+                .unwrap()
+                .open(FRAME_TYPE_DATA, &sealed)
+        };
+        assert!(open(&channel).is_ok());
+        // A reload with the key unchanged must not let the frame be replayed
+        channel.set_auth_key(b"secret");
+        assert_eq!(Err(auth::AuthError::Replay), open(&channel));
+        channel.set_auth_key(b"other");
+        assert_eq!(Err(auth::AuthError::BadTag), open(&channel));
+    }
+
+    #[test]
+    fn test_zero_window() {
+        let (_master, mut channel) = open_pty_channel();
+        assert!(channel.set_window(0).is_err());
+    }
 }
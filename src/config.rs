@@ -1,43 +1,862 @@
 //! Module used for parsing the config file
+//!
+//! Each line is `key = value`. Whitespace around keys and values is
+//! trimmed and `#` starts a comment. Values can be quoted: inside
+//! double quotes `#` and surrounding whitespace are kept and the escapes
+//! `\"`, `\\`, `\n`, `\r`, `\t` and `\u{XXXX}` are understood. Inside
+//! single quotes everything is kept as is, `${NAME}` included. A
+//! `syntax = legacy` line switches the rest of the file to the original
+//! grammar, where whitespace is part of keys and values and a `#`
+//! anywhere starts a comment.
+//!
+//! Besides `key=value` lines a config file can pull in other files
+//! with `include = path`, relative to the including file, and use
+//! environment variables in values as `${NAME}` (`$$` for a literal
+//! `$`). Keys are set in the order they are read, so a later line wins
+//! over an earlier one and lines in an included file win over lines
+//! before the include but not after it. When loading from a file,
+//! `TW_` environment variables then override everything:
+//! `TW_DB_API_KEY` sets `db.api.key`, with `__` standing for an
+//! underscore in the key (`TW_SERIAL_LOCK__DIR` sets `serial.lock_dir`).
+//!
+//! Files ending in `.toml` are read as TOML instead, see the `toml`
+//! module, and go through the same includes, expansion and overrides.
+use crate::log;
+use crate::secret;
+use crate::toml;
 use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Prefix of environment variables overriding keys
+pub const ENV_PREFIX: &str = "TW_";
+const INCLUDE: &str = "include";
+const SYNTAX: &str = "syntax";
 
-#[derive(Debug)]
 pub struct Config {
     kv_pairs: HashMap<String, String>,
+    /// Where each key was set
+    origins: HashMap<String, Origin>,
+    /// File the config was read from
+    path: Option<String>,
 }
 
 struct KVPair {
     key: String,
     value: String,
+    /// Single quoted, so `${NAME}` isn't expanded
+    literal: bool,
+}
+
+/// Grammar a config file is written in
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Syntax {
+    /// Trimmed keys and values, quoting and escapes
+    #[default]
+    Standard,
+    /// The original grammar. Whitespace around `=` is part of the key
+    /// and value and a `#` anywhere, even in a value, starts a comment.
+    Legacy,
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Syntax, String> {
+        match s {
+            "standard" => Ok(Syntax::Standard),
+            "legacy" => Ok(Syntax::Legacy),
+            _ => Err(format!("Unknown config syntax {}", s)),
+        }
+    }
+}
+
+/// Where the value of a key came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// A line of the config file, or of the included file at path
+    File { path: Option<String>, line: usize },
+    /// An environment variable override
+    Env(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::File { path: None, line } => write!(fmt, "line {}", line),
+            Origin::File {
+                path: Some(p),
+                line,
+            } => write!(fmt, "{} line {}", p, line),
+            Origin::Env(var) => write!(fmt, "environment {}", var),
+        }
+    }
+}
+
+/// Why a line of the config file was skipped or is suspect
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// The line has no `=` between key and value
+    MissingSeparator,
+    EmptyKey,
+    EmptyValue,
+    /// The key was already set at the given place in the same file. The
+    /// later value is the one used.
+    DuplicateKey(Origin),
+    /// A `${NAME}` in the value names a variable that isn't set. It is
+    /// replaced with nothing.
+    UndefinedVariable(String),
+    /// A quoted value has no closing quote
+    UnterminatedQuote,
+    /// A backslash in a double quoted value is followed by something
+    /// other than a known escape
+    InvalidEscape,
+    /// Something other than a comment follows a quoted value
+    TrailingText,
+    /// A `syntax` line names a grammar that doesn't exist
+    UnknownSyntax(String),
+    /// An included file couldn't be read
+    Include(String),
+    /// A file includes itself, directly or not
+    IncludeCycle,
+    /// A TOML file couldn't be parsed
+    Toml(String),
+    /// Converting to TOML moved an include above keys it used to follow,
+    /// so the included file now overrides them
+    IncludeMoved,
 }
+
+impl fmt::Display for Reason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::MissingSeparator => write!(fmt, "missing '=' between key and value"),
+            Reason::EmptyKey => write!(fmt, "empty key"),
+            Reason::EmptyValue => write!(fmt, "empty value"),
+            Reason::DuplicateKey(o) => write!(fmt, "duplicate key, already set on {}", o),
+            Reason::UndefinedVariable(name) => {
+                write!(fmt, "environment variable {} is not set", name)
+            }
+            Reason::UnterminatedQuote => write!(fmt, "missing closing quote"),
+            Reason::InvalidEscape => write!(fmt, "invalid escape sequence"),
+            Reason::TrailingText => write!(fmt, "text after closing quote"),
+            Reason::UnknownSyntax(s) => write!(fmt, "unknown syntax '{}'", s),
+            Reason::Include(e) => write!(fmt, "can't include file: {}", e),
+            Reason::IncludeCycle => write!(fmt, "file is already being included"),
+            Reason::Toml(e) => write!(fmt, "{}", e),
+            Reason::IncludeMoved => write!(fmt, "include moved above earlier keys"),
+        }
+    }
+}
+
+/// A problem found on one line of the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// Included file the line is in, None for the config file itself
+    pub path: Option<String>,
+    pub line: usize,
+    /// Character (not byte) the problem starts at, from 1
+    pub column: usize,
+    /// The offending line, with the value left out if it is a secret
+    pub text: String,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(p) = &self.path {
+            write!(fmt, "{} ", p)?;
+        }
+        write!(
+            fmt,
+            "line {}, column {}: {}: '{}'",
+            self.line, self.column, self.reason, self.text
+        )
+    }
+}
+
+// Written out by hand to keep secrets out of it
+impl fmt::Debug for Config {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let kv_pairs: HashMap<&String, &str> = self
+            .kv_pairs
+            .iter()
+            .map(|(k, v)| match secret::is_secret_key(k.trim()) {
+                true => (k, secret::REDACTED),
+                false => (k, v.as_str()),
+            })
+            .collect();
+        fmt.debug_struct("Config")
+            .field("kv_pairs", &kv_pairs)
+            .field("origins", &self.origins)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
 impl Config {
     /// Retuns a Config parsed from the file path provided
     pub fn new(path: &str) -> Result<Config, std::io::Error> {
-        let file = File::open(path)?;
-        let mut config = Config {
-            kv_pairs: HashMap::new(),
-        };
+        Ok(Config::open_with_diagnostics(path)?.0)
+    }
 
-        let reader = io::BufReader::new(file);
-        for line in reader.lines() {
-            match parse_line(String::from(line?)) {
-                None => None,
-                Some(pair) => config.kv_pairs.insert(pair.key, pair.value),
+    /// Returns a Config parsed from the file path provided, with the
+    /// `TW_` environment overrides applied, along with any problems
+    /// found in it.
+    pub fn open_with_diagnostics(path: &str) -> Result<(Config, Vec<Diagnostic>), io::Error> {
+        let mut reader = Reader::new();
+        reader.read_file(Path::new(path), None)?;
+        reader.config.path = Some(path.to_string());
+        reader.config.apply_overrides(env::vars_os());
+        Ok((reader.config, reader.diagnostics))
+    }
+
+    /// Returns a Config parsed from text in the config file format
+    pub fn parse(text: &str) -> Config {
+        Config::parse_with_diagnostics(text).0
+    }
+
+    /// Returns a Config parsed from text in the config file format
+    /// along with any problems found in it. Lines with problems are
+    /// skipped, except for duplicate keys where the last value wins.
+    /// Includes are relative to the working directory and environment
+    /// overrides are not applied.
+    pub fn parse_with_diagnostics(text: &str) -> (Config, Vec<Diagnostic>) {
+        let mut reader = Reader::new();
+        // Reading from a string can't fail
+        reader.read(text.as_bytes(), None, Path::new("")).unwrap();
+        (reader.config, reader.diagnostics)
+    }
+
+    /// Set keys from `TW_` variables in vars. Other variables are left
+    /// alone, whatever they hold, and `TW_` ones that aren't valid UTF-8
+    /// are skipped.
+    fn apply_overrides(&mut self, vars: impl IntoIterator<Item = (OsString, OsString)>) {
+        for (var, value) in vars {
+            if !var.as_bytes().starts_with(ENV_PREFIX.as_bytes()) {
+                continue;
+            }
+            let (var, value) = match (var.into_string(), value.into_string()) {
+                (Ok(var), Ok(value)) => (var, value),
+                (var, _) => {
+                    let var = var.unwrap_or_else(|v| v.to_string_lossy().into_owned());
+                    log::warn(&format!("Ignoring {}, it is not valid UTF-8", var));
+                    continue;
+                }
             };
+            if let Some(key) = env_key(&var) {
+                self.origins.insert(key.clone(), Origin::Env(var));
+                self.kv_pairs.insert(key, value);
+            }
         }
-
-        return Ok(config);
     }
+
     ///Return a value for a key if it exists.
     pub fn get(&self, key: &str) -> Option<&String> {
         self.kv_pairs.get(key)
     }
+
+    /// Where a key was set
+    pub fn origin(&self, key: &str) -> Option<&Origin> {
+        self.origins.get(key)
+    }
+
+    /// Line of the config file a key was set on
+    pub fn line(&self, key: &str) -> Option<usize> {
+        match self.origins.get(key) {
+            Some(Origin::File { line, .. }) => Some(*line),
+            _ => None,
+        }
+    }
+
+    /// All keys set, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.kv_pairs.keys()
+    }
+
+    /// File the config was read from, None when parsed from text
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Keys added, removed or given a new value in other, sorted by key
+    pub fn diff(&self, other: &Config) -> Vec<Change> {
+        let mut changes: Vec<Change> = self
+            .kv_pairs
+            .iter()
+            .filter_map(|(k, v)| match other.get(k) {
+                None => Some(Change::Removed(k.clone())),
+                Some(new) if new != v => Some(Change::Changed(k.clone())),
+                Some(_) => None,
+            })
+            .chain(
+                other
+                    .keys()
+                    .filter(|k| self.get(k).is_none())
+                    .map(|k| Change::Added(k.clone())),
+            )
+            .collect();
+        changes.sort_by(|a, b| a.key().cmp(b.key()));
+        changes
+    }
+
+    /// Return the value for a key converted to T, failing if it can't
+    /// be converted.
+    pub fn get_as<T: FromValue>(&self, key: &str) -> Result<Option<T>, Error> {
+        match self.get(key) {
+            Some(v) => match T::from_value(v.trim()) {
+                Ok(t) => Ok(Some(t)),
+                Err(e) => Err(Error {
+                    key: key.to_string(),
+                    origin: self.origin(key).cloned(),
+                    description: format!("{}: invalid value '{}': {}", key, v, e),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Keys starting with `prefix.`, sorted
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<&str> {
+        let prefix = format!("{}.", prefix);
+        let mut keys: Vec<&str> = self
+            .kv_pairs
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| k.starts_with(&prefix))
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// View of the keys under `prefix.`, e.g. `section("db")` to read
+    /// `db.host` as `host`.
+    pub fn section(&self, prefix: &str) -> Section<'_> {
+        Section {
+            config: self,
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+/// A key that differs between two configs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+impl Change {
+    pub fn key(&self) -> &str {
+        match self {
+            Change::Added(k) | Change::Removed(k) | Change::Changed(k) => k,
+        }
+    }
+}
+
+/// Values are left out as they may be secrets
+impl fmt::Display for Change {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Added(k) => write!(fmt, "{} added", k),
+            Change::Removed(k) => write!(fmt, "{} removed", k),
+            Change::Changed(k) => write!(fmt, "{} changed", k),
+        }
+    }
+}
+
+/// Keys of a Config under a common prefix, looked up without it
+pub struct Section<'a> {
+    config: &'a Config,
+    prefix: String,
+}
+
+impl<'a> Section<'a> {
+    fn key(&self, key: &str) -> String {
+        format!("{}.{}", self.prefix, key)
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn get(&self, key: &str) -> Option<&'a String> {
+        self.config.get(&self.key(key))
+    }
+
+    pub fn get_as<T: FromValue>(&self, key: &str) -> Result<Option<T>, Error> {
+        self.config.get_as(&self.key(key))
+    }
+
+    pub fn origin(&self, key: &str) -> Option<&'a Origin> {
+        self.config.origin(&self.key(key))
+    }
+
+    /// Keys in the section without the prefix, sorted
+    pub fn keys(&self) -> Vec<&'a str> {
+        let skip = self.prefix.len() + 1;
+        self.config
+            .keys_with_prefix(&self.prefix)
+            .into_iter()
+            .map(|k| &k[skip..])
+            .collect()
+    }
+
+    pub fn section(&self, name: &str) -> Section<'a> {
+        self.config.section(&self.key(name))
+    }
+
+    /// Names of the sections nested in this one, e.g. `garden` and
+    /// `roof` for `station.garden.device` and `station.roof.device`
+    pub fn sections(&self) -> Vec<&'a str> {
+        let mut names: Vec<&str> = self
+            .keys()
+            .into_iter()
+            .filter_map(|k| k.split_once('.').map(|(name, _)| name))
+            .collect();
+        names.dedup();
+        names
+    }
+}
+
+/// A value that can't be converted to the type asked for
+#[derive(Debug)]
+pub struct Error {
+    key: String,
+    origin: Option<Origin>,
+    description: String,
+}
+
+impl Error {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match &self.origin {
+            Some(o) => write!(fmt, "config {}: {}", o, self.description),
+            None => write!(fmt, "config: {}", self.description),
+        }
+    }
+}
+
+/// Types a config value can be read as with `get_as`
+pub trait FromValue: Sized {
+    fn from_value(s: &str) -> Result<Self, String>;
+}
+
+macro_rules! from_str_value {
+    ($($t:ty),*) => {
+        $(impl FromValue for $t {
+            fn from_value(s: &str) -> Result<$t, String> {
+                s.parse().map_err(|e: <$t as FromStr>::Err| e.to_string())
+            }
+        })*
+    };
+}
+
+from_str_value!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64, String);
+
+impl FromValue for bool {
+    fn from_value(s: &str) -> Result<bool, String> {
+        match s.to_lowercase().as_str() {
+            "true" | "yes" | "on" => Ok(true),
+            "false" | "no" | "off" => Ok(false),
+            _ => Err("expected true or false".to_string()),
+        }
+    }
+}
+
+/// A bare number is taken as seconds
+impl FromValue for Duration {
+    fn from_value(s: &str) -> Result<Duration, String> {
+        parse_duration(s, Duration::from_secs(1))
+    }
+}
+
+/// Comma separated, e.g. `9600, 19200`
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(s: &str) -> Result<Vec<T>, String> {
+        s.split(',')
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty())
+            .map(T::from_value)
+            .collect()
+    }
+}
+
+/// A number of bytes, written with an optional `k`, `M` or `G` suffix
+/// for multiples of 1024
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Size(pub u64);
+
+impl FromValue for Size {
+    fn from_value(s: &str) -> Result<Size, String> {
+        let (n, unit) = split_unit(s);
+        let multiple: u64 = match unit.to_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            _ => return Err(format!("unknown size unit '{}'", unit)),
+        };
+        let n: u64 = n.parse().map_err(|_| format!("invalid size '{}'", s))?;
+        n.checked_mul(multiple)
+            .map(Size)
+            .ok_or_else(|| "size too large".to_string())
+    }
+}
+
+/// Split `30s` into `30` and `s`
+fn split_unit(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    let i = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    (&s[..i], s[i..].trim())
+}
+
+/// Parse a duration such as `30s`, `500ms`, `5m`, `1.5h` or `2d`. A
+/// bare number is taken in the given unit.
+pub fn parse_duration(s: &str, unit: Duration) -> Result<Duration, String> {
+    let (n, suffix) = split_unit(s);
+    let unit = match suffix {
+        "" => unit,
+        "ms" => Duration::from_millis(1),
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(60 * 60),
+        "d" => Duration::from_secs(24 * 60 * 60),
+        _ => return Err(format!("unknown duration unit '{}'", suffix)),
+    };
+    let n: f64 = n.parse().map_err(|_| format!("invalid duration '{}'", s))?;
+    Duration::try_from_secs_f64(n * unit.as_secs_f64()).map_err(|e| e.to_string())
+}
+
+/// Key overridden by an environment variable, e.g. `TW_DB_API_KEY`
+/// for `db.api.key`. `__` stands for an underscore.
+fn env_key(var: &str) -> Option<String> {
+    let name = var.strip_prefix(ENV_PREFIX)?;
+    if name.is_empty() {
+        return None;
+    }
+    let key = name
+        .split("__")
+        .map(|part| part.replace('_', "."))
+        .collect::<Vec<String>>()
+        .join("_");
+    Some(key.to_lowercase())
+}
+
+/// Replace `${NAME}` with the value of the environment variable and
+/// `$$` with `$`, returning the names of any variables not set.
+fn expand(value: &str, lookup: impl Fn(&str) -> Option<String>) -> (String, Vec<String>) {
+    let mut expanded = String::new();
+    let mut undefined = Vec::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = r;
+        } else if let Some(end) = rest.strip_prefix("${").and_then(|r| r.find('}')) {
+            let name = &rest[2..end + 2];
+            match lookup(name) {
+                Some(v) => expanded.push_str(&v),
+                None => undefined.push(name.to_string()),
+            }
+            rest = &rest[end + 3..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    (expanded, undefined)
+}
+
+/// Convert text in the config file format to TOML, along with any
+/// problems found in it. Values are converted as written, without
+/// expanding variables or reading includes. Lines with problems are
+/// left out and of duplicate keys the last value is kept.
+pub fn to_toml(text: &str) -> (String, Vec<Diagnostic>) {
+    let mut syntax = Syntax::default();
+    let mut entries: Vec<toml::Entry> = Vec::new();
+    let mut includes = Vec::new();
+    let mut diagnostics = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut problem = |column, reason| {
+            diagnostics.push(Diagnostic {
+                path: None,
+                line: n + 1,
+                column,
+                text: redact(line, line_key(line)),
+                reason,
+            })
+        };
+        match parse_line(line, syntax) {
+            Ok(Some(pair)) if pair.key.trim() == SYNTAX => match pair.value.trim().parse() {
+                Ok(s) => syntax = s,
+                Err(_) => problem(1, Reason::UnknownSyntax(pair.value)),
+            },
+            // TOML has no order between keys, so includes go first
+            Ok(Some(pair)) if pair.key.trim() == INCLUDE => {
+                if !entries.is_empty() {
+                    problem(1, Reason::IncludeMoved);
+                }
+                let path = pair.value.trim();
+                includes.push(match pair.literal {
+                    true => path.replace('$', "$$"),
+                    false => path.to_string(),
+                });
+            }
+            Ok(Some(pair)) => match entries.iter_mut().find(|e| e.key == pair.key) {
+                Some(first) => {
+                    let origin = Origin::File {
+                        path: None,
+                        line: first.line,
+                    };
+                    problem(1, Reason::DuplicateKey(origin));
+                    first.value = pair.value;
+                    first.literal = pair.literal;
+                }
+                None => entries.push(toml::Entry {
+                    key: pair.key,
+                    value: pair.value,
+                    literal: pair.literal,
+                    items: Vec::new(),
+                    line: n + 1,
+                }),
+            },
+            Ok(None) => (),
+            Err((column, reason)) => problem(column, reason),
+        }
+    }
+    if !includes.is_empty() {
+        let include = toml::Entry {
+            key: INCLUDE.to_string(),
+            value: includes.join(", "),
+            literal: false,
+            items: includes,
+            line: 0,
+        };
+        entries.insert(0, include);
+    }
+    (toml::write(&entries), diagnostics)
+}
+
+/// Reads a config file and the files it includes
+struct Reader {
+    config: Config,
+    diagnostics: Vec<Diagnostic>,
+    /// Files being read, to catch includes that loop
+    stack: Vec<PathBuf>,
+}
+
+impl Reader {
+    fn new() -> Reader {
+        Reader {
+            config: Config {
+                kv_pairs: HashMap::new(),
+                origins: HashMap::new(),
+                path: None,
+            },
+            diagnostics: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Read the file at path, as TOML when it ends in `.toml`. name is
+    /// how lines from it are reported, None for the top level file.
+    fn read_file(&mut self, path: &Path, name: Option<String>) -> io::Result<()> {
+        let file = File::open(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        self.stack.push(path.canonicalize()?);
+        let res = match is_toml(path) {
+            true => io::read_to_string(file).map(|text| self.read_toml(&text, name, dir)),
+            false => self.read(io::BufReader::new(file), name, dir),
+        };
+        self.stack.pop();
+        res
+    }
+
+    fn read(&mut self, reader: impl BufRead, name: Option<String>, dir: &Path) -> io::Result<()> {
+        // Each file starts out in the standard syntax
+        let mut syntax = Syntax::default();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            let origin = Origin::File {
+                path: name.clone(),
+                line: n + 1,
+            };
+            let problems = match parse_line(&line, syntax) {
+                Ok(Some(pair)) if pair.key.trim() == SYNTAX => match pair.value.trim().parse() {
+                    Ok(s) => {
+                        syntax = s;
+                        Vec::new()
+                    }
+                    Err(_) => vec![(1, Reason::UnknownSyntax(pair.value))],
+                },
+                Ok(Some(pair)) => self.set(pair, origin, &line, dir),
+                Ok(None) => Vec::new(),
+                Err(problem) => vec![problem],
+            };
+            self.report(&name, n + 1, &line, line_key(&line), problems);
+        }
+        Ok(())
+    }
+
+    fn read_toml(&mut self, text: &str, name: Option<String>, dir: &Path) {
+        let lines: Vec<&str> = text.lines().collect();
+        let line_text = |n: usize| lines.get(n - 1).copied().unwrap_or("");
+        let (entries, problems) = toml::parse(text);
+        for entry in entries {
+            let origin = Origin::File {
+                path: name.clone(),
+                line: entry.line,
+            };
+            // Any number of files can be included as an array
+            let values = match entry.key == INCLUDE && !entry.items.is_empty() {
+                true => entry.items,
+                false => vec![entry.value],
+            };
+            for value in values {
+                let pair = KVPair {
+                    key: entry.key.clone(),
+                    value,
+                    literal: entry.literal,
+                };
+                let text = line_text(entry.line);
+                let problems = self.set(pair, origin.clone(), text, dir);
+                self.report(&name, entry.line, text, &entry.key, problems);
+            }
+        }
+        for p in problems {
+            let key = p.key.unwrap_or_default();
+            let problems = vec![(p.column, Reason::Toml(p.message))];
+            self.report(&name, p.line, line_text(p.line), &key, problems);
+        }
+    }
+
+    /// Expand and set a key, or follow an include, returning any
+    /// problems along with the column in line they are at.
+    fn set(
+        &mut self,
+        pair: KVPair,
+        origin: Origin,
+        line: &str,
+        dir: &Path,
+    ) -> Vec<(usize, Reason)> {
+        let mut problems = Vec::new();
+        let (value, undefined) = match pair.literal {
+            true => (pair.value, Vec::new()),
+            false => expand(&pair.value, |name| env::var(name).ok()),
+        };
+        for name in undefined {
+            let column = line
+                .find(&format!("${{{}}}", name))
+                .map(|i| line[..i].chars().count() + 1)
+                .unwrap_or(1);
+            problems.push((column, Reason::UndefinedVariable(name)));
+        }
+        if pair.key.trim() == INCLUDE {
+            if let Some(reason) = self.include(dir.join(value.trim())) {
+                problems.push((1, reason));
+            }
+        } else {
+            let first = self.config.origins.insert(pair.key.clone(), origin.clone());
+            // A key set again by another file is an override, not a mistake
+            if let Some(first) = first.filter(|first| same_file(first, &origin)) {
+                problems.push((1, Reason::DuplicateKey(first)));
+            }
+            self.config.kv_pairs.insert(pair.key, value);
+        }
+        problems
+    }
+
+    fn report(
+        &mut self,
+        name: &Option<String>,
+        line: usize,
+        text: &str,
+        key: &str,
+        problems: Vec<(usize, Reason)>,
+    ) {
+        for (column, reason) in problems {
+            self.diagnostics.push(Diagnostic {
+                path: name.clone(),
+                line,
+                column,
+                text: redact(text, key),
+                reason,
+            });
+        }
+    }
+
+    /// Read an included file, returning why if it can't be
+    fn include(&mut self, path: PathBuf) -> Option<Reason> {
+        if let Ok(canonical) = path.canonicalize() {
+            if self.stack.contains(&canonical) {
+                return Some(Reason::IncludeCycle);
+            }
+        }
+        let name = path.display().to_string();
+        match self.read_file(&path, Some(name)) {
+            Ok(()) => None,
+            Err(e) => Some(Reason::Include(e.to_string())),
+        }
+    }
+}
+
+fn same_file(a: &Origin, b: &Origin) -> bool {
+    match (a, b) {
+        (Origin::File { path: a, .. }, Origin::File { path: b, .. }) => a == b,
+        _ => false,
+    }
+}
+
+/// Key of a `key = value` line, as far as it can be told
+fn line_key(line: &str) -> &str {
+    line.split('=').next().unwrap_or("").trim()
+}
+
+/// The trimmed line, with anything after the `=` left out when key is
+/// a secret
+fn redact(line: &str, key: &str) -> String {
+    let line = line.trim();
+    if !secret::is_secret_key(key.trim()) {
+        return line.to_string();
+    }
+    match line.find('=') {
+        Some(i) => format!("{}= {}", &line[..i], secret::REDACTED),
+        None => secret::REDACTED.to_string(),
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "toml")
 }
 
 fn filter_comments(line: &str) -> String {
-    let comment_pos = match line.find("#") {
+    let comment_pos = match line.find('#') {
         Some(i) => i,
         None => return line.to_string(),
     };
@@ -45,22 +864,140 @@ fn filter_comments(line: &str) -> String {
     filtered.trim().to_string()
 }
 
-fn parse_line(line: String) -> Option<KVPair> {
-    let filtered = filter_comments(&line);
-    // TODO: Find works fine here with ASCII text
-    // but fails to work with unicode. Need
-    // to find a better way to do this
-    let sep_position = match filtered.find("=") {
+/// Parse a line into a key value pair. Blank and comment lines give
+/// None, lines that can't be used the column and reason why.
+fn parse_line(line: &str, syntax: Syntax) -> Result<Option<KVPair>, (usize, Reason)> {
+    match syntax {
+        Syntax::Standard => parse_standard(line),
+        Syntax::Legacy => parse_legacy(line),
+    }
+}
+
+fn parse_legacy(line: &str) -> Result<Option<KVPair>, (usize, Reason)> {
+    let filtered = filter_comments(line);
+    if filtered.trim().is_empty() {
+        return Ok(None);
+    }
+    let sep_position = match filtered.find('=') {
         Some(i) => i,
-        None => return None,
+        None => return Err((1, Reason::MissingSeparator)),
+    };
+    let column = filtered[..sep_position].chars().count() + 1;
+    if filtered[..sep_position].is_empty() {
+        return Err((column, Reason::EmptyKey));
+    }
+    if filtered[sep_position + 1..].is_empty() {
+        return Err((column + 1, Reason::EmptyValue));
+    }
+    Ok(Some(KVPair {
+        key: filtered[..sep_position].to_string(),
+        value: filtered[sep_position + 1..].to_string(),
+        literal: false,
+    }))
+}
+
+fn parse_standard(line: &str) -> Result<Option<KVPair>, (usize, Reason)> {
+    // Working in chars keeps columns right for any text
+    let chars: Vec<char> = line.chars().collect();
+    let skip_space = |mut i: usize| {
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    };
+    let start = skip_space(0);
+    if start == chars.len() || chars[start] == '#' {
+        return Ok(None);
+    }
+    let sep = match chars[start..].iter().position(|c| *c == '=' || *c == '#') {
+        Some(i) if chars[start + i] == '=' => start + i,
+        _ => return Err((start + 1, Reason::MissingSeparator)),
+    };
+    let key: String = chars[start..sep].iter().collect();
+    let key = key.trim_end().to_string();
+    if key.is_empty() {
+        return Err((sep + 1, Reason::EmptyKey));
+    }
+
+    let i = skip_space(sep + 1);
+    let (value, end, literal) = match chars.get(i) {
+        None | Some('#') => return Err((sep + 2, Reason::EmptyValue)),
+        Some('"') => {
+            let (value, end) = unescape(&chars, i + 1)?;
+            (value, end, false)
+        }
+        Some('\'') => match chars[i + 1..].iter().position(|c| *c == '\'') {
+            Some(n) => (chars[i + 1..i + 1 + n].iter().collect(), i + n + 2, true),
+            None => return Err((i + 1, Reason::UnterminatedQuote)),
+        },
+        Some(_) => {
+            let end = chars[i..]
+                .iter()
+                .position(|c| *c == '#')
+                .map_or(chars.len(), |n| i + n);
+            let value: String = chars[i..end].iter().collect();
+            (value.trim_end().to_string(), end, false)
+        }
     };
-    if !filtered[..sep_position].is_empty() && !filtered[sep_position + 1..].is_empty() {
-        return Some(KVPair {
-            key: filtered[..sep_position].to_string(),
-            value: filtered[sep_position + 1..].to_string(),
-        });
+    // Only a comment can follow a quoted value
+    let rest = skip_space(end);
+    if rest < chars.len() && chars[rest] != '#' {
+        return Err((rest + 1, Reason::TrailingText));
     }
-    return None;
+    Ok(Some(KVPair {
+        key,
+        value,
+        literal,
+    }))
+}
+
+/// Read a double quoted value starting just after the opening quote,
+/// returning it and the index just past the closing quote.
+fn unescape(chars: &[char], start: usize) -> Result<(String, usize), (usize, Reason)> {
+    let mut value = String::new();
+    let mut i = start;
+    loop {
+        match chars.get(i) {
+            None => return Err((start, Reason::UnterminatedQuote)),
+            Some('"') => return Ok((value, i + 1)),
+            Some('\\') => {
+                let escaped = match chars.get(i + 1) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => match unicode_escape(chars, i + 2) {
+                        Some((c, end)) => {
+                            value.push(c);
+                            i = end;
+                            continue;
+                        }
+                        None => return Err((i + 1, Reason::InvalidEscape)),
+                    },
+                    _ => return Err((i + 1, Reason::InvalidEscape)),
+                };
+                value.push(escaped);
+                i += 2;
+            }
+            Some(c) => {
+                value.push(*c);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Read the `{XXXX}` of a `\u{XXXX}` escape, returning the character
+/// and the index just past the closing brace.
+fn unicode_escape(chars: &[char], start: usize) -> Option<(char, usize)> {
+    if chars.get(start) != Some(&'{') {
+        return None;
+    }
+    let len = chars[start + 1..].iter().position(|c| *c == '}')?;
+    let hex: String = chars[start + 1..start + 1 + len].iter().collect();
+    let c = char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?;
+    Some((c, start + len + 2))
 }
 
 #[cfg(test)]
@@ -69,6 +1006,7 @@ mod tests {
     use super::*;
     use std::fs;
     use std::io::Write;
+    use std::os::unix::ffi::OsStringExt;
     fn create_empty(s: &String) {
         File::create(s).expect("Error creating test cfg file");
     }
@@ -77,9 +1015,9 @@ mod tests {
         fs::remove_file(s).expect("Unable to remove test cfg");
     }
     fn write(file: &String, s: &String) {
-        let mut f = fs::OpenOptions::new().append(true).open(&file).unwrap();
-        f.write(s.as_bytes()).unwrap();
-        f.write("\n".as_bytes()).unwrap();
+        let mut f = fs::OpenOptions::new().append(true).open(file).unwrap();
+        f.write_all(s.as_bytes()).unwrap();
+        f.write_all("\n".as_bytes()).unwrap();
     }
 
     // Invalid paths should return an Error
@@ -87,7 +1025,7 @@ mod tests {
     #[should_panic]
     fn test_invalid_path() {
         //TODO: Implement PartialEq?
-        let res = Config::new(&String::from("none.text")).unwrap();
+        let _res = Config::new(&String::from("none.text")).unwrap();
         //let expected = Err(std::io::ErrorKind::NotFound);
         //assert_eq!(expected, res);
     }
@@ -111,7 +1049,7 @@ mod tests {
         write(&file, &comment);
         let res = Config::new(&file).unwrap();
         let value = res.get("#Test comment");
-        assert!(!value.is_some());
+        assert!(value.is_none());
         delete_file(&file);
     }
 
@@ -141,7 +1079,7 @@ mod tests {
         let res = Config::new(&file).unwrap();
         let value = res.get("key");
         assert_eq!(Some(&String::from("value")), value);
-        assert!(!res.get("#key1").is_some());
+        assert!(res.get("#key1").is_none());
         delete_file(&file);
     }
 
@@ -156,7 +1094,398 @@ mod tests {
         write(&file, &invalid);
         write(&file, &invalid1);
         let res = Config::new(&file).unwrap();
-        assert!(!res.get("key").is_some());
+        assert!(res.get("key").is_none());
         delete_file(&file);
     }
+
+    // The last line setting a key wins and is the one reported
+    #[test]
+    fn test_line_numbers() {
+        let res = Config::parse("# header\na=1\n\nb=2\na=3\n");
+        assert_eq!(Some(&String::from("3")), res.get("a"));
+        assert_eq!(Some(5), res.line("a"));
+        assert_eq!(Some(4), res.line("b"));
+        assert_eq!(None, res.line("c"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let text = "# header\nserial.baud 9600\n=value\nkey= # comment\nb=1\n\nb=2\n";
+        let (res, diagnostics) = Config::parse_with_diagnostics(text);
+        assert_eq!(Some(&String::from("2")), res.get("b"));
+        let found: Vec<(usize, usize, Reason)> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.reason.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (2, 1, Reason::MissingSeparator),
+                (3, 1, Reason::EmptyKey),
+                (4, 5, Reason::EmptyValue),
+                (
+                    7,
+                    1,
+                    Reason::DuplicateKey(Origin::File {
+                        path: None,
+                        line: 5
+                    })
+                ),
+            ],
+            found
+        );
+        assert_eq!(
+            "line 2, column 1: missing '=' between key and value: 'serial.baud 9600'",
+            diagnostics[0].to_string()
+        );
+        assert_eq!("key= # comment", diagnostics[2].text);
+
+        // Columns count characters rather than bytes
+        let (_, diagnostics) = Config::parse_with_diagnostics("température=\n");
+        assert_eq!(13, diagnostics[0].column);
+    }
+
+    #[test]
+    fn test_env_key() {
+        assert_eq!(Some("db.api.key".to_string()), env_key("TW_DB_API_KEY"));
+        assert_eq!(
+            Some("serial.lock_dir".to_string()),
+            env_key("TW_SERIAL_LOCK__DIR")
+        );
+        assert_eq!(None, env_key("TW_"));
+        assert_eq!(None, env_key("HOME"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| match name {
+            "HOST" => Some("pi-garden".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            ("http://pi-garden:8086".to_string(), vec![]),
+            expand("http://${HOST}:8086", lookup)
+        );
+        assert_eq!(
+            ("a$b ${HOST".to_string(), vec![]),
+            expand("a$$b ${HOST", lookup)
+        );
+        assert_eq!(
+            ("/dev/".to_string(), vec!["NOPE".to_string()]),
+            expand("/dev/${NOPE}", lookup)
+        );
+    }
+
+    // Later lines win, an include counts as the lines it includes and
+    // environment overrides win over everything
+    #[test]
+    fn test_include_precedence() {
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_include_{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(
+            dir.join("config"),
+            "a=main\nb=main\ninclude = conf.d/site\nb=after\ninclude=missing\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("conf.d/site"),
+            "a=site\nb=site\nc=site\ninclude=../config\n",
+        )
+        .unwrap();
+
+        let path = dir.join("config");
+        let (mut res, diagnostics) = Config::open_with_diagnostics(path.to_str().unwrap()).unwrap();
+        assert_eq!(Some(&String::from("site")), res.get("a"));
+        assert_eq!(Some(&String::from("after")), res.get("b"));
+        assert_eq!(Some(&String::from("site")), res.get("c"));
+        assert!(res.get("include").is_none());
+        let site = dir.join("conf.d/site").display().to_string();
+        assert_eq!(
+            Some(&Origin::File {
+                path: Some(site.clone()),
+                line: 1
+            }),
+            res.origin("a")
+        );
+
+        let found: Vec<(Option<String>, usize, Reason)> = diagnostics
+            .iter()
+            .map(|d| (d.path.clone(), d.line, d.reason.clone()))
+            .collect();
+        // Keys set again by the included file are overrides
+        assert!(!found
+            .iter()
+            .any(|(_, _, reason)| matches!(reason, Reason::DuplicateKey(_))));
+        assert!(found.contains(&(Some(site), 4, Reason::IncludeCycle)));
+        assert!(matches!(
+            found.last().unwrap(),
+            (None, 5, Reason::Include(_))
+        ));
+
+        res.apply_overrides(vec![
+            (OsString::from("TW_A"), OsString::from("env")),
+            (OsString::from("PATH"), OsString::from("/bin")),
+            (OsString::from("TW_B"), OsString::from_vec(vec![0x66, 0xff])),
+            (OsString::from_vec(vec![0xff]), OsString::from("x")),
+        ]);
+        assert_eq!(Some(&String::from("env")), res.get("a"));
+        assert_eq!(Some(&Origin::Env("TW_A".to_string())), res.origin("a"));
+        assert_eq!(None, res.line("a"));
+        assert!(res.get("path").is_none());
+        assert_eq!(Some(&String::from("after")), res.get("b"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_quoting() {
+        let text = r#"
+  db.api.key = "abc#123 "  # the token
+db.api.endpoint=/api/v2/write?org=home#frag
+escapes = "a\"b\\c\td\u{e9}"
+literal = '${HOME} \n'
+unicode.clé = värde
+"#;
+        let (res, diagnostics) = Config::parse_with_diagnostics(text);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(Some(&String::from("abc#123 ")), res.get("db.api.key"));
+        assert_eq!(
+            Some(&String::from("/api/v2/write?org=home")),
+            res.get("db.api.endpoint")
+        );
+        assert_eq!(Some(&String::from("a\"b\\c\tdé")), res.get("escapes"));
+        assert_eq!(Some(&String::from("${HOME} \\n")), res.get("literal"));
+        assert_eq!(Some(&String::from("värde")), res.get("unicode.clé"));
+
+        let text = "a=\"open\nb=\"bad\\q\"\nc=\"x\" y\nd = \"\"\n";
+        let (res, diagnostics) = Config::parse_with_diagnostics(text);
+        let found: Vec<(usize, usize, Reason)> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.reason.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, 3, Reason::UnterminatedQuote),
+                (2, 7, Reason::InvalidEscape),
+                (3, 7, Reason::TrailingText),
+            ],
+            found
+        );
+        assert_eq!(Some(&String::new()), res.get("d"));
+    }
+
+    #[test]
+    fn test_legacy_syntax() {
+        let text = "a = 1\nsyntax = legacy\nb = 2 # comment\nc=\"x#y\"\nsyntax=modern\n";
+        let (res, diagnostics) = Config::parse_with_diagnostics(text);
+        assert_eq!(Some(&String::from("1")), res.get("a"));
+        assert_eq!(Some(&String::from(" 2")), res.get("b "));
+        assert_eq!(Some(&String::from("\"x")), res.get("c"));
+        assert!(res.get("syntax").is_none());
+        assert_eq!(
+            Reason::UnknownSyntax("modern".to_string()),
+            diagnostics[0].reason
+        );
+    }
+
+    #[test]
+    fn test_get_as() {
+        let res = Config::parse(
+            "n = 42\nf = 1.5\nb = yes\nt = 1500ms\nbare = 30\nsize = 4k\nrates = 9600, 19200,\nbad = 4x\n",
+        );
+        assert_eq!(Some(42u32), res.get_as("n").unwrap());
+        assert_eq!(Some(1.5f64), res.get_as("f").unwrap());
+        assert_eq!(Some(true), res.get_as("b").unwrap());
+        assert_eq!(Some(Duration::from_millis(1500)), res.get_as("t").unwrap());
+        assert_eq!(Some(Duration::from_secs(30)), res.get_as("bare").unwrap());
+        assert_eq!(Some(Size(4096)), res.get_as("size").unwrap());
+        assert_eq!(Some(vec![9600u32, 19200]), res.get_as("rates").unwrap());
+        assert_eq!(None, res.get_as::<u32>("missing").unwrap());
+
+        let e = res.get_as::<u8>("bad").unwrap_err();
+        assert_eq!("bad", e.key());
+        assert!(e
+            .to_string()
+            .starts_with("config line 8: bad: invalid value '4x'"));
+        assert!(res.get_as::<Size>("bad").is_err());
+        assert!(res.get_as::<Duration>("bad").is_err());
+        assert!(res.get_as::<u8>("n").is_ok());
+        assert!(res.get_as::<bool>("n").is_err());
+
+        assert_eq!(
+            Ok(Duration::from_secs(90 * 60)),
+            parse_duration("1.5h", Duration::from_secs(1))
+        );
+        assert_eq!(
+            Ok(Duration::from_millis(250)),
+            parse_duration("250", Duration::from_millis(1))
+        );
+        assert!(parse_duration("-1s", Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_sections() {
+        let res = Config::parse(
+            "station.reset_after=3\nstation.roof.device=/dev/ttyUSB1\nstation.garden.device=/dev/ttyUSB0\nstation.garden.baud=9600\nstations=2\n",
+        );
+        assert_eq!(
+            vec![
+                "station.garden.baud",
+                "station.garden.device",
+                "station.reset_after",
+                "station.roof.device"
+            ],
+            res.keys_with_prefix("station")
+        );
+
+        let stations = res.section("station");
+        assert_eq!(vec!["garden", "roof"], stations.sections());
+        assert_eq!(Some(3u32), stations.get_as("reset_after").unwrap());
+
+        let garden = stations.section("garden");
+        assert_eq!("station.garden", garden.prefix());
+        assert_eq!(vec!["baud", "device"], garden.keys());
+        assert_eq!(Some(&String::from("/dev/ttyUSB0")), garden.get("device"));
+        assert_eq!(Some(9600u32), garden.get_as("baud").unwrap());
+        assert_eq!(
+            Some(4),
+            garden.origin("baud").and_then(|o| match o {
+                Origin::File { line, .. } => Some(*line),
+                Origin::Env(_) => None,
+            })
+        );
+        assert!(res.section("db").keys().is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = Config::parse("a=1\nb=2\nc=3\n");
+        let new = Config::parse("b=2\nc=4\nd=5\n");
+        assert_eq!(
+            vec![
+                Change::Removed("a".to_string()),
+                Change::Changed("c".to_string()),
+                Change::Added("d".to_string()),
+            ],
+            old.diff(&new)
+        );
+        assert!(new.diff(&new).is_empty());
+        assert_eq!("c changed", old.diff(&new)[1].to_string());
+        assert_eq!(None, old.path());
+    }
+
+    #[test]
+    fn test_toml() {
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_toml_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("site"), "serial.baud=19200\nlog.level=debug\n").unwrap();
+        fs::write(dir.join("extra.toml"), "[db]\nport = 8086\n").unwrap();
+        fs::write(
+            dir.join("config.toml"),
+            "include = [\"site\", \"extra.toml\"]\n\n[serial]\nbaud = 9600\n\
+             match = { usb_vid = 0x0403 }\n[db.api]\nkey = '${TOKEN}'\n\
+             endpoint = \"${TW_CTRL_UNSET}\"\nbad = nope\n",
+        )
+        .unwrap();
+
+        let path = dir.join("config.toml");
+        let (res, diagnostics) = Config::open_with_diagnostics(path.to_str().unwrap()).unwrap();
+        assert_eq!(Some(&String::from("9600")), res.get("serial.baud"));
+        assert_eq!(Some(&String::from("debug")), res.get("log.level"));
+        assert_eq!(Some(&String::from("8086")), res.get("db.port"));
+        assert_eq!(
+            Some(&String::from("0x0403")),
+            res.get("serial.match.usb_vid")
+        );
+        assert_eq!(Some(&String::from("${TOKEN}")), res.get("db.api.key"));
+        assert_eq!(Some(&String::from("")), res.get("db.api.endpoint"));
+        assert_eq!(Some(5), res.line("serial.match.usb_vid"));
+
+        let found: Vec<(usize, Reason)> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.reason.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (8, Reason::UndefinedVariable("TW_CTRL_UNSET".to_string())),
+                (9, Reason::Toml("invalid value 'nope'".to_string())),
+            ],
+            found
+        );
+        assert_eq!("bad = nope", diagnostics[1].text);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let text = "a = 1\nserial.baud = 9600\nserial.baud.candidates = 9600, 19200\n\
+                    log.level = info # comment\ninclude = site\nlog.level = debug\n\
+                    db.api.key = '${TOKEN}'\nbroken\nsyntax = legacy\nx = \"#y\n";
+        let (converted, diagnostics) = to_toml(text);
+        let reasons: Vec<Reason> = diagnostics.iter().map(|d| d.reason.clone()).collect();
+        assert_eq!(
+            vec![
+                Reason::IncludeMoved,
+                Reason::DuplicateKey(Origin::File {
+                    path: None,
+                    line: 4
+                }),
+                Reason::MissingSeparator,
+            ],
+            reasons
+        );
+
+        // The converted file sets the same keys to the same values
+        let (original, _) = Config::parse_with_diagnostics(text);
+        let (entries, problems) = toml::parse(&converted);
+        assert!(problems.is_empty(), "{:?}", problems);
+        let mut keys: Vec<&String> = original.keys().collect();
+        keys.sort();
+        let mut converted_keys: Vec<&String> = entries
+            .iter()
+            .map(|e| &e.key)
+            .filter(|k| *k != "include")
+            .collect();
+        converted_keys.sort();
+        assert_eq!(keys, converted_keys);
+        for e in entries.iter().filter(|e| e.key != "include") {
+            assert_eq!(Some(&e.value), original.get(&e.key), "{}", e.key);
+        }
+        assert!(converted.starts_with("include = \"site\"\n"));
+        assert!(converted.contains("api.key = '${TOKEN}'\n"));
+        assert!(converted.contains("\"x \" = \" \\\"\"\n"));
+    }
+
+    // Diagnostics on lines setting secrets, and Debug, leave values out
+    #[test]
+    fn test_redacted() {
+        let text = "db.api.key = first\ndb.api.key = second\nchannel.auth.key = \"abc\n\
+                    db.api.key = 'x' trailing\nlog.level = \"debug\" x\n";
+        let (res, diagnostics) = Config::parse_with_diagnostics(text);
+        assert_eq!(4, diagnostics.len());
+        for d in &diagnostics[..3] {
+            let shown = d.to_string();
+            for value in ["first", "second", "abc", "trailing"] {
+                assert!(!shown.contains(value), "{}", shown);
+            }
+        }
+        assert_eq!("db.api.key = [redacted]", diagnostics[0].text);
+        assert!(diagnostics[3].to_string().contains("debug"));
+        assert!(!format!("{:?}", res).contains("second"));
+        assert!(format!("{:?}", res).contains("\"db.api.key\": \"[redacted]\""));
+
+        let mut reader = Reader::new();
+        let toml = "[db.api]\nkey = \"unterminated\n[db]\napi = { key = 'a', key = 'b' }\n";
+        reader.read_toml(toml, None, Path::new(""));
+        assert_eq!(2, reader.diagnostics.len());
+        for d in &reader.diagnostics {
+            let shown = d.to_string();
+            assert!(
+                !shown.contains("unterminated") && !shown.contains("'b'"),
+                "{}",
+                shown
+            );
+        }
+
+        let (_, diagnostics) = to_toml("db.api.key = first\ndb.api.key = second\n");
+        assert!(!diagnostics[0].to_string().contains("second"));
+    }
 }
@@ -0,0 +1,211 @@
+//! This module provides table driven CRC calculations. The supported
+//! algorithms are CRC16 XMODEM (the default used by the channel),
+//! CRC16 CCITT-FALSE, CRC16 MODBUS and CRC32 (as used by zlib/ethernet).
+use std::fmt;
+use std::str::FromStr;
+
+/// CRC algorithms available for frame checks
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Algorithm {
+    #[default]
+    Crc16Xmodem,
+    Crc16CcittFalse,
+    Crc16Modbus,
+    Crc32,
+}
+
+// Lookup tables are built at compile time. Non-reflected algorithms
+// process the most significant bit first, reflected ones the least.
+static CRC16_CCITT_TABLE: [u16; 256] = make_table16(0x1021);
+static CRC16_MODBUS_TABLE: [u16; 256] = make_table16_reflected(0xa001);
+static CRC32_TABLE: [u32; 256] = make_table32_reflected(0xedb8_8320);
+
+const fn make_table16(poly: u16) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn make_table16_reflected(poly: u16) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn make_table32_reflected(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc16(table: &[u16; 256], init: u16, arr: &[u8]) -> u16 {
+    arr.iter().fold(init, |crc, b| {
+        (crc << 8) ^ table[((crc >> 8) as u8 ^ b) as usize]
+    })
+}
+
+fn crc16_reflected(table: &[u16; 256], init: u16, arr: &[u8]) -> u16 {
+    arr.iter()
+        .fold(init, |crc, b| (crc >> 8) ^ table[(crc as u8 ^ b) as usize])
+}
+
+fn crc32_reflected(table: &[u32; 256], init: u32, arr: &[u8]) -> u32 {
+    arr.iter()
+        .fold(init, |crc, b| (crc >> 8) ^ table[(crc as u8 ^ b) as usize])
+}
+
+impl Algorithm {
+    ///Calculate the checksum for an arr of bytes
+    pub fn checksum(&self, arr: &[u8]) -> u32 {
+        match self {
+            Algorithm::Crc16Xmodem => crc16(&CRC16_CCITT_TABLE, 0, arr) as u32,
+            Algorithm::Crc16CcittFalse => crc16(&CRC16_CCITT_TABLE, 0xffff, arr) as u32,
+            Algorithm::Crc16Modbus => crc16_reflected(&CRC16_MODBUS_TABLE, 0xffff, arr) as u32,
+            Algorithm::Crc32 => !crc32_reflected(&CRC32_TABLE, 0xffff_ffff, arr),
+        }
+    }
+
+    /// Number of bytes the checksum takes up in a frame
+    pub fn width(&self) -> usize {
+        match self {
+            Algorithm::Crc32 => 4,
+            _ => 2,
+        }
+    }
+
+    /// Calculate the checksum for arr and return it as little
+    /// endian bytes, `width` bytes long.
+    pub fn checksum_bytes(&self, arr: &[u8]) -> Vec<u8> {
+        self.checksum(arr).to_le_bytes()[..self.width()].to_vec()
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Algorithm::Crc16Xmodem => "xmodem",
+            Algorithm::Crc16CcittFalse => "ccitt-false",
+            Algorithm::Crc16Modbus => "modbus",
+            Algorithm::Crc32 => "crc32",
+        })
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Algorithm, String> {
+        match s.to_lowercase().as_str() {
+            "xmodem" => Ok(Algorithm::Crc16Xmodem),
+            "ccitt-false" => Ok(Algorithm::Crc16CcittFalse),
+            "modbus" => Ok(Algorithm::Crc16Modbus),
+            "crc32" => Ok(Algorithm::Crc32),
+            _ => Err(format!("Not an available CRC algorithm: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The standard check input for CRC catalogues
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn test_xmodem() {
+        assert_eq!(0x31c3, Algorithm::Crc16Xmodem.checksum(CHECK));
+        assert_eq!(0, Algorithm::Crc16Xmodem.checksum(&[]));
+    }
+
+    #[test]
+    fn test_ccitt_false() {
+        assert_eq!(0x29b1, Algorithm::Crc16CcittFalse.checksum(CHECK));
+        assert_eq!(0xffff, Algorithm::Crc16CcittFalse.checksum(&[]));
+    }
+
+    #[test]
+    fn test_modbus() {
+        assert_eq!(0x4b37, Algorithm::Crc16Modbus.checksum(CHECK));
+        assert_eq!(0xffff, Algorithm::Crc16Modbus.checksum(&[]));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xcbf4_3926, Algorithm::Crc32.checksum(CHECK));
+        assert_eq!(0, Algorithm::Crc32.checksum(&[]));
+        assert_eq!(
+            0x414f_a339,
+            Algorithm::Crc32.checksum(b"The quick brown fox jumps over the lazy dog")
+        );
+    }
+
+    // Check bytes go out low byte first
+    #[test]
+    fn test_checksum_bytes() {
+        assert_eq!(
+            vec![0xc3, 0x31],
+            Algorithm::Crc16Xmodem.checksum_bytes(CHECK)
+        );
+        assert_eq!(
+            vec![0x26, 0x39, 0xf4, 0xcb],
+            Algorithm::Crc32.checksum_bytes(CHECK)
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        for alg in [
+            Algorithm::Crc16Xmodem,
+            Algorithm::Crc16CcittFalse,
+            Algorithm::Crc16Modbus,
+            Algorithm::Crc32,
+        ]
+        .iter()
+        {
+            assert_eq!(Ok(*alg), alg.to_string().parse());
+        }
+        assert!("crc64".parse::<Algorithm>().is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! This module finds the serial ports present on the system by walking
+//! sysfs (`/sys/class/tty`).
+//!
+//! Paths like `/dev/ttyUSB0` are handed out in the order devices are plugged
+//! in, so they can change between reboots and replugs. The USB attributes
+//! (vendor and product ID, serial number) and driver of each port are read
+//! from sysfs as well so the station can be found by what it is rather than
+//! where it happens to be.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const SYS_CLASS_TTY: &str = "/sys/class/tty";
+const DEV: &str = "/dev";
+
+/// USB attributes of the device a port belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsbInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// A serial port found in sysfs
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortInfo {
+    /// Name of the tty, e.g. ttyUSB0
+    pub name: String,
+    /// Path of the device node
+    pub path: String,
+    /// Name of the kernel driver bound to the port
+    pub driver: Option<String>,
+    /// Set when the port is on a USB device
+    pub usb: Option<UsbInfo>,
+}
+
+/// List the serial ports present on the system.
+pub fn available_ports() -> io::Result<Vec<PortInfo>> {
+    ports_in(Path::new(SYS_CLASS_TTY), Path::new(DEV))
+}
+
+/// List the serial ports found under a sysfs tty class directory,
+/// with device nodes under `dev`.
+///
+/// Ttys without a backing device (virtual consoles, ptys) are skipped.
+pub fn ports_in(class_dir: &Path, dev: &Path) -> io::Result<Vec<PortInfo>> {
+    let mut ports = Vec::new();
+    for entry in fs::read_dir(class_dir)? {
+        let entry = entry?;
+        let device = match fs::canonicalize(entry.path().join("device")) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        ports.push(PortInfo {
+            path: dev.join(&name).to_string_lossy().to_string(),
+            name,
+            driver: driver_name(&device),
+            usb: usb_info(&device),
+        });
+    }
+    ports.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ports)
+}
+
+fn driver_name(device: &Path) -> Option<String> {
+    let driver = fs::read_link(device.join("driver")).ok()?;
+    driver.file_name().map(|n| n.to_string_lossy().to_string())
+}
+
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// The USB attributes live on the USB device, which is an ancestor
+/// of the interface the tty hangs off.
+fn usb_info(device: &Path) -> Option<UsbInfo> {
+    let usb_dir: PathBuf = device
+        .ancestors()
+        .find(|dir| dir.join("idVendor").is_file())?
+        .to_path_buf();
+    Some(UsbInfo {
+        vendor_id: u16::from_str_radix(&read_attr(&usb_dir, "idVendor")?, 16).ok()?,
+        product_id: u16::from_str_radix(&read_attr(&usb_dir, "idProduct")?, 16).ok()?,
+        serial: read_attr(&usb_dir, "serial"),
+        manufacturer: read_attr(&usb_dir, "manufacturer"),
+        product: read_attr(&usb_dir, "product"),
+    })
+}
+
+/// Criteria used to pick a port. Unset fields match anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Match {
+    pub usb_vid: Option<u16>,
+    pub usb_pid: Option<u16>,
+    pub usb_serial: Option<String>,
+    pub driver: Option<String>,
+}
+
+impl Match {
+    /// True if no criteria are set
+    pub fn is_empty(&self) -> bool {
+        *self == Match::default()
+    }
+
+    pub fn matches(&self, port: &PortInfo) -> bool {
+        let usb = port.usb.as_ref();
+        if self.usb_vid.is_some() && self.usb_vid != usb.map(|u| u.vendor_id) {
+            return false;
+        }
+        if self.usb_pid.is_some() && self.usb_pid != usb.map(|u| u.product_id) {
+            return false;
+        }
+        if self.usb_serial.is_some() && self.usb_serial != usb.and_then(|u| u.serial.clone()) {
+            return false;
+        }
+        if self.driver.is_some() && self.driver != port.driver {
+            return false;
+        }
+        true
+    }
+}
+
+/// Return the first port matching the criteria.
+pub fn find<'a>(ports: &'a [PortInfo], criteria: &Match) -> Option<&'a PortInfo> {
+    ports.iter().find(|p| criteria.matches(p))
+}
+
+/// Parse a USB ID written in hex, with or without a 0x prefix
+pub fn parse_usb_id(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Not a valid USB ID: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // Builds a cut down sysfs with an FTDI adapter, an on board UART and
+    // a virtual console.
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("tw_ctrl_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let usb = root.join("devices/pci0000:00/usb1/1-1");
+        let iface = usb.join("1-1:1.0/ttyUSB0");
+        let uart = root.join("devices/platform/serial8250");
+        let ftdi = root.join("bus/usb-serial/drivers/ftdi_sio");
+        let s8250 = root.join("bus/platform/drivers/serial8250");
+        for dir in [&iface, &uart, &ftdi, &s8250].iter() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for (attr, value) in [
+            ("idVendor", "0403\n"),
+            ("idProduct", "6001\n"),
+            ("serial", "ABC123\n"),
+            ("manufacturer", "FTDI\n"),
+            ("product", "FT232R USB UART\n"),
+        ]
+        .iter()
+        {
+            fs::write(usb.join(attr), value).unwrap();
+        }
+        symlink(&ftdi, iface.join("driver")).unwrap();
+        symlink(&s8250, uart.join("driver")).unwrap();
+
+        let class = root.join("class/tty");
+        for (tty, device) in [
+            ("ttyUSB0", Some(&iface)),
+            ("ttyS0", Some(&uart)),
+            ("tty0", None),
+        ]
+        .iter()
+        {
+            fs::create_dir_all(class.join(tty)).unwrap();
+            if let Some(d) = device {
+                symlink(d, class.join(tty).join("device")).unwrap();
+            }
+        }
+        root
+    }
+
+    #[test]
+    fn test_ports_in() {
+        let root = fake_sysfs("ports_in");
+        let ports = ports_in(&root.join("class/tty"), Path::new("/dev")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(2, ports.len());
+        assert_eq!("ttyS0", ports[0].name);
+        assert_eq!(Some("serial8250".to_string()), ports[0].driver);
+        assert_eq!(None, ports[0].usb);
+
+        assert_eq!("/dev/ttyUSB0", ports[1].path);
+        assert_eq!(Some("ftdi_sio".to_string()), ports[1].driver);
+        let usb = ports[1].usb.as_ref().unwrap();
+        assert_eq!(0x0403, usb.vendor_id);
+        assert_eq!(0x6001, usb.product_id);
+        assert_eq!(Some("ABC123".to_string()), usb.serial);
+        assert_eq!(Some("FT232R USB UART".to_string()), usb.product);
+    }
+
+    #[test]
+    fn test_find() {
+        let root = fake_sysfs("find");
+        let ports = ports_in(&root.join("class/tty"), Path::new("/dev")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let by_serial = Match {
+            usb_serial: Some("ABC123".to_string()),
+            ..Match::default()
+        };
+        assert_eq!("ttyUSB0", find(&ports, &by_serial).unwrap().name);
+        let by_ids = Match {
+            usb_vid: Some(0x0403),
+            usb_pid: Some(0x6001),
+            ..Match::default()
+        };
+        assert_eq!("ttyUSB0", find(&ports, &by_ids).unwrap().name);
+        let by_driver = Match {
+            driver: Some("serial8250".to_string()),
+            ..Match::default()
+        };
+        assert_eq!("ttyS0", find(&ports, &by_driver).unwrap().name);
+        let wrong_serial = Match {
+            usb_vid: Some(0x0403),
+            usb_serial: Some("XYZ".to_string()),
+            ..Match::default()
+        };
+        assert_eq!(None, find(&ports, &wrong_serial));
+        assert!(Match::default().is_empty());
+    }
+
+    #[test]
+    fn test_parse_usb_id() {
+        assert_eq!(Ok(0x0403), parse_usb_id("0403"));
+        assert_eq!(Ok(0x10c4), parse_usb_id("0x10C4"));
+        assert!(parse_usb_id("usb").is_err());
+    }
+}
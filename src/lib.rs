@@ -1,18 +1,37 @@
-use reqwest;
 use std::error::Error;
+use std::sync::mpsc;
 use std::thread::sleep;
 use std::time::Duration;
 
-use channel::Channel;
-mod channel;
+use channel::{Channel, ErrorKind, Event, EventKind};
+use supervisor::State;
+use transport::Transport;
+#[cfg(feature = "async")]
+pub mod async_channel;
+#[cfg(feature = "async")]
+pub mod async_serialport;
+mod auth;
+pub mod channel;
 pub mod config;
-mod crc16;
+pub mod crc;
+pub mod enumerate;
+mod lock;
 pub mod log;
+pub mod reload;
+pub mod rfc2217;
+pub mod rfcomm;
+pub mod secret;
 mod serialize;
-mod serialport;
+pub mod serialport;
+pub mod settings;
+pub mod supervisor;
+pub mod tcp;
 mod termios;
+mod toml;
+pub mod transport;
 
-#[derive(Debug)]
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
 enum Commands {
     Reset = 0x01,
     ReqTPH = 0x02,
@@ -21,157 +40,495 @@ enum Commands {
     ReqH = 0x05,
 }
 
-fn str_to_loglvl(s: &str) -> log::Level {
-    match s.to_lowercase().as_str() {
-        "debug" => log::Level::Debug,
-        "info" => log::Level::Info,
-        "warning" => log::Level::Warning,
-        "error" => log::Level::Error,
-        "fatal" => log::Level::Fatal,
-        "off" => log::Level::Off,
-        _ => panic!("Not an available log level: {}", s),
+impl Commands {
+    /// How long the station is given to respond to the command
+    fn timeout(&self) -> Duration {
+        match self {
+            Commands::Reset => Duration::from_secs(10),
+            Commands::ReqTPH => Duration::from_secs(3),
+            Commands::ReqT | Commands::ReqP | Commands::ReqH => Duration::from_secs(1),
+        }
     }
 }
 
+/// How long DTR is cleared for when resetting the station
+const DTR_PULSE: Duration = Duration::from_millis(250);
+/// Time a station takes to boot after a DTR reset
+const BOOT_DELAY: Duration = Duration::from_secs(2);
+
 /// Main function of execution.
 pub fn run(config: config::Config) -> Result<(), Box<dyn Error>> {
-    let baud: u32 = match config.get("serial.baud") {
-        Some(n) => n.parse()?,
-        None => panic!("No rate listed in config"),
-    };
-    let device = match config.get("serial.device") {
-        Some(d) => d,
-        None => panic!("No device listed in config"),
-    };
-
-    let logger = match config.get("log.file") {
-        Some(f) => match config.get("log.level") {
-            Some(lvl) => Some(log::file::Logger::new(f, str_to_loglvl(lvl))?),
-            None => Some(log::file::Logger::new(f, log::Level::Debug)?),
-        },
+    // Check everything up front so a bad setting stops us here
+    let mut settings = settings::ControllerConfig::new(&config)?;
+    let mut config = config;
+    let mut watcher = match config.path() {
+        Some(path) => Some(reload::Watcher::new(path, settings.watch)?),
         None => None,
     };
 
-    let timeout: u64 = match config.get("serial.timeout") {
-        Some(n) => n.parse()?,
-        None => 0,
-    };
+    let mut logger = open_logger(&settings.log)?;
 
-    //TODO: Provide conversion function from
-    // u32 to rate
-    let rate = match baud {
-        9600 => serialport::BaudRate::B9600,
-        115200 => serialport::BaudRate::B115200,
-        _ => panic!("Unsupported baud rate"),
-    };
+    // The channel outlives reconnects so the replay counters and the
+    // event subscription carry over.
+    let mut channel = Channel::new(build_transport(&settings.serial), 5);
+    configure_channel(&mut channel, &settings.channel)?;
+    let events = channel.subscribe();
 
-    let port = serialport::SerialPort::new(device, rate, Duration::from_secs(timeout));
+    let mut supervisor = supervisor::Supervisor::new(supervisor::Backoff::default());
+    configure_supervisor(&mut supervisor, &settings.supervisor);
 
-    if let Some(l) = &logger {
-        l.info(&format!("Opening connection to {}", device));
-    }
+    // Rate found by auto-baud, tried first on the next connect
+    let mut detected: Option<serialport::Rate> = None;
+    let mut connected = false;
+    // Set when polling stopped for a reload
+    let mut reload_pending = false;
+    loop {
+        let requested =
+            std::mem::take(&mut reload_pending) || watcher.as_ref().is_some_and(|w| w.pending());
+        let reloaded = match requested {
+            true => reload(&config),
+            false => None,
+        };
+        if let Some((new_config, mut new_settings)) = reloaded {
+            let changes = config.diff(&new_config);
+            let changed = |prefix: &str| changes.iter().any(|c| c.key().starts_with(prefix));
+            if changes.is_empty() {
+                log::info("Config reloaded, nothing changed");
+            } else {
+                let list: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+                log::info(&format!("Config reloaded: {}", list.join(", ")));
+            }
+            if changed("log.") {
+                match open_logger(&new_settings.log) {
+                    Ok(l) => logger = l,
+                    Err(e) => log::error(&format!("Could not open the new log file: {}", e)),
+                }
+            }
+            if changed("channel.") {
+                if let Err(e) = configure_channel(&mut channel, &new_settings.channel) {
+                    log::error(&format!("Keeping the old channel settings: {}", e));
+                    new_settings.channel = settings.channel.clone();
+                }
+            }
+            if changed("supervisor.") {
+                configure_supervisor(&mut supervisor, &new_settings.supervisor);
+            }
+            if changed("config.") {
+                if let Some(path) = new_config.path() {
+                    watcher = Some(reload::Watcher::new(path, new_settings.watch)?);
+                }
+            }
+            // A new key starts the replay counters over, reconnecting has the
+            // heartbeat challenge move them up to the station's again
+            if connected && new_settings.channel.auth_key != settings.channel.auth_key {
+                supervisor.transition(State::Disconnected, "Channel key changed");
+                let _ = channel.close();
+                connected = false;
+            }
+            // Otherwise only the serial settings are worth dropping the link for
+            if changed("serial.") {
+                if connected {
+                    supervisor.transition(State::Disconnected, "Serial settings changed");
+                    let _ = channel.close();
+                    connected = false;
+                }
+                channel.set_transport(build_transport(&new_settings.serial));
+                detected = None;
+            }
+            config = new_config;
+            settings = new_settings;
+        }
+
+        if !connected {
+            let serial = &settings.serial;
+            supervisor.transition(State::Connecting, "Opening channel");
+            let device = match resolve_device(serial) {
+                Ok(Some(d)) => d,
+                Ok(None) => {
+                    supervisor.transition(State::Disconnected, "No serial port matches");
+                    sleep(supervisor.backoff());
+                    continue;
+                }
+                Err(e) => {
+                    supervisor.transition(State::Disconnected, &e.to_string());
+                    sleep(supervisor.backoff());
+                    continue;
+                }
+            };
+            if let Some(l) = &logger {
+                let _ = l.info(&format!("Opening connection to {}", device));
+            }
+            channel.set_path(&device);
+
+            let opened = match serial.baud {
+                Some(_) => channel.open(),
+                None => {
+                    let mut order: Vec<serialport::Rate> = detected.into_iter().collect();
+                    order.extend(
+                        serial
+                            .baud_candidates
+                            .iter()
+                            .filter(|r| Some(**r) != detected),
+                    );
+                    channel.open_auto_baud(&order).map(|r| {
+                        detected = Some(r);
+                        if let Some(l) = &logger {
+                            let _ = l.info(&format!("Detected baud rate {}", r));
+                        }
+                    })
+                }
+            };
+            if let Err(e) = opened {
+                if let Some(l) = &logger {
+                    let _ = l.error(&format!("Could not open channel to device: {:?}", e));
+                }
+                supervisor.transition(State::Disconnected, &e.to_string());
+                sleep(supervisor.backoff());
+                continue;
+            }
+
+            if let Some(l) = &logger {
+                let _ = l.info("Connected!");
+            }
+            supervisor.connected();
+            connected = true;
+        }
 
-    let mut channel = Channel::new(port, 5);
-    if let Err(e) = channel.open() {
+        let e = match poll(&settings, &logger, &channel, &events, watcher.as_ref())? {
+            Stop::Reload => {
+                reload_pending = true;
+                continue;
+            }
+            Stop::Failed(e) => e,
+        };
         if let Some(l) = &logger {
-            l.fatal(&format!("Could not open channel to device: {:?}", e));
+            let _ = l.error(&format!("Lost connection to station: {:?}", e));
         }
-        panic!("Could not open channel to device: {:?}", e);
+        let reason = if e.is_disconnect() {
+            format!("Device lost: {}", e)
+        } else {
+            format!("Channel failed: {}", e)
+        };
+        supervisor.transition(State::Disconnected, &reason);
+        let _ = channel.close();
+        connected = false;
+        sleep(supervisor.backoff());
     }
+}
 
-    if let Some(l) = &logger {
-        l.info("Connected!");
+fn open_logger(settings: &settings::LogConfig) -> std::io::Result<Option<log::file::Logger>> {
+    match &settings.file {
+        Some(f) => Ok(Some(log::file::Logger::new(f, settings.level)?)),
+        None => Ok(None),
     }
+}
 
+/// Build the transport named by `serial.device`. The path is filled in
+/// each time the device is resolved.
+fn build_transport(serial: &settings::SerialConfig) -> Box<dyn Transport> {
+    let device = serial.device.as_deref().unwrap_or("");
+    if device.starts_with(transport::SCHEME_TCP) {
+        Box::new(tcp::TcpTransport::new(device))
+    } else if device.starts_with(transport::SCHEME_RFC2217) {
+        let mut rfc2217 = rfc2217::Rfc2217Transport::new(device)
+            .data_bits(serial.data_bits)
+            .parity(serial.parity)
+            .stop_bits(serial.stop_bits)
+            .flow_control(serial.flow_control);
+        if let Some(r) = serial.baud {
+            rfc2217 = rfc2217.baud(r);
+        }
+        Box::new(rfc2217)
+    } else if device.starts_with(transport::SCHEME_BLUETOOTH) {
+        Box::new(rfcomm::RfcommTransport::new(device))
+    } else {
+        let mut builder = serialport::SerialPortBuilder::new("")
+            .timeout(serial.timeout)
+            .data_bits(serial.data_bits)
+            .parity(serial.parity)
+            .stop_bits(serial.stop_bits)
+            .flow_control(serial.flow_control)
+            .vmin(serial.vmin)
+            .exclusive(serial.exclusive)
+            .lock_dir(&serial.lock_dir);
+        if let Some(r) = serial.baud {
+            builder = builder.baud(r);
+        }
+        if let Some(on) = serial.dtr {
+            builder = builder.dtr(on);
+        }
+        if let Some(on) = serial.rts {
+            builder = builder.rts(on);
+        }
+        Box::new(builder.build())
+    }
+}
+
+fn configure_channel(
+    channel: &mut Channel,
+    settings: &settings::ChannelConfig,
+) -> Result<(), channel::Error> {
+    channel.set_window(settings.window)?;
+    channel.set_crc(settings.crc);
+    match &settings.auth_key {
+        Some(k) => channel.set_auth_key(k.expose()),
+        None => channel.clear_auth_key(),
+    }
+    channel.set_timeout(settings.frame_timeout);
+    Ok(())
+}
+
+fn configure_supervisor(
+    supervisor: &mut supervisor::Supervisor,
+    settings: &settings::SupervisorConfig,
+) {
+    supervisor.set_backoff(supervisor::Backoff::new(
+        settings.backoff_initial,
+        settings.backoff_max,
+    ));
+    match &settings.state_file {
+        Some(f) => supervisor.set_state_file(f),
+        None => supervisor.clear_state_file(),
+    }
+}
+
+/// Read the config file again, returning None and keeping the current
+/// config if it can't be read or isn't valid.
+fn reload(config: &config::Config) -> Option<(config::Config, settings::ControllerConfig)> {
+    let path = config.path()?;
+    log::info(&format!("Reloading config from {}", path));
+    let (new_config, diagnostics) = match config::Config::open_with_diagnostics(path) {
+        Ok(c) => c,
+        Err(e) => {
+            log::error(&format!(
+                "Could not read config, keeping the current one -- {}",
+                e
+            ));
+            return None;
+        }
+    };
+    for d in diagnostics {
+        log::warn(&format!("config {}", d));
+    }
+    match settings::ControllerConfig::new(&new_config) {
+        Ok(s) => Some((new_config, s)),
+        Err(e) => {
+            log::error(&format!("{}, keeping the current config", e));
+            None
+        }
+    }
+}
+
+/// Why polling stopped
+enum Stop {
+    /// The channel failed with the error
+    Failed(channel::Error),
+    /// The config is to be reloaded
+    Reload,
+}
+
+/// Poll the station until the channel fails or a reload is asked
+/// for. Errors outside of the channel are passed up as usual.
+fn poll(
+    settings: &settings::ControllerConfig,
+    logger: &Option<log::file::Logger>,
+    channel: &Channel,
+    events: &mpsc::Receiver<Event>,
+    watcher: Option<&reload::Watcher>,
+) -> Result<Stop, Box<dyn Error>> {
+    let reset_after = settings.station.reset_after;
+    // Requests in a row the station has not answered
+    let mut timeouts = 0;
     loop {
-        sleep(Duration::from_secs(2));
-        let mut payload: Vec<u8> = Vec::new();
-        if let Some(l) = &logger {
-            l.info(&format!("Sending command {:?}", Commands::ReqTPH));
+        // Pick up anything the station pushes between commands
+        if let Err(e) = channel.listen(settings.station.poll_interval) {
+            log::error(&format!(
+                "Channel encountered error while listening: {:?}",
+                e
+            ));
+            return Ok(Stop::Failed(e));
         }
-        //TODO Actual commands
-        payload.push(Commands::ReqTPH as u8);
-        match channel.send(&payload) {
-            Ok(()) => {
-                if let Some(l) = &logger {
-                    l.info("Send complete");
+        for event in events.try_iter() {
+            handle_event(&settings.db, event)?;
+        }
+        if watcher.is_some_and(|w| w.pending()) {
+            return Ok(Stop::Reload);
+        }
+        // Bluetooth adapters keep the tty around when the link drops
+        // but clear DCD
+        if settings.serial.carrier_detect {
+            match channel.port().modem_status() {
+                Ok(status) if !status.dcd => {
+                    return Ok(Stop::Failed(channel::Error::new(
+                        ErrorKind::SerialPort(serialport::ErrorKind::Disconnected),
+                        "Carrier lost",
+                    )))
                 }
+                Ok(_) => (),
+                Err(e) => return Ok(Stop::Failed(e.into())),
             }
-            Err(e) => log::error(&format!(
-                "Channel encountered error during sending: {:?}",
-                e
-            )),
         }
 
-        let data = match channel.recv() {
+        let command = Commands::ReqTPH;
+        if let Some(l) = logger {
+            let _ = l.info(&format!("Sending command {:?}", command));
+        }
+        //TODO Actual commands
+        let data = match run_command(channel, &settings.channel, command) {
             Ok(v) => v,
             Err(e) => {
-                log::error(&format!("Channel encountered error during recv: {:?}", e));
-                break;
+                log::error(&format!(
+                    "Channel encountered error during request: {:?}",
+                    e
+                ));
+                // A late response will be discarded by the next request
+                if let ErrorKind::Timeout | ErrorKind::MaxAttempts = e.kind() {
+                    timeouts += 1;
+                    if reset_after > 0 && timeouts >= reset_after {
+                        timeouts = 0;
+                        if let Err(e) = reset_station(channel, &settings.channel) {
+                            return Ok(Stop::Failed(e));
+                        }
+                    }
+                    continue;
+                }
+                return Ok(Stop::Failed(e));
             }
         };
+        timeouts = 0;
 
-        if let Some(l) = &logger {
-            l.info(&format!("Recieved data: {:?}", data));
-        }
-
-        let mut temp_u32: u32 = 0;
-        let mut press_u32: u32 = 0;
-        let mut hum_u32: u32 = 0;
-        for i in 0..4 {
-            temp_u32 |= (data[i] as u32) << (8 * i);
+        if let Some(l) = logger {
+            let _ = l.info(&format!("Recieved data: {:?}", data));
         }
-        for i in 0..4 {
-            press_u32 |= (data[4 + i] as u32) << (8 * i);
+        record_reading(&settings.db, &data)?;
+        for event in events.try_iter() {
+            handle_event(&settings.db, event)?;
         }
-        for i in 0..4 {
-            hum_u32 |= (data[8 + i] as u32) << (8 * i);
+    }
+}
+
+/// Send a command and wait for the station's reply. Stations without
+/// request frames (`channel.requests=false`) are sent the command as a
+/// data frame and reply with one.
+fn run_command(
+    channel: &Channel,
+    settings: &settings::ChannelConfig,
+    command: Commands,
+) -> Result<Vec<u8>, channel::Error> {
+    if settings.requests {
+        return channel.request(&[command as u8], command.timeout());
+    }
+    channel.send(&[command as u8])?;
+    channel.recv()
+}
+
+/// Reset the station, asking it to reset itself first and pulsing DTR
+/// if it won't. The DTR pulse works on Arduino style boards whose
+/// reset pin is wired to DTR, even when the firmware has hung.
+fn reset_station(
+    channel: &Channel,
+    settings: &settings::ChannelConfig,
+) -> Result<(), channel::Error> {
+    log::warn("Station is not responding, resetting it");
+    match run_command(channel, settings, Commands::Reset) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.is_disconnect() => return Err(e),
+        Err(e) => log::warn(&format!("Reset command failed, pulsing DTR: {}", e)),
+    }
+    channel.port().pulse_dtr(DTR_PULSE)?;
+    // Give the bootloader time to hand over to the firmware
+    sleep(BOOT_DELAY);
+    Ok(())
+}
+
+/// Work out which device the station is on.
+///
+/// When any `serial.match.*` keys are set the first port matching them is
+/// used, falling back to `serial.device` if none do. This is redone on
+/// every reconnect since the path can change when the device is replugged.
+/// Network and Bluetooth devices (`tcp://`, `rfc2217://`, `bt://`) are
+/// used as they are.
+fn resolve_device(serial: &settings::SerialConfig) -> std::io::Result<Option<String>> {
+    let device = &serial.device;
+    if device.as_deref().is_some_and(transport::is_url) {
+        return Ok(device.clone());
+    }
+    let criteria = &serial.criteria;
+    if !criteria.is_empty() {
+        let ports = enumerate::available_ports()?;
+        if let Some(port) = enumerate::find(&ports, criteria) {
+            log::info(&format!("Found station on {}", port.path));
+            return Ok(Some(port.path.clone()));
         }
-        let temp_f32: f32 = temp_u32 as i32 as f32 / 100.0;
-        let press_f32: f32 = press_u32 as i32 as f32 / 256.0;
-        let hum_f32: f32 = hum_u32 as i32 as f32 / 1024.0;
-        log::info(&format!(
-            "Temp: {}, Press: {}, Hum: {}",
-            temp_f32, press_f32, hum_f32
-        ));
+        log::warn(&format!("No serial port matches {:?}", criteria));
+    }
+    Ok(device.clone())
+}
 
-        let dt: chrono::DateTime<chrono::Local> = chrono::Local::now();
+/// Dispatch an event pushed by the station.
+fn handle_event(db: &settings::DbConfig, event: Event) -> Result<(), Box<dyn Error>> {
+    match event.kind {
+        EventKind::ThresholdAlarm => log::warn(&format!("Station alarm: {:?}", event.data)),
+        EventKind::Boot => log::info("Station reported a boot"),
+        EventKind::BufferedReading => {
+            log::info("Recieved buffered reading");
+            record_reading(db, &event.data)?;
+        }
+        EventKind::Unknown(k) => log::warn(&format!("Unknown event kind {:#x}", k)),
+    }
+    Ok(())
+}
 
-        let data = format!(
-            "envSensor,node=1 temperature={},humidity={},pressure={} {}",
-            temp_f32,
-            hum_f32,
-            press_f32,
-            dt.timestamp()
-        );
-        //Send data to influxDB
-        //
-        log::debug(&format!("Writing data to Influx: {}", data));
-        let addr = config.get("db.host").unwrap();
-        let port = config.get("db.port").unwrap();
-        let api_key = config.get("db.api.key").unwrap();
-        let api_endpoint = config.get("db.api.endpoint").unwrap();
-        let api = InfluxWebClient {
-            host: Host {
-                addr: addr.to_string(),
-                port: port.parse()?,
-            },
-            api_key: api_key.to_string(),
-            api_endpoint: api_endpoint.to_string(),
-        };
-        log::info(&format!("{:?}", api.send(data)));
+/// Decode a TPH reading and write it to Influx. A reading too short to
+/// decode is logged and skipped.
+fn record_reading(db: &settings::DbConfig, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    if data.len() < 12 {
+        log::warn(&format!("Skipping reading too short to decode: {:?}", data));
+        return Ok(());
     }
+    let temp_u32 = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let press_u32 = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let hum_u32 = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+    let temp_f32: f32 = temp_u32 as i32 as f32 / 100.0;
+    let press_f32: f32 = press_u32 as i32 as f32 / 256.0;
+    let hum_f32: f32 = hum_u32 as i32 as f32 / 1024.0;
+    log::info(&format!(
+        "Temp: {}, Press: {}, Hum: {}",
+        temp_f32, press_f32, hum_f32
+    ));
+
+    let dt: chrono::DateTime<chrono::Local> = chrono::Local::now();
+
+    let data = format!(
+        "envSensor,node=1 temperature={},humidity={},pressure={} {}",
+        temp_f32,
+        hum_f32,
+        press_f32,
+        dt.timestamp()
+    );
+    //Send data to influxDB
+    //
+    log::debug(&format!("Writing data to Influx: {}", data));
+    let api = InfluxWebClient {
+        host: Host {
+            addr: db.host.clone(),
+            port: db.port,
+        },
+        api_key: db.api_key.clone(),
+        api_endpoint: db.api_endpoint.clone(),
+    };
+    log::info(&format!("{:?}", api.send(data)));
 
     Ok(())
 }
 
 struct Host {
     addr: String,
-    port: u32,
+    port: u16,
 }
 struct InfluxWebClient {
     host: Host,
-    api_key: String,
+    api_key: secret::Secret<String>,
     api_endpoint: String,
 }
 
@@ -186,9 +543,117 @@ impl InfluxWebClient {
                     + &self.host.port.to_string()
                     + &self.api_endpoint,
             )
-            .header("Authorization", "Token ".to_string() + &self.api_key)
+            .header(
+                "Authorization",
+                "Token ".to_string() + self.api_key.expose(),
+            )
             .header("Content-Type", "text/plain; charset=utf-8")
             .body(data)
             .send()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    // A short reading, as a response or a buffered reading event, is
+    // skipped and polling carries on until the station goes away
+    #[test]
+    fn test_poll_skips_short_reading() {
+        let (mut master, path) = serialport::open_pty();
+        let port = serialport::SerialPortBuilder::new(&path)
+            .timeout(Duration::from_millis(200))
+            .exclusive(false)
+            .open()
+            .unwrap();
+        let mut channel = Channel::new(port, 5);
+        channel.set_timeout(Duration::from_millis(200));
+        let events = channel.subscribe();
+        let config = config::Config::parse(
+            "serial.device=/dev/null\nserial.baud=9600\ndb.host=localhost\ndb.port=8086\ndb.api.key=token\n\
+             db.api.endpoint=/write\nstation.poll_interval=10ms\n",
+        );
+        let settings = settings::ControllerConfig::new(&config).unwrap();
+
+        let station = thread::spawn(move || {
+            let crc = crc::Algorithm::default();
+            let mut requests = 0;
+            while requests < 2 {
+                let mut header = [0; 3];
+                master.read_exact(&mut header).unwrap();
+                let mut body = vec![0; header[2] as usize + crc.width() + 1];
+                master.read_exact(&mut body).unwrap();
+                if header[1] != channel::FRAME_TYPE_REQUEST {
+                    continue;
+                }
+                requests += 1;
+                let ack = channel::make_control_frame(crc, channel::ControlType::Ack);
+                let event = channel::make_frame(crc, channel::FRAME_TYPE_EVENT, &[0x03, 1]);
+                let response =
+                    channel::make_frame(crc, channel::FRAME_TYPE_RESPONSE, &[body[0], 1, 2]);
+                master.write_all(&ack).unwrap();
+                master.write_all(&event).unwrap();
+                master.write_all(&response).unwrap();
+            }
+            requests
+        });
+        let stop = poll(&settings, &None, &channel, &events, None).unwrap();
+        assert!(matches!(stop, Stop::Failed(_)));
+        assert_eq!(2, station.join().unwrap());
+    }
+
+    // Stations predating request frames get the command as a data frame
+    // and answer with one
+    #[test]
+    fn test_run_command_data_frames() {
+        let (mut master, path) = serialport::open_pty();
+        let port = serialport::SerialPortBuilder::new(&path)
+            .timeout(Duration::from_millis(200))
+            .exclusive(false)
+            .open()
+            .unwrap();
+        let mut channel = Channel::new(port, 5);
+        channel.set_timeout(Duration::from_millis(200));
+        let config = config::Config::parse(
+            "serial.device=/dev/null\nserial.baud=9600\ndb.host=localhost\ndb.port=8086\ndb.api.key=token\n\
+             db.api.endpoint=/write\nchannel.requests=false\n",
+        );
+        let settings = settings::ControllerConfig::new(&config).unwrap();
+
+        let station = thread::spawn(move || {
+            let crc = crc::Algorithm::default();
+            let read = |master: &mut nix::pty::PtyMaster| {
+                let mut header = [0; 3];
+                master.read_exact(&mut header).unwrap();
+                let mut body = vec![0; header[2] as usize + crc.width() + 1];
+                master.read_exact(&mut body).unwrap();
+                body.truncate(header[2] as usize);
+                (header[1], body)
+            };
+            let command = read(&mut master);
+            let ack = channel::make_control_frame(crc, channel::ControlType::Ack);
+            master.write_all(&ack).unwrap();
+            let data = channel::make_frame(crc, channel::FRAME_TYPE_DATA, &[1, 2, 3]);
+            master.write_all(&data).unwrap();
+            let reply = read(&mut master);
+            (command, reply, master)
+        });
+        let data = run_command(&channel, &settings.channel, Commands::ReqTPH).unwrap();
+        let (command, reply, _master) = station.join().unwrap();
+        assert_eq!(vec![1, 2, 3], data);
+        assert_eq!(
+            (channel::FRAME_TYPE_DATA, vec![Commands::ReqTPH as u8]),
+            command
+        );
+        assert_eq!(
+            (
+                channel::FRAME_TYPE_CTRL,
+                vec![channel::ControlType::Ack as u8]
+            ),
+            reply
+        );
+    }
+}
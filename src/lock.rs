@@ -0,0 +1,216 @@
+//! Module providing UUCP style lock files for serial devices.
+//!
+//! Before a device is opened a file named `LCK..<device>` is created in
+//! the lock directory (usually /var/lock) holding the PID of the owner as
+//! ten right aligned ASCII digits followed by a newline. Other programs
+//! following the same convention (minicom, picocom, ModemManager) leave
+//! the device alone while the file exists. Lock files left behind by a
+//! process that is no longer running are considered stale and replaced.
+//! Devices are locked by their real path, so a symlink such as
+//! `/dev/serial/by-id/...` takes the same lock as the tty it points at.
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const LOCK_DIR_DEFAULT: &str = "/var/lock";
+/// A lock file younger than this without a PID is taken to be still
+/// being written by its owner
+const FRESH: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub enum LockError {
+    /// The device is locked by the process with this PID, if known
+    Busy(Option<u32>),
+    Io(io::ErrorKind),
+}
+
+/// A held lock, released when dropped
+#[derive(Debug)]
+pub struct LockFile {
+    path: PathBuf,
+}
+
+/// Lock file name for a device path, e.g. /dev/ttyUSB0 -> LCK..ttyUSB0
+fn lock_name(device: &str) -> String {
+    let name = device.strip_prefix("/dev/").unwrap_or(device);
+    format!("LCK..{}", name.trim_start_matches('/').replace('/', "_"))
+}
+
+/// PID held in a lock file. Both the ASCII and the old binary
+/// formats are understood.
+fn read_pid(path: &Path) -> Option<u32> {
+    let contents = fs::read(path).ok()?;
+    match std::str::from_utf8(&contents) {
+        Ok(s) if !s.trim().is_empty() => s.trim().parse().ok(),
+        _ if contents.len() == 4 => Some(u32::from_ne_bytes([
+            contents[0],
+            contents[1],
+            contents[2],
+            contents[3],
+        ])),
+        _ => None,
+    }
+}
+
+fn is_fresh(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|age| age < FRESH)
+}
+
+fn is_running(pid: u32) -> bool {
+    match kill(Pid::from_raw(pid as i32), None) {
+        Ok(_) => true,
+        // Running, but owned by someone else
+        Err(Errno::EPERM) => true,
+        Err(_) => false,
+    }
+}
+
+impl LockFile {
+    /// Lock the device using a lock file in dir.
+    pub fn acquire(dir: &Path, device: &str) -> Result<LockFile, LockError> {
+        let device = fs::canonicalize(device)
+            .ok()
+            .and_then(|p| p.to_str().map(String::from))
+            .unwrap_or_else(|| device.to_string());
+        let name = lock_name(&device);
+        let pid = std::process::id();
+        // The PID is written to a file of our own that is then linked
+        // into place, so the lock file never exists without it
+        let temp = dir.join(format!("{}.{}", name, pid));
+        fs::write(&temp, format!("{:>10}\n", pid)).map_err(|e| LockError::Io(e.kind()))?;
+        let res = LockFile::link(&temp, &dir.join(name));
+        let _ = fs::remove_file(&temp);
+        res
+    }
+
+    fn link(temp: &Path, path: &Path) -> Result<LockFile, LockError> {
+        // A second attempt is made after clearing out a stale lock
+        for _ in 0..2 {
+            match fs::hard_link(temp, path) {
+                Ok(()) => {
+                    return Ok(LockFile {
+                        path: path.to_path_buf(),
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => match read_pid(path) {
+                    Some(owner) if is_running(owner) => return Err(LockError::Busy(Some(owner))),
+                    // Other programs write the PID after creating the file
+                    None if is_fresh(path) => return Err(LockError::Busy(None)),
+                    _ => {
+                        crate::log::warn(&format!("Removing stale lock {}", path.display()));
+                        if let Err(e) = fs::remove_file(path) {
+                            return Err(LockError::Io(e.kind()));
+                        }
+                    }
+                },
+                Err(e) => return Err(LockError::Io(e.kind())),
+            }
+        }
+        Err(LockError::Io(io::ErrorKind::AlreadyExists))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lock_name() {
+        assert_eq!("LCK..ttyUSB0", lock_name("/dev/ttyUSB0"));
+        assert_eq!("LCK..pts_3", lock_name("/dev/pts/3"));
+        assert_eq!("LCK..rfcomm0", lock_name("rfcomm0"));
+    }
+
+    #[test]
+    fn test_acquire_release() {
+        let dir = lock_dir("lock");
+        let lock = LockFile::acquire(&dir, "/dev/ttyUSB0").unwrap();
+        let path = lock.path().to_path_buf();
+        assert_eq!(
+            format!("{:>10}\n", std::process::id()),
+            fs::read_to_string(&path).unwrap()
+        );
+        drop(lock);
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_busy_and_stale() {
+        let dir = lock_dir("lock_busy");
+        let path = dir.join("LCK..ttyUSB0");
+        // PID 1 is always running
+        fs::write(&path, format!("{:>10}\n", 1)).unwrap();
+        assert_eq!(
+            LockError::Busy(Some(1)),
+            LockFile::acquire(&dir, "/dev/ttyUSB0").unwrap_err()
+        );
+
+        // Another program may not have written its PID yet
+        fs::write(&path, "").unwrap();
+        assert_eq!(
+            LockError::Busy(None),
+            LockFile::acquire(&dir, "/dev/ttyUSB0").unwrap_err()
+        );
+        let old = std::time::SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let lock = LockFile::acquire(&dir, "/dev/ttyUSB0").unwrap();
+        drop(lock);
+
+        // Beyond the largest PID Linux hands out
+        fs::write(&path, format!("{:>10}\n", 99999999)).unwrap();
+        let lock = LockFile::acquire(&dir, "/dev/ttyUSB0").unwrap();
+        assert_eq!(Some(std::process::id()), read_pid(lock.path()));
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_symlink() {
+        let dir = lock_dir("lock_symlink");
+        let device = dir.join("ttyUSB0");
+        fs::write(&device, "").unwrap();
+        let link = dir.join("usb-FTDI_FT232R-if00-port0");
+        std::os::unix::fs::symlink(&device, &link).unwrap();
+
+        let lock = LockFile::acquire(&dir, device.to_str().unwrap()).unwrap();
+        assert_eq!(
+            LockError::Busy(Some(std::process::id())),
+            LockFile::acquire(&dir, link.to_str().unwrap()).unwrap_err()
+        );
+        drop(lock);
+        // The files the PID was written to are cleaned up
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,7 @@
 //! This module provides logging to a file and to std out
-use chrono::prelude::*;
-use chrono::{DateTime, Local};
+use std::fmt;
 use std::io::Write;
-#[derive(PartialOrd, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
 pub enum Level {
     Off,
     Fatal,
@@ -12,16 +11,16 @@ pub enum Level {
     Debug,
 }
 
-impl ToString for Level {
-    fn to_string(&self) -> String {
-        match self {
-            Level::Off => "".to_string(),
-            Level::Debug => "DEBUG".to_string(),
-            Level::Info => "INFO".to_string(),
-            Level::Warning => "WARN".to_string(),
-            Level::Error => "ERROR".to_string(),
-            Level::Fatal => "FATAL".to_string(),
-        }
+impl fmt::Display for Level {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Level::Off => "",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warning => "WARN",
+            Level::Error => "ERROR",
+            Level::Fatal => "FATAL",
+        })
     }
 }
 
@@ -55,38 +54,38 @@ pub mod file {
             let dt = chrono::Local::now().to_rfc3339();
             match level {
                 Level::Off => (),
-                _ => writeln!(&self.file, "[{}] [{}] {}", dt, level.to_string(), s)?,
+                _ => writeln!(&self.file, "[{}] [{}] {}", dt, level, s)?,
             };
 
             Ok(())
         }
         pub fn debug(&self, s: &str) -> Result<()> {
             if Level::Debug <= self.level {
-                self.log(&Level::Debug, &s)?;
+                self.log(&Level::Debug, s)?;
             }
             Ok(())
         }
         pub fn info(&self, s: &str) -> Result<()> {
             if Level::Info <= self.level {
-                self.log(&Level::Info, &s)?;
+                self.log(&Level::Info, s)?;
             }
             Ok(())
         }
         pub fn warn(&self, s: &str) -> Result<()> {
             if Level::Warning <= self.level {
-                self.log(&Level::Warning, &s)?;
+                self.log(&Level::Warning, s)?;
             }
             Ok(())
         }
         pub fn error(&self, s: &str) -> Result<()> {
             if Level::Error <= self.level {
-                self.log(&Level::Error, &s)?;
+                self.log(&Level::Error, s)?;
             }
             Ok(())
         }
         pub fn fatal(&self, s: &str) -> Result<()> {
             if Level::Fatal <= self.level {
-                self.log(&Level::Fatal, &s)?;
+                self.log(&Level::Fatal, s)?;
             }
             Ok(())
         }
@@ -108,36 +107,36 @@ pub fn log(level: &Level, s: &str) {
     let dt = chrono::Local::now().to_rfc3339();
     match level {
         Level::Off => (),
-        _ => println!("[{}] [{}] {}", dt, level.to_string(), s),
+        _ => println!("[{}] [{}] {}", dt, level, s),
     }
 }
 
 pub fn debug(s: &str) {
     if Level::Debug <= LOGLEVEL {
-        log(&Level::Debug, &s);
+        log(&Level::Debug, s);
     }
 }
 
 pub fn info(s: &str) {
     if Level::Info <= LOGLEVEL {
-        log(&Level::Info, &s);
+        log(&Level::Info, s);
     }
 }
 
 pub fn warn(s: &str) {
     if Level::Warning <= LOGLEVEL {
-        log(&Level::Warning, &s);
+        log(&Level::Warning, s);
     }
 }
 
 pub fn error(s: &str) {
     if Level::Error <= LOGLEVEL {
-        log(&Level::Error, &s);
+        log(&Level::Error, s);
     }
 }
 
 pub fn fatal(s: &str) {
     if Level::Fatal <= LOGLEVEL {
-        log(&Level::Fatal, &s);
+        log(&Level::Fatal, s);
     }
 }
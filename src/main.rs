@@ -1,29 +1,162 @@
 use std::env;
-use std::error::Error;
-use std::path::PathBuf;
+use std::fs;
+use std::path::Path;
 use std::process;
-use tw_ctrl::config::Config;
+use tw_ctrl::config::{self, Config};
+use tw_ctrl::enumerate;
 use tw_ctrl::log;
+use tw_ctrl::settings;
 
 //TODO: Add logger for output
 fn main() {
-    let mut dir = env::current_exe().expect("How did we get here?");
-    dir.pop();
-    dir.push("config");
-    let config = Config::new(&dir.to_str().unwrap().to_string()).unwrap_or_else(|err| {
-        log::fatal(&format!(
-            "Failed opening config file -- {}",
-            err.to_string()
-        ));
-        process::exit(1);
-    });
+    let args: Vec<String> = env::args().collect();
+    if args.iter().any(|a| a == "--list-ports") {
+        list_ports();
+        return;
+    }
+    if let Some(i) = args.iter().position(|a| a == "--check-config") {
+        let path = args.get(i + 1).cloned().unwrap_or_else(default_config);
+        process::exit(check_config(&path));
+    }
+    if args.get(1).map(String::as_str) == Some("config") {
+        match (args.get(2).map(String::as_str), args.get(3)) {
+            (Some("convert"), Some(input)) => process::exit(convert_config(input, args.get(4))),
+            _ => {
+                eprintln!("usage: {} config convert <config> [<config.toml>]", args[0]);
+                process::exit(2);
+            }
+        }
+    }
+
+    let (config, diagnostics) =
+        Config::open_with_diagnostics(&default_config()).unwrap_or_else(|err| {
+            log::fatal(&format!("Failed opening config file -- {}", err));
+            process::exit(1);
+        });
+    for d in diagnostics {
+        log::warn(&format!("config {}", d));
+    }
 
     // Run the controller
     if let Err(e) = tw_ctrl::run(config) {
         log::fatal(&format!(
             "Contoller encountered error during execution -- {}",
-            e.to_string()
+            e
         ));
         process::exit(1);
     }
 }
+
+/// The config file next to the executable, `config.toml` if there is
+/// no plain `config`
+fn default_config() -> String {
+    let mut dir = env::current_exe().expect("How did we get here?");
+    dir.pop();
+    let toml = dir.join("config.toml");
+    dir.push("config");
+    if !dir.exists() && toml.exists() {
+        dir = toml;
+    }
+    dir.to_str().unwrap().to_string()
+}
+
+/// Convert a config file to TOML, writing it to output or printing it
+/// when there's no output. Returns the exit status.
+fn convert_config(input: &str, output: Option<&String>) -> i32 {
+    let text = match fs::read_to_string(input) {
+        Ok(t) => t,
+        Err(err) => {
+            eprintln!("{}: {}", input, err);
+            return 1;
+        }
+    };
+    let (converted, diagnostics) = config::to_toml(&text);
+    for d in diagnostics {
+        eprintln!("{}: {}", input, d);
+    }
+    let output = match output {
+        Some(o) => o,
+        None => {
+            print!("{}", converted);
+            return 0;
+        }
+    };
+    if Path::new(output).extension().map_or(true, |e| e != "toml") {
+        eprintln!(
+            "{}: warning: only files ending in .toml are read as TOML",
+            output
+        );
+    }
+    // Never overwrite, the output could be the file being converted
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)
+        .and_then(|mut f| std::io::Write::write_all(&mut f, converted.as_bytes()));
+    match written {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}: {}", output, err);
+            1
+        }
+    }
+}
+
+/// Print every problem found in the config file, returning the exit
+/// status: 0 if there are none, 1 otherwise.
+fn check_config(path: &str) -> i32 {
+    let (config, diagnostics) = match Config::open_with_diagnostics(path) {
+        Ok(c) => c,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return 1;
+        }
+    };
+    let mut problems = diagnostics.len();
+    for d in diagnostics {
+        println!("{}: {}", path, d);
+    }
+    for key in settings::unknown_keys(&config) {
+        problems += 1;
+        match config.origin(key) {
+            Some(o) => println!("{}: {}: unknown setting {}", path, o, key),
+            None => println!("{}: unknown setting {}", path, key),
+        }
+    }
+    if let Err(e) = settings::ControllerConfig::check(&config) {
+        problems += 1;
+        match e.origin() {
+            Some(o) => println!("{}: {}: {}", path, o, e.description()),
+            None => println!("{}: {}", path, e.description()),
+        }
+    }
+    if problems == 0 {
+        println!("{}: OK", path);
+        return 0;
+    }
+    1
+}
+
+/// Print the serial ports found on the system along with the
+/// attributes usable in `serial.match.*` settings.
+fn list_ports() {
+    let ports = enumerate::available_ports().unwrap_or_else(|err| {
+        log::fatal(&format!("Failed listing serial ports -- {}", err));
+        process::exit(1);
+    });
+    for port in ports {
+        let driver = port.driver.unwrap_or_else(|| "-".to_string());
+        match port.usb {
+            Some(usb) => println!(
+                "{}\tdriver={} usb_vid={:04x} usb_pid={:04x} usb_serial={} ({})",
+                port.path,
+                driver,
+                usb.vendor_id,
+                usb.product_id,
+                usb.serial.unwrap_or_else(|| "-".to_string()),
+                usb.product.unwrap_or_default()
+            ),
+            None => println!("{}\tdriver={}", port.path, driver),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Module watching for requests to reload the config file.
+//!
+//! A reload is asked for by sending the controller SIGHUP. Optionally
+//! the config file is watched with inotify as well, so saving it is
+//! enough. The directory is watched rather than the file since editors
+//! usually write a new file and rename it over the old one. Files it
+//! includes aren't watched, changes to them need a SIGHUP.
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::ffi::OsString;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the SIGHUP handler, cleared once seen
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_: nix::libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+pub struct Watcher {
+    /// Watch on the config file's directory and the file's name in it
+    inotify: Option<(Inotify, OsString)>,
+}
+
+impl Watcher {
+    /// Start listening for SIGHUP, and for changes to the file at path
+    /// when watch_file is set.
+    pub fn new(path: &str, watch_file: bool) -> nix::Result<Watcher> {
+        let action = SigAction::new(
+            SigHandler::Handler(on_hangup),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { signal::sigaction(Signal::SIGHUP, &action) }?;
+
+        let inotify = match watch_file {
+            true => Some(watch(Path::new(path))?),
+            false => None,
+        };
+        Ok(Watcher { inotify })
+    }
+
+    /// True if a reload has been asked for since the last call.
+    pub fn pending(&self) -> bool {
+        let mut pending = HANGUP.swap(false, Ordering::SeqCst);
+        if let Some((inotify, name)) = &self.inotify {
+            loop {
+                match inotify.read_events() {
+                    Ok(events) => {
+                        pending |= events.iter().any(|e| e.name.as_ref() == Some(name));
+                    }
+                    Err(Errno::EAGAIN) => break,
+                    Err(e) => {
+                        crate::log::error(&format!("Could not watch the config file: {}", e));
+                        break;
+                    }
+                }
+            }
+        }
+        pending
+    }
+}
+
+/// Watch the directory of path for the file being written or replaced
+fn watch(path: &Path) -> nix::Result<(Inotify, OsString)> {
+    let name = path.file_name().ok_or(Errno::EINVAL)?.to_os_string();
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    let flags = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO;
+    if let Err(e) = inotify.add_watch(dir, flags) {
+        let _ = nix::unistd::close(inotify.as_raw_fd());
+        return Err(e);
+    }
+    Ok((inotify, name))
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some((inotify, _)) = &self.inotify {
+            let _ = nix::unistd::close(inotify.as_raw_fd());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Both ways of asking share the SIGHUP flag so are tested together
+    #[test]
+    fn test_pending() {
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_reload_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        fs::write(&path, "a=1\n").unwrap();
+
+        let watcher = Watcher::new(path.to_str().unwrap(), true).unwrap();
+        assert!(!watcher.pending());
+        signal::raise(Signal::SIGHUP).unwrap();
+        assert!(watcher.pending());
+        assert!(!watcher.pending());
+
+        // Other files in the directory are ignored
+        fs::write(dir.join("other"), "b=2\n").unwrap();
+        assert!(!watcher.pending());
+        fs::write(&path, "a=2\n").unwrap();
+        assert!(watcher.pending());
+        // Replaced the way editors do it
+        fs::write(dir.join("config.tmp"), "a=3\n").unwrap();
+        fs::rename(dir.join("config.tmp"), &path).unwrap();
+        assert!(watcher.pending());
+        assert!(!watcher.pending());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,491 @@
+//! Module providing an RFC 2217 (Telnet COM Port Control) transport.
+//!
+//! The station's port on the terminal server is driven over a Telnet
+//! session. After the COM-PORT-OPTION is offered the line settings are
+//! sent as subnegotiations, e.g. to set 115200 baud:
+//!
+//! [IAC][SB][44][1][0x00 0x01 0xc2 0x00][IAC][SE]
+//!
+//! Data bytes equal to IAC (0xff) are doubled on the wire in both
+//! directions. Anything else the server sends outside of the data
+//! stream (option negotiation, acknowledgements, modem state
+//! notifications) is picked out while reading.
+use crate::log;
+use crate::serialport::{
+    DataBits, Error, ErrorKind, FlowControl, ModemStatus, Parity, Rate, Result, StopBits,
+};
+use crate::tcp::TcpTransport;
+use crate::transport::Transport;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Telnet commands
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+// Telnet options
+const OPT_BINARY: u8 = 0;
+const OPT_SGA: u8 = 3;
+const OPT_COM_PORT: u8 = 44;
+
+// COM-PORT-OPTION commands, as sent by the client. The server
+// answers with the same command plus SERVER_OFFSET.
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const NOTIFY_MODEMSTATE: u8 = 7;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+const SERVER_OFFSET: u8 = 100;
+
+// SET-CONTROL values
+const CONTROL_NONE: u8 = 1;
+const CONTROL_XONXOFF: u8 = 2;
+const CONTROL_HARDWARE: u8 = 3;
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+
+// PURGE-DATA values
+const PURGE_RX: u8 = 1;
+const PURGE_BOTH: u8 = 3;
+
+// Modem state bits
+const MODEM_CD: u8 = 0x80;
+const MODEM_RI: u8 = 0x40;
+const MODEM_DSR: u8 = 0x20;
+const MODEM_CTS: u8 = 0x10;
+
+/// Where the reader is in the Telnet stream
+#[derive(Debug, PartialEq)]
+enum Parse {
+    Data,
+    /// Just seen an IAC
+    Iac,
+    /// Waiting on the option of a WILL/WONT/DO/DONT
+    Negotiate(u8),
+    /// Inside a subnegotiation
+    Sub(Vec<u8>),
+    /// IAC inside a subnegotiation
+    SubIac(Vec<u8>),
+}
+
+pub struct Rfc2217Transport {
+    inner: TcpTransport,
+    baud: Rate,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+    parse: RefCell<Parse>,
+    /// Data picked out of the stream but not yet read
+    pending: RefCell<VecDeque<u8>>,
+    /// Last modem state the server notified us of
+    modem: Cell<Option<u8>>,
+    dtr: Cell<bool>,
+}
+
+impl Rfc2217Transport {
+    /// Create a transport to `rfc2217://host:port` using 9600 8N1
+    /// and no flow control.
+    pub fn new(path: &str) -> Rfc2217Transport {
+        Rfc2217Transport {
+            inner: TcpTransport::new(path),
+            baud: Rate::from(9600),
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            parse: RefCell::new(Parse::Data),
+            pending: RefCell::new(VecDeque::new()),
+            modem: Cell::new(None),
+            dtr: Cell::new(true),
+        }
+    }
+    pub fn baud(mut self, baud: impl Into<Rate>) -> Rfc2217Transport {
+        self.baud = baud.into();
+        self
+    }
+    pub fn data_bits(mut self, data_bits: DataBits) -> Rfc2217Transport {
+        self.data_bits = data_bits;
+        self
+    }
+    pub fn parity(mut self, parity: Parity) -> Rfc2217Transport {
+        self.parity = parity;
+        self
+    }
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Rfc2217Transport {
+        self.stop_bits = stop_bits;
+        self
+    }
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Rfc2217Transport {
+        self.flow_control = flow_control;
+        self
+    }
+
+    fn send_raw(&self, bytes: &[u8]) -> Result<()> {
+        self.inner.write(bytes)?;
+        Ok(())
+    }
+
+    /// Send a COM-PORT-OPTION subnegotiation
+    fn com_port(&self, command: u8, value: &[u8]) -> Result<()> {
+        let mut sub = vec![IAC, SB, OPT_COM_PORT, command];
+        sub.extend_from_slice(&escape(value));
+        sub.extend_from_slice(&[IAC, SE]);
+        self.send_raw(&sub)
+    }
+
+    fn send_line_settings(&self) -> Result<()> {
+        self.com_port(SET_BAUDRATE, &self.baud.bps().to_be_bytes())?;
+        self.com_port(
+            SET_DATASIZE,
+            &[match self.data_bits {
+                DataBits::Five => 5,
+                DataBits::Six => 6,
+                DataBits::Seven => 7,
+                DataBits::Eight => 8,
+            }],
+        )?;
+        self.com_port(
+            SET_PARITY,
+            &[match self.parity {
+                Parity::None => 1,
+                Parity::Odd => 2,
+                Parity::Even => 3,
+            }],
+        )?;
+        self.com_port(
+            SET_STOPSIZE,
+            &[match self.stop_bits {
+                StopBits::One => 1,
+                StopBits::Two => 2,
+            }],
+        )?;
+        self.com_port(
+            SET_CONTROL,
+            &[match self.flow_control {
+                FlowControl::None => CONTROL_NONE,
+                FlowControl::Software => CONTROL_XONXOFF,
+                FlowControl::Hardware => CONTROL_HARDWARE,
+            }],
+        )
+    }
+
+    /// Answer an option request. Binary mode, suppress-go-ahead and the
+    /// COM port option are accepted, everything else refused.
+    fn negotiate(&self, command: u8, option: u8) -> Result<()> {
+        let wanted = matches!(option, OPT_BINARY | OPT_SGA | OPT_COM_PORT);
+        let reply = match (command, wanted) {
+            (DO, true) | (WILL, true) => return Ok(()),
+            (DO, false) => WONT,
+            (WILL, false) => DONT,
+            // Already off on our side
+            _ => return Ok(()),
+        };
+        self.send_raw(&[IAC, reply, option])
+    }
+
+    fn subnegotiation(&self, sub: &[u8]) {
+        match sub {
+            [OPT_COM_PORT, command, value @ ..] if *command > SERVER_OFFSET => {
+                match command - SERVER_OFFSET {
+                    NOTIFY_MODEMSTATE => {
+                        if let Some(state) = value.first() {
+                            self.modem.set(Some(*state));
+                        }
+                    }
+                    c => log::debug(&format!("RFC 2217: server set {}: {:?}", c, value)),
+                }
+            }
+            _ => log::debug(&format!("RFC 2217: ignoring subnegotiation {:?}", sub)),
+        }
+    }
+
+    /// Have the server purge its buffers and drop what has already
+    /// been recieved here.
+    fn purge(&self, buffers: u8) -> Result<()> {
+        self.com_port(PURGE_DATA, &[buffers])?;
+        // Anything already here still has to go through the parser
+        // so negotiation and notifications aren't lost with the data
+        self.inner.read_available(|bytes| self.feed(bytes))?;
+        self.pending.borrow_mut().clear();
+        Ok(())
+    }
+
+    /// Run recieved bytes through the Telnet parser, queuing data
+    /// bytes and handling everything else.
+    fn feed(&self, bytes: &[u8]) -> Result<()> {
+        for &b in bytes {
+            let state = self.parse.replace(Parse::Data);
+            let next = match state {
+                Parse::Data if b == IAC => Parse::Iac,
+                Parse::Data => {
+                    self.pending.borrow_mut().push_back(b);
+                    Parse::Data
+                }
+                Parse::Iac => match b {
+                    IAC => {
+                        self.pending.borrow_mut().push_back(IAC);
+                        Parse::Data
+                    }
+                    WILL | WONT | DO | DONT => Parse::Negotiate(b),
+                    SB => Parse::Sub(Vec::new()),
+                    _ => Parse::Data,
+                },
+                Parse::Negotiate(command) => {
+                    self.negotiate(command, b)?;
+                    Parse::Data
+                }
+                Parse::Sub(sub) if b == IAC => Parse::SubIac(sub),
+                Parse::Sub(mut sub) => {
+                    sub.push(b);
+                    Parse::Sub(sub)
+                }
+                Parse::SubIac(sub) if b == SE => {
+                    self.subnegotiation(&sub);
+                    Parse::Data
+                }
+                Parse::SubIac(mut sub) => {
+                    sub.push(b);
+                    Parse::Sub(sub)
+                }
+            };
+            self.parse.replace(next);
+        }
+        Ok(())
+    }
+}
+
+/// Double any IAC bytes in data
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        out.push(b);
+        if b == IAC {
+            out.push(IAC);
+        }
+    }
+    out
+}
+
+impl Transport for Rfc2217Transport {
+    fn open(&mut self) -> Result<()> {
+        self.inner.connect()?;
+        self.parse.replace(Parse::Data);
+        self.pending.borrow_mut().clear();
+        self.modem.set(None);
+        let opened = self
+            .send_raw(&[
+                IAC,
+                WILL,
+                OPT_COM_PORT,
+                IAC,
+                WILL,
+                OPT_BINARY,
+                IAC,
+                DO,
+                OPT_BINARY,
+                IAC,
+                WILL,
+                OPT_SGA,
+                IAC,
+                DO,
+                OPT_SGA,
+            ])
+            .and_then(|_| self.send_line_settings())
+            // Have the server tell us about every modem line change
+            .and_then(|_| self.com_port(SET_MODEMSTATE_MASK, &[0xff]));
+        if let Err(e) = opened {
+            let _ = self.inner.close();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.inner.close()
+    }
+
+    fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize> {
+        let mut buf = vec![0; arr.len().max(64)];
+        while self.pending.borrow().is_empty() {
+            let n = self.inner.read_until_deadline(&mut buf, deadline)?;
+            self.feed(&buf[..n])?;
+        }
+        let mut pending = self.pending.borrow_mut();
+        let n = arr.len().min(pending.len());
+        for (dst, src) in arr.iter_mut().zip(pending.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, arr: &[u8]) -> Result<usize> {
+        self.send_raw(&escape(arr))?;
+        Ok(arr.len())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.purge(PURGE_BOTH)
+    }
+
+    fn flush_input(&self) -> Result<()> {
+        self.purge(PURGE_RX)
+    }
+
+    fn drain(&self) -> Result<()> {
+        self.inner.drain()
+    }
+
+    fn set_baud(&mut self, baud: Rate) -> Result<()> {
+        self.baud = baud;
+        match self.inner.stream() {
+            Ok(_) => self.com_port(SET_BAUDRATE, &baud.bps().to_be_bytes()),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn set_path(&mut self, path: &str) {
+        self.inner.set_path(path)
+    }
+
+    fn path(&self) -> &str {
+        self.inner.path()
+    }
+
+    /// The input lines as last notified by the server
+    fn modem_status(&self) -> Result<ModemStatus> {
+        self.inner.stream()?;
+        let state = match self.modem.get() {
+            Some(s) => s,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Unknown,
+                    "No modem state recieved from the server",
+                ))
+            }
+        };
+        Ok(ModemStatus {
+            dtr: self.dtr.get(),
+            rts: false,
+            cts: state & MODEM_CTS != 0,
+            dsr: state & MODEM_DSR != 0,
+            dcd: state & MODEM_CD != 0,
+            ri: state & MODEM_RI != 0,
+        })
+    }
+
+    fn pulse_dtr(&self, duration: Duration) -> Result<()> {
+        self.com_port(SET_CONTROL, &[CONTROL_DTR_OFF])?;
+        self.dtr.set(false);
+        std::thread::sleep(duration);
+        self.com_port(SET_CONTROL, &[CONTROL_DTR_ON])?;
+        self.dtr.set(true);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Read from conn until the bytes read end with pattern
+    fn read_until(conn: &mut std::net::TcpStream, pattern: &[u8]) -> Vec<u8> {
+        let mut seen = Vec::new();
+        let mut b = [0; 1];
+        while !seen.ends_with(pattern) {
+            conn.read_exact(&mut b).unwrap();
+            seen.push(b[0]);
+        }
+        seen
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(vec![1, IAC, IAC, 2], escape(&[1, IAC, 2]));
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("rfc2217://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let setup = read_until(&mut conn, &[IAC, SB, OPT_COM_PORT, SET_CONTROL, 1, IAC, SE]);
+            assert!(setup.starts_with(&[IAC, WILL, OPT_COM_PORT]));
+            let baud = [
+                IAC,
+                SB,
+                OPT_COM_PORT,
+                SET_BAUDRATE,
+                0x00,
+                0x01,
+                0xc2,
+                0x00,
+                IAC,
+                SE,
+            ];
+            assert!(setup.windows(baud.len()).any(|w| w == baud));
+            let parity = [IAC, SB, OPT_COM_PORT, SET_PARITY, 3, IAC, SE];
+            assert!(setup.windows(parity.len()).any(|w| w == parity));
+
+            // An option we don't want, a modem state notification
+            // and data with an escaped IAC in it
+            let mut reply = vec![IAC, DO, 24];
+            reply.extend_from_slice(&[
+                IAC,
+                SB,
+                OPT_COM_PORT,
+                SERVER_OFFSET + NOTIFY_MODEMSTATE,
+                MODEM_CD | MODEM_CTS,
+                IAC,
+                SE,
+            ]);
+            reply.extend_from_slice(&[1, IAC, IAC, 2]);
+            conn.write_all(&reply).unwrap();
+
+            read_until(&mut conn, &[IAC, WONT, 24]);
+            read_until(&mut conn, &[IAC, IAC, 3]);
+            let baud = [
+                IAC,
+                SB,
+                OPT_COM_PORT,
+                SET_BAUDRATE,
+                0x00,
+                0x00,
+                0x25,
+                0x80,
+                IAC,
+                SE,
+            ];
+            read_until(&mut conn, &baud);
+        });
+
+        let mut transport = Rfc2217Transport::new(&path)
+            .baud(115200)
+            .parity(Parity::Even);
+        transport.open().unwrap();
+        let mut buf = [0; 3];
+        transport
+            .read_exact_until_deadline(&mut buf, Instant::now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!([1, IAC, 2], buf);
+        let status = transport.modem_status().unwrap();
+        assert!(status.dcd && status.cts && !status.dsr);
+
+        transport.write(&[IAC, 3]).unwrap();
+        transport.set_baud(Rate::from(9600)).unwrap();
+        server.join().unwrap();
+        transport.close().unwrap();
+    }
+}
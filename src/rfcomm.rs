@@ -0,0 +1,354 @@
+//! Module providing a Bluetooth RFCOMM transport.
+//!
+//! The station is reached by opening an RFCOMM socket straight to its
+//! adapter, so no `/dev/rfcomm0` has to be bound beforehand. The device
+//! is given as the adapter's address and RFCOMM channel:
+//!
+//! bt://AA:BB:CC:DD:EE:FF/1
+//!
+//! The channel defaults to 1 when left off. RFCOMM has no line settings,
+//! the rate is fixed by the station's Bluetooth module.
+use crate::serialport::{hung_up, wait_readable, wait_writable, Error, ErrorKind, Rate, Result};
+use crate::transport::{Transport, SCHEME_BLUETOOTH};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::sys::socket::{getsockopt, sockopt};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+const BTPROTO_RFCOMM: libc::c_int = 3;
+const CHANNEL_DEFAULT: u8 = 1;
+/// Paging a device takes a few seconds, one out of range would
+/// otherwise hold the connect up for the kernel's much longer timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// struct sockaddr_rc from <bluetooth/rfcomm.h>
+#[repr(C)]
+struct SockaddrRc {
+    rc_family: libc::sa_family_t,
+    /// Address with the bytes in reverse (little endian) order
+    rc_bdaddr: [u8; 6],
+    rc_channel: u8,
+}
+
+/// Address and channel of an RFCOMM device
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Address {
+    pub bdaddr: [u8; 6],
+    pub channel: u8,
+}
+
+impl Address {
+    /// Parse `AA:BB:CC:DD:EE:FF/1`, with or without the bt:// scheme.
+    pub fn parse(s: &str) -> Result<Address> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::Errno(Errno::EINVAL),
+                &format!("Invalid Bluetooth address {}", s),
+            )
+        };
+        let s = s.strip_prefix(SCHEME_BLUETOOTH).unwrap_or(s);
+        let (mac, channel) = match s.split_once('/') {
+            Some((mac, "")) => (mac, CHANNEL_DEFAULT),
+            Some((mac, c)) => (mac, c.parse().map_err(|_| invalid())?),
+            None => (s, CHANNEL_DEFAULT),
+        };
+        // RFCOMM channels run from 1 to 30
+        if !(1..=30).contains(&channel) {
+            return Err(invalid());
+        }
+        let octets: Vec<&str> = mac.split(':').collect();
+        if octets.len() != 6 {
+            return Err(invalid());
+        }
+        let mut bdaddr = [0; 6];
+        for (i, octet) in octets.iter().enumerate() {
+            if octet.len() != 2 {
+                return Err(invalid());
+            }
+            bdaddr[i] = u8::from_str_radix(octet, 16).map_err(|_| invalid())?;
+        }
+        Ok(Address { bdaddr, channel })
+    }
+
+    fn sockaddr(&self) -> SockaddrRc {
+        let mut rc_bdaddr = self.bdaddr;
+        rc_bdaddr.reverse();
+        SockaddrRc {
+            rc_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            rc_bdaddr,
+            rc_channel: self.channel,
+        }
+    }
+}
+
+/// Map errors from creating and connecting the socket. A kernel
+/// without Bluetooth is reported as unsupported, an adapter or
+/// station that can't be reached as a disconnect so it is retried.
+fn socket_error(e: Errno) -> Error {
+    match e {
+        Errno::EAFNOSUPPORT | Errno::EPROTONOSUPPORT | Errno::ESOCKTNOSUPPORT => Error::new(
+            ErrorKind::Unsupported,
+            "Bluetooth RFCOMM is not supported on this system",
+        ),
+        Errno::EHOSTDOWN
+        | Errno::EHOSTUNREACH
+        | Errno::ECONNREFUSED
+        | Errno::ECONNRESET
+        | Errno::ETIMEDOUT
+        | Errno::ENODEV => Error::new(ErrorKind::Disconnected, e.desc()),
+        _ => e.into(),
+    }
+}
+
+/// Connect the socket to addr, giving up at the deadline. The connect
+/// is made non-blocking and waited for with poll, the socket is left
+/// blocking again once connected.
+fn connect(
+    fd: RawFd,
+    addr: *const libc::sockaddr,
+    len: libc::socklen_t,
+    deadline: Instant,
+) -> Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    if unsafe { libc::connect(fd, addr, len) } < 0 {
+        match Errno::last() {
+            // Interrupted connects carry on in the background too
+            Errno::EINPROGRESS | Errno::EINTR => (),
+            e => return Err(socket_error(e)),
+        }
+        if !wait_writable(fd, deadline)? {
+            return Err(socket_error(Errno::ETIMEDOUT));
+        }
+        match getsockopt(fd, sockopt::SocketError)? {
+            0 => (),
+            e => return Err(socket_error(Errno::from_i32(e))),
+        }
+    }
+    fcntl(fd, FcntlArg::F_SETFL(flags))?;
+    Ok(())
+}
+
+pub struct RfcommTransport {
+    path: String,
+    fd: Option<RawFd>,
+}
+
+impl RfcommTransport {
+    /// Create a transport to `bt://AA:BB:CC:DD:EE:FF/channel`
+    pub fn new(path: &str) -> RfcommTransport {
+        RfcommTransport {
+            path: path.to_string(),
+            fd: None,
+        }
+    }
+
+    fn fd(&self) -> Result<RawFd> {
+        match self.fd {
+            Some(fd) => Ok(fd),
+            None => Err(Error::new(ErrorKind::PortClosed, "Connection is not open")),
+        }
+    }
+}
+
+impl Transport for RfcommTransport {
+    fn open(&mut self) -> Result<()> {
+        let addr = Address::parse(&self.path)?;
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                BTPROTO_RFCOMM,
+            )
+        };
+        if fd < 0 {
+            return Err(socket_error(Errno::last()));
+        }
+        let sockaddr = addr.sockaddr();
+        let connected = connect(
+            fd,
+            &sockaddr as *const SockaddrRc as *const libc::sockaddr,
+            std::mem::size_of::<SockaddrRc>() as libc::socklen_t,
+            Instant::now() + CONNECT_TIMEOUT,
+        );
+        if let Err(e) = connected {
+            let _ = nix::unistd::close(fd);
+            return Err(e);
+        }
+        self.fd = Some(fd);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.fd.take() {
+            Some(fd) => {
+                nix::unistd::close(fd)?;
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::PortClosed, "Connection is not open")),
+        }
+    }
+
+    fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize> {
+        let fd = self.fd()?;
+        loop {
+            if !wait_readable(fd, deadline)? {
+                return Err(Error::new(
+                    ErrorKind::Timeout,
+                    "Timeout reached. No bytes read",
+                ));
+            }
+            match nix::unistd::read(fd, arr) {
+                Ok(0) => return Err(Error::new(ErrorKind::Disconnected, "Link closed")),
+                Ok(n) => return Ok(n),
+                Err(Errno::EINTR) | Err(Errno::EAGAIN) => (),
+                Err(e) => return Err(socket_error(e)),
+            }
+        }
+    }
+
+    fn write(&self, arr: &[u8]) -> Result<usize> {
+        let fd = self.fd()?;
+        let mut nbytes = 0;
+        while nbytes < arr.len() {
+            match nix::unistd::write(fd, &arr[nbytes..]) {
+                Ok(n) => nbytes += n,
+                Err(Errno::EINTR) => (),
+                Err(e) => return Err(socket_error(e)),
+            }
+        }
+        Ok(nbytes)
+    }
+
+    /// Throw away anything already received.
+    fn flush(&self) -> Result<()> {
+        let fd = self.fd()?;
+        let mut buf = [0; 256];
+        while wait_readable(fd, Instant::now())? {
+            if hung_up(fd) {
+                return Err(Error::new(ErrorKind::Disconnected, "Link closed"));
+            }
+            if let Ok(0) | Err(_) = nix::unistd::read(fd, &mut buf) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn drain(&self) -> Result<()> {
+        self.fd()?;
+        Ok(())
+    }
+
+    /// The rate is fixed by the station's Bluetooth module
+    fn set_baud(&mut self, _baud: Rate) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "The rate of an RFCOMM link is fixed by the station's Bluetooth module",
+        ))
+    }
+
+    fn set_path(&mut self, path: &str) {
+        self.path = path.to_string();
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for RfcommTransport {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        let addr = Address::parse("bt://AA:BB:CC:DD:EE:0f/3").unwrap();
+        assert_eq!([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f], addr.bdaddr);
+        assert_eq!(3, addr.channel);
+        assert_eq!(
+            [0x0f, 0xee, 0xdd, 0xcc, 0xbb, 0xaa],
+            addr.sockaddr().rc_bdaddr
+        );
+        assert_eq!(1, Address::parse("AA:BB:CC:DD:EE:FF").unwrap().channel);
+
+        for bad in [
+            "bt://AA:BB:CC:DD:EE/1",
+            "bt://AA:BB:CC:DD:EE:GG/1",
+            "bt://AA:BB:CC:DD:EE:FF/0",
+            "bt://AA:BB:CC:DD:EE:FF/x",
+            "bt://AAA:BB:CC:DD:EE:F/1",
+        ]
+        .iter()
+        {
+            assert!(Address::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_socket_errors() {
+        assert!(matches!(
+            socket_error(Errno::EAFNOSUPPORT).kind(),
+            ErrorKind::Unsupported
+        ));
+        assert!(matches!(
+            socket_error(Errno::EHOSTDOWN).kind(),
+            ErrorKind::Disconnected
+        ));
+        assert!(matches!(
+            socket_error(Errno::EACCES).kind(),
+            ErrorKind::Errno(Errno::EACCES)
+        ));
+    }
+
+    #[test]
+    fn test_open_errors() {
+        let transport = RfcommTransport::new("bt://00:11:22:33:44:55/1");
+        assert!(matches!(
+            transport.write(&[1]).unwrap_err().kind(),
+            ErrorKind::PortClosed
+        ));
+
+        let mut transport = RfcommTransport::new("bt://nonsense");
+        assert!(matches!(
+            transport.open().unwrap_err().kind(),
+            ErrorKind::Errno(Errno::EINVAL)
+        ));
+    }
+
+    // Bluetooth isn't around to test against, TCP over loopback goes
+    // through the same non-blocking connect
+    #[test]
+    fn test_connect() {
+        use std::net::{SocketAddr, TcpListener};
+
+        let connect_to = |addr: SocketAddr| {
+            let sockaddr = nix::sys::socket::InetAddr::from_std(&addr);
+            let sockaddr = nix::sys::socket::SockAddr::new_inet(sockaddr);
+            let (ptr, len) = sockaddr.as_ffi_pair();
+            let fd =
+                unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+            let res = connect(fd, ptr, len, Instant::now() + Duration::from_secs(1));
+            let blocking = fcntl(fd, FcntlArg::F_GETFL).unwrap() & libc::O_NONBLOCK == 0;
+            nix::unistd::close(fd).unwrap();
+            res.map(|_| blocking)
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(connect_to(addr).unwrap());
+        drop(listener);
+        assert!(matches!(
+            connect_to(addr).unwrap_err().kind(),
+            ErrorKind::Disconnected
+        ));
+    }
+}
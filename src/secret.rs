@@ -0,0 +1,179 @@
+//! Module for keeping secrets, such as the InfluxDB token, out of logs.
+//!
+//! A `Secret` prints as `[redacted]` through both `Debug` and `Display`
+//! so it can't end up in a log line by accident, the value has to be
+//! asked for with `expose`. Secrets can be kept out of the config file
+//! too, in a file only the controller can read or as a systemd
+//! credential (`LoadCredential=`), found in `$CREDENTIALS_DIRECTORY`.
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Printed in place of a secret
+pub const REDACTED: &str = "[redacted]";
+/// Set by systemd to the directory holding the unit's credentials
+pub const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+/// Config keys holding secrets
+pub const KEYS: &[&str] = &["db.api.key", "channel.auth.key"];
+
+/// True if the config key is a secret, or a table holding one
+pub fn is_secret_key(key: &str) -> bool {
+    KEYS.iter().any(|k| {
+        *k == key
+            || k.strip_prefix(key)
+                .is_some_and(|rest| rest.starts_with('.'))
+    })
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    /// The secret itself. Don't log it.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(REDACTED)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// The file couldn't be read
+    Io(std::io::ErrorKind),
+    /// Anyone on the system can read the file
+    WorldReadable,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    description: String,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.description)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Read a secret from the file at path with any trailing newline
+/// removed, refusing files anyone can read.
+pub fn read_file(path: &Path) -> Result<Secret<String>> {
+    let io_error = |e: std::io::Error| Error {
+        kind: ErrorKind::Io(e.kind()),
+        description: format!("Can't read {}: {}", path.display(), e),
+    };
+    let mode = fs::metadata(path).map_err(io_error)?.permissions().mode();
+    if mode & 0o004 != 0 {
+        return Err(Error {
+            kind: ErrorKind::WorldReadable,
+            description: format!(
+                "{} is readable by everyone, restrict it with chmod o-r",
+                path.display()
+            ),
+        });
+    }
+    let text = fs::read_to_string(path).map_err(io_error)?;
+    Ok(Secret(text.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// The unit's credentials directory when running with credentials
+pub fn credentials_dir() -> Option<OsString> {
+    env::var_os(CREDENTIALS_DIRECTORY)
+}
+
+/// Where to look for a secret file. A relative path names a systemd
+/// credential when running with credentials.
+pub fn resolve(path: &str, credentials: Option<&OsStr>) -> PathBuf {
+    match credentials {
+        Some(dir) if Path::new(path).is_relative() => Path::new(dir).join(path),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Path of the systemd credential called name, if there is one
+pub fn credential(name: &str, credentials: Option<&OsStr>) -> Option<PathBuf> {
+    let path = Path::new(credentials?).join(name);
+    path.exists().then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let secret = Secret::new("hunter2".to_string());
+        assert_eq!("[redacted]", format!("{}", secret));
+        assert_eq!("Some([redacted])", format!("{:?}", Some(&secret)));
+        assert_eq!("hunter2", secret.expose());
+        assert!(is_secret_key("db.api.key"));
+        assert!(is_secret_key("db.api"));
+        assert!(!is_secret_key("db.api.key_file"));
+        assert!(!is_secret_key("db.api.endpoint"));
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_secret_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token");
+        fs::write(&path, "s3cr3t\n").unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!("s3cr3t", read_file(&path).unwrap().expose());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let e = read_file(&path).unwrap_err();
+        assert_eq!(ErrorKind::WorldReadable, *e.kind());
+        assert!(!e.to_string().contains("s3cr3t"));
+
+        assert!(matches!(
+            read_file(&dir.join("missing")).unwrap_err().kind(),
+            ErrorKind::Io(std::io::ErrorKind::NotFound)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve() {
+        let creds = Some(OsStr::new("/run/credentials/tw_ctrl.service"));
+        assert_eq!(
+            PathBuf::from("/run/credentials/tw_ctrl.service/influx"),
+            resolve("influx", creds)
+        );
+        assert_eq!(
+            PathBuf::from("/run/secrets/influx"),
+            resolve("/run/secrets/influx", creds)
+        );
+        assert_eq!(PathBuf::from("influx"), resolve("influx", None));
+    }
+}
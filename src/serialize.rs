@@ -1,3 +1,4 @@
+#[allow(dead_code)]
 pub trait Serializable {
     type Error;
     fn serialize(&self) -> Result<Vec<u8>, Self::Error>;
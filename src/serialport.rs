@@ -1,14 +1,16 @@
 //! Module for opening Serial devices
-use crate::log::{debug, log};
-use crate::termios;
+use crate::lock::{LockError, LockFile, LOCK_DIR_DEFAULT};
+use crate::log::{debug, warn};
 use nix::fcntl::{self, OFlag};
 use nix::sys::stat::Mode;
 pub use nix::sys::termios::BaudRate;
+use nix::sys::termios::Termios;
 use std::os::unix::io::RawFd;
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use crate::termios::{get_termios, set_termios};
+use crate::termios::{get_termios, set_termios, Snapshot};
 use std::error::Error as stderr;
 use std::fmt;
 
@@ -18,6 +20,12 @@ pub enum ErrorKind {
     Unknown,
     PortClosed,
     Timeout,
+    /// The device went away or hung up (unplugged, link dropped)
+    Disconnected,
+    /// The system can't drive this kind of device (e.g. no Bluetooth)
+    Unsupported,
+    /// The device is in use, by the process with this PID if known
+    Busy(Option<u32>),
     Errno(nix::errno::Errno),
 }
 
@@ -61,18 +69,352 @@ impl Error {
 //it will be fine to just wrap Errno in my enum
 impl From<nix::errno::Errno> for Error {
     fn from(e: nix::errno::Errno) -> Error {
-        Error::new(ErrorKind::Errno(e), e.desc())
+        use nix::errno::Errno;
+        match e {
+            Errno::EIO | Errno::ENXIO | Errno::ENODEV => {
+                Error::new(ErrorKind::Disconnected, e.desc())
+            }
+            Errno::EBUSY => Error::new(ErrorKind::Busy(None), e.desc()),
+            _ => Error::new(ErrorKind::Errno(e), e.desc()),
+        }
+    }
+}
+
+/// Number of data bits per character
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FlowControl {
+    None,
+    /// RTS/CTS
+    Hardware,
+    /// XON/XOFF
+    Software,
+}
+
+impl FromStr for DataBits {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<DataBits, String> {
+        match s {
+            "5" => Ok(DataBits::Five),
+            "6" => Ok(DataBits::Six),
+            "7" => Ok(DataBits::Seven),
+            "8" => Ok(DataBits::Eight),
+            _ => Err(format!("Not an available number of data bits: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Parity {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Parity, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Parity::None),
+            "odd" => Ok(Parity::Odd),
+            "even" => Ok(Parity::Even),
+            _ => Err(format!("Not an available parity: {}", s)),
+        }
+    }
+}
+
+impl FromStr for StopBits {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<StopBits, String> {
+        match s {
+            "1" => Ok(StopBits::One),
+            "2" => Ok(StopBits::Two),
+            _ => Err(format!("Not an available number of stop bits: {}", s)),
+        }
+    }
+}
+
+impl FromStr for FlowControl {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<FlowControl, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(FlowControl::None),
+            "rtscts" | "hardware" => Ok(FlowControl::Hardware),
+            "xonxoff" | "software" => Ok(FlowControl::Software),
+            _ => Err(format!("Not an available flow control: {}", s)),
+        }
+    }
+}
+
+/// Baud rate of a serial port.
+///
+/// Rates termios has a constant for are set the usual way. Anything
+/// else is set through the Linux termios2 interface (BOTHER), which
+/// most USB serial adapters support.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Rate {
+    Standard(BaudRate),
+    Custom(u32),
+}
+
+/// Every standard rate termios defines on Linux, B0 (hang up) aside.
+const STANDARD_RATES: [(u32, BaudRate); 30] = [
+    (50, BaudRate::B50),
+    (75, BaudRate::B75),
+    (110, BaudRate::B110),
+    (134, BaudRate::B134),
+    (150, BaudRate::B150),
+    (200, BaudRate::B200),
+    (300, BaudRate::B300),
+    (600, BaudRate::B600),
+    (1200, BaudRate::B1200),
+    (1800, BaudRate::B1800),
+    (2400, BaudRate::B2400),
+    (4800, BaudRate::B4800),
+    (9600, BaudRate::B9600),
+    (19200, BaudRate::B19200),
+    (38400, BaudRate::B38400),
+    (57600, BaudRate::B57600),
+    (115200, BaudRate::B115200),
+    (230400, BaudRate::B230400),
+    (460800, BaudRate::B460800),
+    (500000, BaudRate::B500000),
+    (576000, BaudRate::B576000),
+    (921600, BaudRate::B921600),
+    (1000000, BaudRate::B1000000),
+    (1152000, BaudRate::B1152000),
+    (1500000, BaudRate::B1500000),
+    (2000000, BaudRate::B2000000),
+    (2500000, BaudRate::B2500000),
+    (3000000, BaudRate::B3000000),
+    (3500000, BaudRate::B3500000),
+    (4000000, BaudRate::B4000000),
+];
+
+impl Rate {
+    /// Rate in bits per second
+    pub fn bps(&self) -> u32 {
+        match self {
+            Rate::Standard(b) => STANDARD_RATES
+                .iter()
+                .find(|(_, rate)| rate == b)
+                .map(|(n, _)| *n)
+                .unwrap_or(0),
+            Rate::Custom(n) => *n,
+        }
+    }
+}
+
+impl From<u32> for Rate {
+    fn from(n: u32) -> Rate {
+        match STANDARD_RATES.iter().find(|(bps, _)| *bps == n) {
+            Some((_, b)) => Rate::Standard(*b),
+            None => Rate::Custom(n),
+        }
+    }
+}
+
+impl From<BaudRate> for Rate {
+    fn from(b: BaudRate) -> Rate {
+        Rate::Standard(b)
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.bps())
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Rate, String> {
+        match s.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(Rate::from(n)),
+            _ => Err(format!("Not a valid baud rate: {}", s)),
+        }
+    }
+}
+
+mod ioctl {
+    use nix::libc;
+    nix::ioctl_read_bad!(tcgets2, libc::TCGETS2, libc::termios2);
+    nix::ioctl_write_ptr_bad!(tcsets2, libc::TCSETS2, libc::termios2);
+    nix::ioctl_none_bad!(tiocexcl, libc::TIOCEXCL);
+    nix::ioctl_write_ptr_bad!(tiocmbis, libc::TIOCMBIS, libc::c_int);
+    nix::ioctl_write_ptr_bad!(tiocmbic, libc::TIOCMBIC, libc::c_int);
+    nix::ioctl_read_bad!(tiocmget, libc::TIOCMGET, libc::c_int);
+    nix::ioctl_write_ptr_bad!(tiocmset, libc::TIOCMSET, libc::c_int);
+    nix::ioctl_none_bad!(tiocsbrk, libc::TIOCSBRK);
+    nix::ioctl_none_bad!(tioccbrk, libc::TIOCCBRK);
+    nix::ioctl_write_int_bad!(tiocmiwait, libc::TIOCMIWAIT);
+}
+
+/// Modem input lines that can be waited on
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ModemLine {
+    /// Clear To Send
+    Cts,
+    /// Data Set Ready
+    Dsr,
+    /// Data Carrier Detect
+    Dcd,
+    /// Ring Indicator
+    Ri,
+}
+
+impl ModemLine {
+    fn bit(&self) -> nix::libc::c_int {
+        use nix::libc::{TIOCM_CAR, TIOCM_CTS, TIOCM_DSR, TIOCM_RNG};
+        match self {
+            ModemLine::Cts => TIOCM_CTS,
+            ModemLine::Dsr => TIOCM_DSR,
+            ModemLine::Dcd => TIOCM_CAR,
+            ModemLine::Ri => TIOCM_RNG,
+        }
+    }
+}
+
+/// State of the modem control lines
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct ModemStatus {
+    pub dtr: bool,
+    pub rts: bool,
+    pub cts: bool,
+    pub dsr: bool,
+    pub dcd: bool,
+    pub ri: bool,
+}
+
+impl ModemStatus {
+    fn from_bits(bits: nix::libc::c_int) -> ModemStatus {
+        use nix::libc::{TIOCM_DTR, TIOCM_RTS};
+        let set = |bit| bits & bit != 0;
+        ModemStatus {
+            dtr: set(TIOCM_DTR),
+            rts: set(TIOCM_RTS),
+            cts: set(ModemLine::Cts.bit()),
+            dsr: set(ModemLine::Dsr.bit()),
+            dcd: set(ModemLine::Dcd.bit()),
+            ri: set(ModemLine::Ri.bit()),
+        }
     }
 }
 
 pub struct SerialPort {
     fd: Option<RawFd>,
     path: String,
-    baud: BaudRate,
+    baud: Rate,
     timeout: Duration,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+    vmin: u8,
+    exclusive: bool,
+    lock_dir: PathBuf,
+    lock: Option<LockFile>,
+    /// Settings the device had before it was opened
+    saved: Option<Snapshot>,
+    dtr: Option<bool>,
+    rts: Option<bool>,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Builder used to configure a serial port before it is opened.
+///
+/// Defaults to 9600 8N1, no flow control, a zero timeout, VMIN of
+/// zero, exclusive access and the DTR/RTS lines left as they are.
+pub struct SerialPortBuilder {
+    port: SerialPort,
+}
+
+impl SerialPortBuilder {
+    pub fn new(path: &str) -> SerialPortBuilder {
+        SerialPortBuilder {
+            port: SerialPort::new(path, BaudRate::B9600, Duration::from_secs(0)),
+        }
+    }
+    pub fn baud(mut self, baud: impl Into<Rate>) -> SerialPortBuilder {
+        self.port.baud = baud.into();
+        self
+    }
+    /// How long a read waits for bytes to arrive
+    pub fn timeout(mut self, timeout: Duration) -> SerialPortBuilder {
+        self.port.timeout = timeout;
+        self
+    }
+    pub fn data_bits(mut self, data_bits: DataBits) -> SerialPortBuilder {
+        self.port.data_bits = data_bits;
+        self
+    }
+    pub fn parity(mut self, parity: Parity) -> SerialPortBuilder {
+        self.port.parity = parity;
+        self
+    }
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> SerialPortBuilder {
+        self.port.stop_bits = stop_bits;
+        self
+    }
+    pub fn flow_control(mut self, flow_control: FlowControl) -> SerialPortBuilder {
+        self.port.flow_control = flow_control;
+        self
+    }
+    /// Minimum number of bytes a read waits for (VMIN).
+    ///
+    /// With VMIN > 0 the timeout becomes an interbyte timeout -- the
+    /// timer only starts after the first byte has been recieved. Leave
+    /// it at zero for the timer to start at the call to read.
+    pub fn vmin(mut self, vmin: u8) -> SerialPortBuilder {
+        self.port.vmin = vmin;
+        self
+    }
+    /// Stop other processes from opening the device (TIOCEXCL and
+    /// a UUCP lock file)
+    pub fn exclusive(mut self, exclusive: bool) -> SerialPortBuilder {
+        self.port.exclusive = exclusive;
+        self
+    }
+    /// Directory the lock file is created in
+    pub fn lock_dir(mut self, dir: &str) -> SerialPortBuilder {
+        self.port.lock_dir = PathBuf::from(dir);
+        self
+    }
+    /// State to put the DTR line in once open
+    pub fn dtr(mut self, on: bool) -> SerialPortBuilder {
+        self.port.dtr = Some(on);
+        self
+    }
+    /// State to put the RTS line in once open
+    pub fn rts(mut self, on: bool) -> SerialPortBuilder {
+        self.port.rts = Some(on);
+        self
+    }
+    /// Build the serial port without opening it
+    pub fn build(self) -> SerialPort {
+        self.port
+    }
+    /// Build and open the serial port
+    pub fn open(self) -> Result<SerialPort> {
+        let mut port = self.port;
+        port.open()?;
+        Ok(port)
+    }
+}
+
 impl Drop for SerialPort {
     fn drop(&mut self) {
         let _ = self.close();
@@ -80,13 +422,26 @@ impl Drop for SerialPort {
 }
 
 impl SerialPort {
-    ///Create a new serial port.
-    pub fn new(path: &str, baud: BaudRate, timeout: Duration) -> SerialPort {
+    ///Create a new serial port using 8N1 and no flow control.
+    ///
+    ///Use `SerialPortBuilder` for any other line settings.
+    pub fn new(path: &str, baud: impl Into<Rate>, timeout: Duration) -> SerialPort {
         SerialPort {
             path: path.into(),
             fd: None,
-            baud,
+            baud: baud.into(),
             timeout,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            vmin: 0,
+            exclusive: true,
+            lock_dir: PathBuf::from(LOCK_DIR_DEFAULT),
+            lock: None,
+            saved: None,
+            dtr: None,
+            rts: None,
         }
     }
 
@@ -102,31 +457,83 @@ impl SerialPort {
         }
     }
     /// Read bytes from the serial port into
-    /// the the supplied array
+    /// the the supplied array, waiting up to the
+    /// port's timeout for them to arrive.
     pub fn read(&self, arr: &mut [u8]) -> Result<usize> {
+        self.read_until_deadline(arr, Instant::now() + self.timeout)
+    }
+
+    /// Read whatever is available into the supplied array, waiting
+    /// until the deadline for at least one byte to arrive.
+    pub fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize> {
+        use nix::errno::Errno;
         use nix::unistd::read;
-        match self.fd {
-            Some(fd) => match read(fd, arr) {
-                Ok(n) => {
-                    if n == 0 {
-                        return Err(Error::new(
-                            ErrorKind::Timeout,
-                            "Timeout reached. No bytes read",
-                        ));
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        loop {
+            if !wait_readable(fd, deadline)? {
+                return Err(Error::new(
+                    ErrorKind::Timeout,
+                    "Timeout reached. No bytes read",
+                ));
+            }
+            match read(fd, arr) {
+                Ok(0) => {
+                    if hung_up(fd) {
+                        return Err(Error::new(ErrorKind::Disconnected, "Device hung up"));
                     }
-                    Ok(n)
+                    // Readable but empty, e.g. the input was flushed
+                    // in the meantime. Wait out the rest of the deadline.
                 }
+                Ok(n) => return Ok(n),
+                Err(Errno::EINTR) | Err(Errno::EAGAIN) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
+    /// Fill the supplied array, failing with a timeout if it
+    /// isn't full by the deadline.
+    pub fn read_exact_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<()> {
+        let mut nbytes = 0;
+        while nbytes < arr.len() {
+            nbytes += self.read_until_deadline(&mut arr[nbytes..], deadline)?;
+        }
+        Ok(())
+    }
+
+    /// Fill the supplied array, failing with a timeout if it
+    /// isn't full within the given time. Unlike the port's timeout
+    /// this bounds the read as a whole rather than each wait.
+    pub fn read_exact_timeout(&self, arr: &mut [u8], timeout: Duration) -> Result<()> {
+        self.read_exact_until_deadline(arr, Instant::now() + timeout)
+    }
+
+    /// Wait until everything written has been transmitted.
+    pub fn drain(&self) -> Result<()> {
+        use nix::sys::termios::tcdrain;
+        match self.fd {
+            Some(fd) => match tcdrain(fd) {
+                Ok(_) => Ok(()),
                 Err(e) => Err(e.into()),
             },
             None => Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
         }
     }
 
-    /// Close the serial port.
+    /// Close the serial port, putting back the settings it had
+    /// before it was opened and releasing the lock.
     pub fn close(&mut self) -> Result<()> {
         use nix::unistd::close;
-        match self.fd {
+        if let (Some(fd), Some(saved)) = (self.fd, self.saved.take()) {
+            if let Err(e) = saved.restore(fd) {
+                debug(&format!("Serial: could not restore settings: {}", e));
+            }
+        }
+        self.lock = None;
+        match self.fd.take() {
             Some(fd) => match close(fd) {
                 Ok(_) => Ok(()),
                 Err(e) => Err(e.into()),
@@ -145,19 +552,36 @@ impl SerialPort {
         }
     }
 
-    //TODO: Add some way to configure the port
-    //before you open. Might actually implement
-    //a builder pattern.
+    /// Discard anything recieved but not yet read, leaving queued
+    /// output to go out.
+    pub fn flush_input(&self) -> Result<()> {
+        use nix::sys::termios::{tcflush, FlushArg};
+        match self.fd {
+            Some(fd) => match tcflush(fd, FlushArg::TCIFLUSH) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            None => Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        }
+    }
+
+    /// Set the device path. Takes effect the next time the
+    /// port is opened.
+    pub fn set_path(&mut self, path: &str) {
+        self.path = path.to_string();
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     /// Open the serial port
     pub fn open(&mut self) -> Result<()> {
         use nix::fcntl::fcntl;
         use nix::fcntl::FcntlArg::F_SETFL;
-        use nix::sys::termios::{
-            cfsetispeed, cfsetospeed, ControlFlags, InputFlags, LocalFlags, OutputFlags,
-            SpecialCharacterIndices,
-        };
-        // Unwrapping for now, eventually I will
-        // replace with returning my own error
+        if self.exclusive {
+            self.lock()?;
+        }
         let mut fd = match fcntl::open(
             Path::new(&self.path),
             OFlag::O_NOCTTY | OFlag::O_RDWR | OFlag::O_NONBLOCK,
@@ -166,26 +590,110 @@ impl SerialPort {
             Ok(n) => n,
             Err(e) => {
                 debug(&format!("Serial: {:?}", e));
+                self.lock = None;
                 return Err(e.into());
             }
         };
-        let mut settings = get_termios(&fd)?;
+        self.fd = Some(fd);
+        let configured = get_termios(&fd).and_then(|mut settings| {
+            let saved = Snapshot::from(&settings);
+            self.saved = Some(saved);
+            self.configure(&mut settings)?;
+            set_termios(&mut fd, &settings)?;
+            for change in saved.diff(&Snapshot::from(&settings)) {
+                debug(&format!("Serial: {}", change));
+            }
+            fcntl(fd, F_SETFL(nix::fcntl::OFlag::empty()))?;
+            Ok(())
+        });
+        if let Err(e) = configured {
+            let _ = self.close();
+            return Err(e);
+        }
+
+        if let Err(e) = self
+            .apply_custom_rate()
+            .and_then(|_| self.apply_line_state())
+        {
+            let _ = self.close();
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        // just set it how I want
-        // until I figure out what I want to do with
-        // settings
-        settings.control_flags &= !ControlFlags::PARENB;
-        settings.control_flags &= !ControlFlags::CSTOPB;
+    /// Take the lock file for the device. A lock directory that is
+    /// missing or not writable only gets a warning, as not every
+    /// system has one.
+    fn lock(&mut self) -> Result<()> {
+        match LockFile::acquire(&self.lock_dir, &self.path) {
+            Ok(lock) => {
+                debug(&format!("Serial: locked with {}", lock.path().display()));
+                self.lock = Some(lock);
+                Ok(())
+            }
+            Err(LockError::Busy(Some(pid))) => Err(Error::new(
+                ErrorKind::Busy(Some(pid)),
+                &format!("Device {} is locked by process {}", self.path, pid),
+            )),
+            Err(LockError::Busy(None)) => Err(Error::new(
+                ErrorKind::Busy(None),
+                &format!("Device {} is locked by another process", self.path),
+            )),
+            Err(LockError::Io(kind)) => {
+                warn(&format!(
+                    "Serial: could not lock {} in {}: {:?}",
+                    self.path,
+                    self.lock_dir.display(),
+                    kind
+                ));
+                Ok(())
+            }
+        }
+    }
+
+    /// Apply the line settings to the termios settings
+    fn configure(&self, settings: &mut Termios) -> Result<()> {
+        use nix::sys::termios::{
+            cfsetispeed, cfsetospeed, ControlFlags, InputFlags, LocalFlags, OutputFlags,
+            SpecialCharacterIndices,
+        };
         settings.control_flags &= !ControlFlags::CSIZE;
-        settings.control_flags |= ControlFlags::CS8;
+        settings.control_flags |= match self.data_bits {
+            DataBits::Five => ControlFlags::CS5,
+            DataBits::Six => ControlFlags::CS6,
+            DataBits::Seven => ControlFlags::CS7,
+            DataBits::Eight => ControlFlags::CS8,
+        };
+        settings.control_flags &= !(ControlFlags::PARENB | ControlFlags::PARODD);
+        settings.input_flags &= !InputFlags::INPCK;
+        match self.parity {
+            Parity::None => (),
+            Parity::Odd => {
+                settings.control_flags |= ControlFlags::PARENB | ControlFlags::PARODD;
+                settings.input_flags |= InputFlags::INPCK;
+            }
+            Parity::Even => {
+                settings.control_flags |= ControlFlags::PARENB;
+                settings.input_flags |= InputFlags::INPCK;
+            }
+        }
+        match self.stop_bits {
+            StopBits::One => settings.control_flags &= !ControlFlags::CSTOPB,
+            StopBits::Two => settings.control_flags |= ControlFlags::CSTOPB,
+        }
         settings.control_flags &= !ControlFlags::CRTSCTS;
+        settings.input_flags &= !(InputFlags::IXON | InputFlags::IXOFF | InputFlags::IXANY);
+        match self.flow_control {
+            FlowControl::None => (),
+            FlowControl::Hardware => settings.control_flags |= ControlFlags::CRTSCTS,
+            FlowControl::Software => settings.input_flags |= InputFlags::IXON | InputFlags::IXOFF,
+        }
         settings.control_flags |= ControlFlags::CREAD | ControlFlags::CLOCAL;
         settings.local_flags &= !LocalFlags::ICANON;
         settings.local_flags &= !LocalFlags::ECHO;
         settings.local_flags &= !LocalFlags::ECHOE;
         settings.local_flags &= !LocalFlags::ECHONL;
         settings.local_flags &= !LocalFlags::ISIG;
-        settings.input_flags &= !(InputFlags::IXON | InputFlags::IXOFF | InputFlags::IXANY);
         settings.input_flags &= !(InputFlags::IGNBRK
             | InputFlags::BRKINT
             | InputFlags::PARMRK
@@ -194,34 +702,61 @@ impl SerialPort {
             | InputFlags::ICRNL);
         settings.output_flags &= !OutputFlags::OPOST;
         settings.output_flags &= !OutputFlags::ONLCR;
-        //Used for timeout and read behavior
+        //Read timeouts are handled with poll, so with VMIN at zero (the
+        //default) VTIME is zero as well and a read returns whatever has
+        //arrived as soon as the port is readable.
         //
-        //NOTE: VTIME's units are deciseconds
-        //control_chars is a &[u8] so the maximum time out using
-        // VTIME is 25.5 seconds which is 255 deciseconds
-        let vtime = {
-            let sec = self.timeout.as_secs_f32();
-            if sec > 25.5 {
-                255
-            } else {
-                // should give me seconds
-                // in deciseconds
-                (sec * 10.0) as u8
-            }
-        };
-        settings.control_chars[SpecialCharacterIndices::VTIME as usize] = vtime;
-
         //NOTE: Per the man pages of termios, VMIN > 0 and VTIME > 0 gives
         //an interbyte timeout -- the timer only starts AFTER the first bytes
-        //has been recieved and restarts each consecutive byte. Thus VMIN should be
-        //set to zero for normal timeout behavior where the timer is started
-        //after the call to read.
-        settings.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
-        cfsetospeed(&mut settings, self.baud)?;
-        cfsetispeed(&mut settings, self.baud)?;
-        set_termios(&mut fd, &settings)?;
-        fcntl(fd, F_SETFL(nix::fcntl::OFlag::empty()))?;
-        self.fd = Some(fd);
+        //has been recieved and restarts each consecutive byte. When VMIN is
+        //set the timeout is kept on VTIME for this.
+        settings.control_chars[SpecialCharacterIndices::VTIME as usize] = self.vtime();
+        settings.control_chars[SpecialCharacterIndices::VMIN as usize] = self.vmin;
+        // Custom rates are applied with termios2 once the
+        // rest of the settings are in place.
+        if let Rate::Standard(baud) = self.baud {
+            cfsetospeed(settings, baud)?;
+            cfsetispeed(settings, baud)?;
+        }
+        Ok(())
+    }
+
+    /// Set a non standard rate using termios2 and BOTHER
+    fn apply_custom_rate(&self) -> Result<()> {
+        use nix::libc::{termios2, BOTHER, CBAUD, IBSHIFT};
+        let (fd, bps) = match (self.fd, self.baud) {
+            (Some(fd), Rate::Custom(bps)) => (fd, bps),
+            (_, Rate::Standard(_)) => return Ok(()),
+            (None, _) => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        let mut tio: termios2 = unsafe { std::mem::zeroed() };
+        unsafe { ioctl::tcgets2(fd, &mut tio) }?;
+        tio.c_cflag &= !(CBAUD | (CBAUD << IBSHIFT));
+        tio.c_cflag |= BOTHER | (BOTHER << IBSHIFT);
+        tio.c_ispeed = bps;
+        tio.c_ospeed = bps;
+        unsafe { ioctl::tcsets2(fd, &tio) }?;
+        Ok(())
+    }
+
+    /// Apply exclusive access and the initial DTR/RTS state
+    fn apply_line_state(&self) -> Result<()> {
+        use nix::libc::{c_int, TIOCM_DTR, TIOCM_RTS};
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        if self.exclusive {
+            unsafe { ioctl::tiocexcl(fd) }?;
+        }
+        for (line, state) in [(TIOCM_DTR, self.dtr), (TIOCM_RTS, self.rts)].iter() {
+            let bits: c_int = *line;
+            match state {
+                Some(true) => unsafe { ioctl::tiocmbis(fd, &bits) }?,
+                Some(false) => unsafe { ioctl::tiocmbic(fd, &bits) }?,
+                None => 0,
+            };
+        }
         Ok(())
     }
 
@@ -230,56 +765,436 @@ impl SerialPort {
     ///Calling this will set the rate immediately if
     ///the port is open. Otherwise it will be set once open
     ///is called.
-    fn set_baud(&mut self, baud: BaudRate) -> Result<()> {
+    pub fn set_baud(&mut self, baud: impl Into<Rate>) -> Result<()> {
         use nix::sys::termios::{cfsetispeed, cfsetospeed};
+        self.baud = baud.into();
         match self.fd {
-            None => {
-                self.baud = baud;
-                Ok(())
-            }
-            Some(mut fd) => {
-                self.baud = baud;
-                let mut settings = get_termios(&fd)?;
+            None => Ok(()),
+            Some(mut fd) => match self.baud {
+                Rate::Standard(baud) => {
+                    let mut settings = get_termios(&fd)?;
 
-                cfsetospeed(&mut settings, self.baud)?;
-                cfsetispeed(&mut settings, self.baud)?;
-                set_termios(&mut fd, &settings)?;
-                Ok(())
+                    cfsetospeed(&mut settings, baud)?;
+                    cfsetispeed(&mut settings, baud)?;
+                    set_termios(&mut fd, &settings)?;
+                    Ok(())
+                }
+                Rate::Custom(_) => self.apply_custom_rate(),
+            },
+        }
+    }
+    #[cfg(feature = "async")]
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    fn modem_bits(&self) -> Result<nix::libc::c_int> {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        let mut bits = 0;
+        unsafe { ioctl::tiocmget(fd, &mut bits) }?;
+        Ok(bits)
+    }
+
+    fn set_modem_bit(&self, bit: nix::libc::c_int, on: bool) -> Result<()> {
+        let mut bits = self.modem_bits()?;
+        if on {
+            bits |= bit;
+        } else {
+            bits &= !bit;
+        }
+        if let Some(fd) = self.fd {
+            unsafe { ioctl::tiocmset(fd, &bits) }?;
+        }
+        Ok(())
+    }
+
+    /// Read the state of the modem control lines (TIOCMGET).
+    pub fn modem_status(&self) -> Result<ModemStatus> {
+        Ok(ModemStatus::from_bits(self.modem_bits()?))
+    }
+
+    /// Assert or clear Data Terminal Ready.
+    pub fn set_dtr(&self, on: bool) -> Result<()> {
+        self.set_modem_bit(nix::libc::TIOCM_DTR, on)
+    }
+
+    /// Assert or clear Request To Send.
+    pub fn set_rts(&self, on: bool) -> Result<()> {
+        self.set_modem_bit(nix::libc::TIOCM_RTS, on)
+    }
+
+    /// Clear DTR for the given time then assert it again.
+    ///
+    /// This is how the Arduino IDE resets a board: the falling edge
+    /// is coupled through a capacitor to the reset pin.
+    pub fn pulse_dtr(&self, duration: Duration) -> Result<()> {
+        self.set_dtr(false)?;
+        std::thread::sleep(duration);
+        self.set_dtr(true)
+    }
+
+    /// Hold the line in the break condition for the given time.
+    pub fn send_break(&self, duration: Duration) -> Result<()> {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        unsafe { ioctl::tiocsbrk(fd) }?;
+        std::thread::sleep(duration);
+        unsafe { ioctl::tioccbrk(fd) }?;
+        Ok(())
+    }
+
+    /// Block until one of the given lines changes state (TIOCMIWAIT),
+    /// returning the new state of the lines.
+    ///
+    /// Not every driver supports this; ptys and some USB adapters
+    /// fail with ENOTTY or EINVAL.
+    pub fn wait_modem_change(&self, lines: &[ModemLine]) -> Result<ModemStatus> {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        let mask = lines.iter().fold(0, |mask, line| mask | line.bit());
+        loop {
+            match unsafe { ioctl::tiocmiwait(fd, mask) } {
+                Ok(_) => return self.modem_status(),
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
             }
         }
     }
+
+    /// Switch an open port over to non-blocking reads for use with an
+    /// event loop. VMIN is set to one so an empty read fails with EAGAIN
+    /// rather than returning zero bytes, leaving zero bytes to mean the
+    /// device hung up.
+    #[cfg(feature = "async")]
+    pub(crate) fn set_nonblocking(&mut self) -> Result<()> {
+        use nix::fcntl::fcntl;
+        use nix::fcntl::FcntlArg::F_SETFL;
+        use nix::sys::termios::SpecialCharacterIndices;
+        let mut fd = match self.fd {
+            Some(fd) => fd,
+            None => return Err(Error::new(ErrorKind::PortClosed, "Serial port is not open")),
+        };
+        let mut settings = get_termios(&fd)?;
+        settings.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        settings.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        set_termios(&mut fd, &settings)?;
+        fcntl(fd, F_SETFL(OFlag::O_NONBLOCK))?;
+        Ok(())
+    }
+
+    /// VTIME for the current timeout. Only used as the interbyte
+    /// timer when VMIN is set.
+    fn vtime(&self) -> u8 {
+        if self.vmin == 0 {
+            return 0;
+        }
+        //VTIME's units are deciseconds and it is a u8 so the
+        //maximum is 25.5 seconds
+        let deciseconds = self.timeout.as_millis().div_ceil(100);
+        std::cmp::min(deciseconds, 255) as u8
+    }
+
     /// Set the timeout
     ///
     /// Calling this will set the timeout immediately if
     /// the port is open. Otherwise, it will be set once
     /// open is called.
+    #[allow(dead_code)]
     fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
         use nix::sys::termios::SpecialCharacterIndices;
-        //TODO:
-        //Same as set_baud
+        self.timeout = timeout;
         match self.fd {
-            None => {
-                self.timeout = timeout;
-                Ok(())
-            }
+            None => Ok(()),
             Some(mut fd) => {
-                self.timeout = timeout;
                 let mut settings = get_termios(&fd)?;
-                //VTIME's units are deciseconds
-                let vtime = {
-                    let sec = self.timeout.as_secs_f32();
-                    if sec > 25.5 {
-                        255
-                    } else {
-                        // should give me seconds
-                        // in deciseconds
-                        (sec * 10.0) as u8
-                    }
-                };
-                settings.control_chars[SpecialCharacterIndices::VTIME as usize] = vtime;
+                settings.control_chars[SpecialCharacterIndices::VTIME as usize] = self.vtime();
                 set_termios(&mut fd, &settings)?;
                 Ok(())
             }
         }
     }
 }
+
+/// Wait for the port to become readable (or hang up). Returns false
+/// if the deadline passed first.
+pub(crate) fn wait_readable(fd: RawFd, deadline: Instant) -> Result<bool> {
+    wait_for(fd, nix::poll::PollFlags::POLLIN, deadline)
+}
+
+/// Wait for a socket to become writable, e.g. for a non-blocking
+/// connect to finish. Returns false if the deadline passed first.
+pub(crate) fn wait_writable(fd: RawFd, deadline: Instant) -> Result<bool> {
+    wait_for(fd, nix::poll::PollFlags::POLLOUT, deadline)
+}
+
+fn wait_for(fd: RawFd, events: nix::poll::PollFlags, deadline: Instant) -> Result<bool> {
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd};
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // Round up so short timeouts don't turn into a non-blocking poll
+        let ms = std::cmp::min(remaining.as_micros().div_ceil(1000), i32::MAX as u128) as i32;
+        let mut fds = [PollFd::new(fd, events)];
+        match poll(&mut fds, ms) {
+            Ok(0) if Instant::now() >= deadline => return Ok(false),
+            Ok(0) => continue,
+            Ok(_) => return Ok(true),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// A read of zero bytes is either a timeout or a hang up. Tell
+/// them apart by asking poll.
+pub(crate) fn hung_up(fd: RawFd) -> bool {
+    use nix::poll::{poll, PollFd, PollFlags};
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    match poll(&mut fds, 0) {
+        Ok(n) if n > 0 => fds[0]
+            .revents()
+            .map(|r| r.intersects(PollFlags::POLLHUP | PollFlags::POLLERR))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn open_pty() -> (nix::pty::PtyMaster, String) {
+    use nix::pty::{grantpt, posix_openpt, ptsname_r, unlockpt};
+    let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+    grantpt(&master).unwrap();
+    unlockpt(&master).unwrap();
+    let path = ptsname_r(&master).unwrap();
+    (master, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::termios::{ControlFlags, InputFlags, SpecialCharacterIndices};
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    // ptys force 8 data bits and no parity so the settings are
+    // checked before they are applied.
+    #[test]
+    fn test_builder_line_settings() {
+        let (master, _path) = open_pty();
+        let port = SerialPortBuilder::new("/dev/null")
+            .data_bits(DataBits::Seven)
+            .parity(Parity::Even)
+            .stop_bits(StopBits::Two)
+            .flow_control(FlowControl::Software)
+            .vmin(4)
+            .build();
+        let mut settings = get_termios(&master.as_raw_fd()).unwrap();
+        port.configure(&mut settings).unwrap();
+        let cflags = settings.control_flags;
+        assert_eq!(ControlFlags::CS7, cflags & ControlFlags::CSIZE);
+        assert!(cflags.contains(ControlFlags::PARENB | ControlFlags::CSTOPB));
+        assert!(!cflags.contains(ControlFlags::PARODD));
+        assert!(settings
+            .input_flags
+            .contains(InputFlags::IXON | InputFlags::IXOFF | InputFlags::INPCK));
+        assert_eq!(
+            4,
+            settings.control_chars[SpecialCharacterIndices::VMIN as usize]
+        );
+    }
+
+    // SerialPort::new keeps opening ports as 8N1
+    #[test]
+    fn test_default_line_settings() {
+        let (_master, path) = open_pty();
+        let mut port = SerialPort::new(&path, BaudRate::B9600, Duration::from_secs(0));
+        port.exclusive = false;
+        port.open().unwrap();
+        let settings = get_termios(&port.fd.unwrap()).unwrap();
+        let cflags = settings.control_flags;
+        assert_eq!(ControlFlags::CS8, cflags & ControlFlags::CSIZE);
+        assert!(!cflags.intersects(ControlFlags::PARENB | ControlFlags::CSTOPB));
+        assert!(!settings
+            .input_flags
+            .intersects(InputFlags::IXON | InputFlags::IXOFF));
+    }
+
+    #[test]
+    fn test_rate_conversion() {
+        assert_eq!(Rate::Standard(BaudRate::B38400), Rate::from(38400));
+        assert_eq!(Rate::Standard(BaudRate::B57600), "57600".parse().unwrap());
+        assert_eq!(Rate::Custom(250000), Rate::from(250000));
+        for (bps, _) in STANDARD_RATES.iter() {
+            assert_eq!(*bps, Rate::from(*bps).bps());
+        }
+        assert!("0".parse::<Rate>().is_err());
+        assert!("fast".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn test_modem_lines() {
+        use nix::libc::{TIOCM_CAR, TIOCM_DTR};
+        let status = ModemStatus::from_bits(TIOCM_DTR | TIOCM_CAR);
+        assert_eq!(
+            ModemStatus {
+                dtr: true,
+                dcd: true,
+                ..ModemStatus::default()
+            },
+            status
+        );
+
+        let port = SerialPort::new("/dev/null", BaudRate::B9600, Duration::from_secs(0));
+        assert!(matches!(
+            port.modem_status().unwrap_err().kind(),
+            ErrorKind::PortClosed
+        ));
+
+        // Ptys have no modem lines but do accept a break
+        let (master, path) = open_pty();
+        let port = SerialPortBuilder::new(&path)
+            .exclusive(false)
+            .open()
+            .unwrap();
+        assert!(port.set_dtr(true).is_err());
+        assert!(port.wait_modem_change(&[ModemLine::Dcd]).is_err());
+        port.send_break(Duration::from_millis(10)).unwrap();
+        drop(master);
+    }
+
+    #[test]
+    fn test_restore_settings() {
+        use nix::sys::termios::{tcgetattr, LocalFlags};
+        let (master, path) = open_pty();
+        let cooked = |master: &nix::pty::PtyMaster| {
+            tcgetattr(master.as_raw_fd())
+                .unwrap()
+                .local_flags
+                .contains(LocalFlags::ICANON)
+        };
+        assert!(cooked(&master));
+        let mut port = SerialPortBuilder::new(&path)
+            .exclusive(false)
+            .open()
+            .unwrap();
+        assert!(!cooked(&master));
+        port.close().unwrap();
+        assert!(cooked(&master));
+
+        // Dropping an open port restores them too
+        let port = SerialPortBuilder::new(&path)
+            .exclusive(false)
+            .open()
+            .unwrap();
+        assert!(!cooked(&master));
+        drop(port);
+        assert!(cooked(&master));
+    }
+
+    #[test]
+    fn test_lock() {
+        let (master, path) = open_pty();
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_serial_lock_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+        let builder = || SerialPortBuilder::new(&path).lock_dir(&dir);
+
+        let mut first = builder().open().unwrap();
+        let e = builder().open().err().unwrap();
+        assert!(matches!(e.kind(), ErrorKind::Busy(Some(pid)) if *pid == std::process::id()));
+
+        // Released on close
+        first.close().unwrap();
+        let second = builder().open().unwrap();
+        drop(second);
+        let mut shared = builder().exclusive(false).open().unwrap();
+        shared.close().unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+        drop(master);
+    }
+
+    // Closing the station end of a pty looks just like an unplug
+    #[test]
+    fn test_disconnected() {
+        let (master, path) = open_pty();
+        let mut port = SerialPortBuilder::new(&path)
+            .timeout(Duration::from_millis(100))
+            .exclusive(false)
+            .open()
+            .unwrap();
+        let mut buf = [0; 1];
+        assert!(matches!(
+            port.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::Timeout
+        ));
+        drop(master);
+        assert!(matches!(
+            port.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::Disconnected
+        ));
+        port.close().unwrap();
+        assert!(matches!(
+            port.close().unwrap_err().kind(),
+            ErrorKind::PortClosed
+        ));
+    }
+
+    // Timeouts are no longer rounded to tenths of a second
+    #[test]
+    fn test_read_deadlines() {
+        let (mut master, path) = open_pty();
+        let port = SerialPortBuilder::new(&path)
+            .timeout(Duration::from_millis(50))
+            .exclusive(false)
+            .open()
+            .unwrap();
+        let mut buf = [0; 4];
+
+        let start = Instant::now();
+        assert!(matches!(
+            port.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::Timeout
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(100));
+
+        master.write_all(&[1, 2]).unwrap();
+        let e = port
+            .read_exact_timeout(&mut buf, Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Timeout));
+
+        master.write_all(&[1, 2, 3, 4]).unwrap();
+        port.read_exact_timeout(&mut buf, Duration::from_millis(50))
+            .unwrap();
+        assert_eq!([1, 2, 3, 4], buf);
+    }
+
+    #[test]
+    fn test_custom_rate() {
+        use nix::libc::termios2;
+        let (_master, path) = open_pty();
+        let port = SerialPortBuilder::new(&path).baud(250000).open().unwrap();
+        let mut tio: termios2 = unsafe { std::mem::zeroed() };
+        unsafe { ioctl::tcgets2(port.fd.unwrap(), &mut tio) }.unwrap();
+        assert_eq!(250000, tio.c_ospeed);
+        assert_eq!(250000, tio.c_ispeed);
+    }
+
+    #[test]
+    fn test_parse_line_settings() {
+        assert_eq!(Ok(DataBits::Seven), "7".parse());
+        assert_eq!(Ok(Parity::Even), "even".parse());
+        assert_eq!(Ok(StopBits::Two), "2".parse());
+        assert_eq!(Ok(FlowControl::Hardware), "rtscts".parse());
+        assert!("9".parse::<DataBits>().is_err());
+        assert!("mark".parse::<Parity>().is_err());
+    }
+}
@@ -0,0 +1,664 @@
+//! Module turning the parsed config file into typed settings.
+//!
+//! `ControllerConfig::new` reads every setting the controller knows
+//! about up front, filling in defaults, so a bad or missing value stops
+//! the controller at startup instead of partway through a run. Errors
+//! point at the line of the config file the value came from. Keys the
+//! controller doesn't know about are logged as warnings, as they are
+//! usually typos.
+use crate::auth;
+use crate::channel;
+use crate::config::{self, Config, FromValue, Origin};
+use crate::crc;
+use crate::enumerate;
+use crate::lock;
+use crate::log;
+use crate::secret::{self, Secret};
+use crate::serialport::{DataBits, FlowControl, Parity, Rate, StopBits};
+use crate::transport;
+use std::ffi::OsString;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Rates tried in order when `serial.baud` is set to auto
+const AUTO_BAUD_RATES: [u32; 8] = [9600, 115200, 38400, 57600, 19200, 230400, 4800, 2400];
+
+/// Longest any duration setting can be, so deadlines worked out from
+/// them can't overflow
+const DURATION_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Devices whose rate can't be set from here, so can't be detected
+const FIXED_RATE_SCHEMES: [&str; 2] = [transport::SCHEME_TCP, transport::SCHEME_BLUETOOTH];
+
+/// Every key the controller reads
+const KNOWN_KEYS: [&str; 36] = [
+    "serial.device",
+    "serial.baud",
+    "serial.baud.candidates",
+    "serial.timeout",
+    "serial.data_bits",
+    "serial.parity",
+    "serial.stop_bits",
+    "serial.flow_control",
+    "serial.vmin",
+    "serial.exclusive",
+    "serial.lock_dir",
+    "serial.dtr",
+    "serial.rts",
+    "serial.carrier_detect",
+    "serial.match.usb_serial",
+    "serial.match.usb_vid",
+    "serial.match.usb_pid",
+    "serial.match.driver",
+    "log.file",
+    "log.level",
+    "channel.crc",
+    "channel.auth.key",
+    "channel.frame_timeout",
+    "channel.window",
+    "channel.requests",
+    "supervisor.backoff.initial",
+    "supervisor.backoff.max",
+    "supervisor.state_file",
+    "station.reset_after",
+    "station.poll_interval",
+    "db.host",
+    "db.port",
+    "db.api.key",
+    "db.api.key_file",
+    "db.api.endpoint",
+    "config.watch",
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// A required setting is not set
+    Missing,
+    /// A setting has a value of the wrong type or out of range
+    Invalid,
+    /// A secret is kept somewhere others can read it
+    Insecure,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    /// Key the error is about
+    key: String,
+    /// Where the key was set
+    origin: Option<Origin>,
+    description: String,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+    /// Line of the config file the key was set on
+    pub fn line(&self) -> Option<usize> {
+        match self.origin {
+            Some(Origin::File { line, .. }) => Some(line),
+            _ => None,
+        }
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match &self.origin {
+            Some(o) => write!(fmt, "config {}: {}", o, self.description),
+            None => write!(fmt, "config: {}", self.description),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+pub struct SerialConfig {
+    /// Device path or transport URL. Can be left out when the
+    /// device is found with the match criteria.
+    pub device: Option<String>,
+    /// None to detect the rate
+    pub baud: Option<Rate>,
+    /// Rates tried when detecting, in order
+    pub baud_candidates: Vec<Rate>,
+    pub timeout: Duration,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub vmin: u8,
+    pub exclusive: bool,
+    pub lock_dir: String,
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    pub carrier_detect: bool,
+    pub criteria: enumerate::Match,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub file: Option<String>,
+    pub level: log::Level,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    pub crc: crc::Algorithm,
+    pub auth_key: Option<Secret<Vec<u8>>>,
+    pub frame_timeout: Duration,
+    pub window: u8,
+    /// False for stations only answering commands sent as data frames
+    pub requests: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    pub backoff_initial: Duration,
+    pub backoff_max: Duration,
+    pub state_file: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StationConfig {
+    /// Unanswered requests in a row before the station is reset,
+    /// zero for never
+    pub reset_after: u32,
+    /// Time spent listening for events between readings
+    pub poll_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    pub host: String,
+    pub port: u16,
+    pub api_key: Secret<String>,
+    pub api_endpoint: String,
+}
+
+/// All of the controller's settings
+#[derive(Debug, Clone)]
+pub struct ControllerConfig {
+    pub serial: SerialConfig,
+    pub log: LogConfig,
+    pub channel: ChannelConfig,
+    pub supervisor: SupervisorConfig,
+    pub station: StationConfig,
+    pub db: DbConfig,
+    /// Reload when the config file changes, not only on SIGHUP
+    pub watch: bool,
+}
+
+/// Parse a log level name
+pub fn parse_level(s: &str) -> std::result::Result<log::Level, String> {
+    match s.to_lowercase().as_str() {
+        "debug" => Ok(log::Level::Debug),
+        "info" => Ok(log::Level::Info),
+        "warning" => Ok(log::Level::Warning),
+        "error" => Ok(log::Level::Error),
+        "fatal" => Ok(log::Level::Fatal),
+        "off" => Ok(log::Level::Off),
+        _ => Err(format!("Not an available log level: {}", s)),
+    }
+}
+
+/// Reads typed values out of a Config, keeping track of where they
+/// came from for errors.
+struct Reader<'a> {
+    config: &'a Config,
+    /// Directory systemd credentials are found in
+    credentials: Option<OsString>,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, kind: ErrorKind, key: &str, description: String) -> Error {
+        Error {
+            kind,
+            key: key.to_string(),
+            origin: self.config.origin(key).cloned(),
+            description,
+        }
+    }
+
+    fn string(&self, key: &str) -> Option<String> {
+        self.config.get(key).cloned()
+    }
+
+    fn required(&self, key: &str) -> Result<String> {
+        match self.config.get(key) {
+            Some(v) => Ok(v.clone()),
+            None => Err(self.error(
+                ErrorKind::Missing,
+                key,
+                format!("{} is required but not set", key),
+            )),
+        }
+    }
+
+    /// Parse a value with the given function
+    fn with<T, E: fmt::Display>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> std::result::Result<T, E>,
+    ) -> Result<Option<T>> {
+        match self.config.get(key) {
+            Some(v) => match parse(v.trim()) {
+                Ok(t) => Ok(Some(t)),
+                Err(e) => Err(self.error(
+                    ErrorKind::Invalid,
+                    key,
+                    format!("{}: invalid value '{}': {}", key, v, e),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Parse a secret value with the given function. Unlike `with`
+    /// the value is left out of errors.
+    fn secret_with<T, E: fmt::Display>(
+        &self,
+        key: &str,
+        parse: impl Fn(&str) -> std::result::Result<T, E>,
+    ) -> Result<Option<Secret<T>>> {
+        match self.config.get(key) {
+            Some(v) => match parse(v.trim()) {
+                Ok(t) => Ok(Some(Secret::new(t))),
+                Err(e) => Err(self.error(ErrorKind::Invalid, key, format!("{}: {}", key, e))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Read a secret from the file named by key
+    fn secret_file(&self, key: &str, path: &str) -> Result<Secret<String>> {
+        secret::read_file(&secret::resolve(path, self.credentials.as_deref())).map_err(|e| {
+            let kind = match e.kind() {
+                secret::ErrorKind::WorldReadable => ErrorKind::Insecure,
+                secret::ErrorKind::Io(_) => ErrorKind::Invalid,
+            };
+            self.error(kind, key, format!("{}: {}", key, e))
+        })
+    }
+
+    fn get<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.with(key, |v| v.parse::<T>())
+    }
+
+    fn require<T>(&self, key: &str) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.required(key)?;
+        Ok(self.get(key)?.unwrap())
+    }
+
+    fn get_or<T>(&self, key: &str, default: T) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// `true`/`false`, `yes`/`no` or `on`/`off`
+    fn flag(&self, key: &str) -> Result<Option<bool>> {
+        self.with(key, bool::from_value)
+    }
+
+    /// A duration where a bare number is in seconds
+    fn secs(&self, key: &str, default: Duration) -> Result<Duration> {
+        self.duration(key, Duration::from_secs(1), default)
+    }
+
+    /// A duration where a bare number is in milliseconds
+    fn millis(&self, key: &str, default: Duration) -> Result<Duration> {
+        self.duration(key, Duration::from_millis(1), default)
+    }
+
+    fn duration(&self, key: &str, unit: Duration, default: Duration) -> Result<Duration> {
+        let parse = |v: &str| match config::parse_duration(v, unit)? {
+            d if d > DURATION_MAX => Err("longer than a day".to_string()),
+            d => Ok(d),
+        };
+        Ok(self.with(key, parse)?.unwrap_or(default))
+    }
+}
+
+impl ControllerConfig {
+    /// Read and check all of the settings in config, logging a
+    /// warning for every key that isn't used.
+    pub fn new(config: &Config) -> Result<ControllerConfig> {
+        let settings = ControllerConfig::check(config)?;
+        for key in unknown_keys(config) {
+            let msg = format!("Unknown setting {} is ignored", key);
+            match config.origin(key) {
+                Some(o) => log::warn(&format!("config {}: {}", o, msg)),
+                None => log::warn(&format!("config: {}", msg)),
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Read and check all of the settings in config without logging
+    /// anything.
+    pub fn check(config: &Config) -> Result<ControllerConfig> {
+        ControllerConfig::read(config, secret::credentials_dir())
+    }
+
+    fn read(config: &Config, credentials: Option<OsString>) -> Result<ControllerConfig> {
+        let r = Reader {
+            config,
+            credentials,
+        };
+        Ok(ControllerConfig {
+            serial: read_serial(&r)?,
+            log: LogConfig {
+                file: r.string("log.file"),
+                level: r
+                    .with("log.level", parse_level)?
+                    .unwrap_or(log::Level::Debug),
+            },
+            channel: ChannelConfig {
+                crc: r.get_or("channel.crc", crc::Algorithm::default())?,
+                auth_key: r.secret_with("channel.auth.key", auth::parse_key)?,
+                frame_timeout: r.millis("channel.frame_timeout", channel::FRAME_TIMEOUT_DEFAULT)?,
+                window: r
+                    .with("channel.window", |v| match v.parse::<u8>() {
+                        Ok(0) => Err("the window can't be empty".to_string()),
+                        Ok(n) => Ok(n),
+                        Err(e) => Err(e.to_string()),
+                    })?
+                    .unwrap_or(channel::WINDOW_SIZE_DEFAULT),
+                requests: r.flag("channel.requests")?.unwrap_or(true),
+            },
+            supervisor: read_supervisor(&r)?,
+            station: StationConfig {
+                reset_after: r.get_or("station.reset_after", 0)?,
+                poll_interval: r.secs("station.poll_interval", Duration::from_secs(2))?,
+            },
+            db: DbConfig {
+                host: r.required("db.host")?,
+                port: r.require("db.port")?,
+                api_key: read_api_key(&r)?,
+                api_endpoint: r.required("db.api.endpoint")?,
+            },
+            watch: r.flag("config.watch")?.unwrap_or(false),
+        })
+    }
+}
+
+fn read_serial(r: &Reader) -> Result<SerialConfig> {
+    // No rate means the rate is to be detected once the port is open
+    let baud = match r.required("serial.baud")?.trim() {
+        "auto" => None,
+        _ => r.get("serial.baud")?,
+    };
+    let baud_candidates = r
+        .with("serial.baud.candidates", |list| {
+            list.split(',')
+                .map(|rate| rate.trim().parse())
+                .collect::<std::result::Result<Vec<Rate>, _>>()
+        })?
+        .unwrap_or_else(|| AUTO_BAUD_RATES.iter().map(|r| (*r).into()).collect());
+
+    let criteria = enumerate::Match {
+        usb_vid: r.with("serial.match.usb_vid", enumerate::parse_usb_id)?,
+        usb_pid: r.with("serial.match.usb_pid", enumerate::parse_usb_id)?,
+        usb_serial: r.string("serial.match.usb_serial"),
+        driver: r.string("serial.match.driver"),
+    };
+    let device = r.string("serial.device");
+    if criteria.is_empty() && device.is_none() {
+        return Err(r.error(
+            ErrorKind::Missing,
+            "serial.device",
+            "serial.device is required unless serial.match.* is set".to_string(),
+        ));
+    }
+    let fixed_rate = FIXED_RATE_SCHEMES
+        .iter()
+        .find(|scheme| device.as_deref().is_some_and(|d| d.starts_with(*scheme)));
+    if let (None, Some(scheme)) = (&baud, fixed_rate) {
+        return Err(r.error(
+            ErrorKind::Invalid,
+            "serial.baud",
+            format!(
+                "serial.baud can't be auto for {} devices, their rate is fixed",
+                scheme
+            ),
+        ));
+    }
+
+    Ok(SerialConfig {
+        device,
+        baud,
+        baud_candidates,
+        timeout: r.secs("serial.timeout", Duration::from_secs(0))?,
+        data_bits: r.get_or("serial.data_bits", DataBits::Eight)?,
+        parity: r.get_or("serial.parity", Parity::None)?,
+        stop_bits: r.get_or("serial.stop_bits", StopBits::One)?,
+        flow_control: r.get_or("serial.flow_control", FlowControl::None)?,
+        vmin: r.get_or("serial.vmin", 0)?,
+        exclusive: r.flag("serial.exclusive")?.unwrap_or(true),
+        lock_dir: r
+            .string("serial.lock_dir")
+            .unwrap_or_else(|| lock::LOCK_DIR_DEFAULT.to_string()),
+        dtr: r.flag("serial.dtr")?,
+        rts: r.flag("serial.rts")?,
+        carrier_detect: r.flag("serial.carrier_detect")?.unwrap_or(false),
+        criteria,
+    })
+}
+
+fn read_supervisor(r: &Reader) -> Result<SupervisorConfig> {
+    let backoff_initial = r.secs("supervisor.backoff.initial", Duration::from_secs(1))?;
+    let backoff_max = r.secs("supervisor.backoff.max", Duration::from_secs(60))?;
+    if backoff_initial > backoff_max {
+        return Err(r.error(
+            ErrorKind::Invalid,
+            "supervisor.backoff.initial",
+            "supervisor.backoff.initial can't be longer than supervisor.backoff.max".to_string(),
+        ));
+    }
+    Ok(SupervisorConfig {
+        backoff_initial,
+        backoff_max,
+        state_file: r.string("supervisor.state_file"),
+    })
+}
+
+/// The InfluxDB token, from the config, a file or the systemd
+/// credential named `db.api.key`
+fn read_api_key(r: &Reader) -> Result<Secret<String>> {
+    const KEY: &str = "db.api.key";
+    const KEY_FILE: &str = "db.api.key_file";
+    match (r.string(KEY), r.string(KEY_FILE)) {
+        (Some(_), Some(_)) => Err(r.error(
+            ErrorKind::Invalid,
+            KEY_FILE,
+            format!("{} and {} can't both be set", KEY, KEY_FILE),
+        )),
+        (Some(key), None) => Ok(Secret::new(key)),
+        (None, Some(path)) => r.secret_file(KEY_FILE, path.trim()),
+        (None, None) => match secret::credential(KEY, r.credentials.as_deref()) {
+            Some(path) => r.secret_file(KEY, &path.to_string_lossy()),
+            None => Err(r.error(
+                ErrorKind::Missing,
+                KEY,
+                format!("{} or {} is required but not set", KEY, KEY_FILE),
+            )),
+        },
+    }
+}
+
+/// Keys set in config that the controller doesn't read
+pub fn unknown_keys(config: &Config) -> Vec<&str> {
+    let mut keys: Vec<&str> = config
+        .keys()
+        .map(|k| k.as_str())
+        .filter(|k| !KNOWN_KEYS.contains(k))
+        .collect();
+    keys.sort_by_key(|k| config.line(k));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const MINIMAL: &str = "serial.device=/dev/ttyUSB0
+serial.baud=115200
+db.host=localhost
+db.port=8086
+db.api.key=token
+db.api.endpoint=/api/v2/write
+";
+
+    #[test]
+    fn test_defaults() {
+        let settings = ControllerConfig::new(&Config::parse(MINIMAL)).unwrap();
+        assert_eq!(Some("/dev/ttyUSB0".to_string()), settings.serial.device);
+        assert_eq!(Some(Rate::from(115200)), settings.serial.baud);
+        assert_eq!(Parity::None, settings.serial.parity);
+        assert!(settings.serial.exclusive);
+        assert_eq!(Duration::from_secs(1), settings.channel.frame_timeout);
+        assert_eq!(Duration::from_secs(60), settings.supervisor.backoff_max);
+        assert_eq!(8086, settings.db.port);
+        assert_eq!(0, settings.station.reset_after);
+
+        let config = Config::parse(&format!(
+            "{}serial.timeout=1500ms\nchannel.frame_timeout=2s\nsupervisor.backoff.max=5\n",
+            MINIMAL
+        ));
+        let settings = ControllerConfig::new(&config).unwrap();
+        assert_eq!(Duration::from_millis(1500), settings.serial.timeout);
+        assert_eq!(Duration::from_secs(2), settings.channel.frame_timeout);
+        assert_eq!(Duration::from_secs(5), settings.supervisor.backoff_max);
+    }
+
+    #[test]
+    fn test_errors() {
+        let config = Config::parse(&format!("{}serial.parity=sometimes\n", MINIMAL));
+        let e = ControllerConfig::new(&config).unwrap_err();
+        assert_eq!(ErrorKind::Invalid, *e.kind());
+        assert_eq!("serial.parity", e.key());
+        assert_eq!(Some(7), e.line());
+        assert!(e.to_string().starts_with("config line 7: serial.parity"));
+
+        let config = Config::parse(&MINIMAL.replace("db.host=localhost\n", ""));
+        let e = ControllerConfig::new(&config).unwrap_err();
+        assert_eq!(ErrorKind::Missing, *e.kind());
+        assert_eq!("db.host", e.key());
+        assert_eq!(None, e.line());
+
+        let config = Config::parse(&MINIMAL.replace("serial.device=/dev/ttyUSB0\n", ""));
+        assert_eq!(
+            "serial.device",
+            ControllerConfig::new(&config).unwrap_err().key()
+        );
+        let config = Config::parse(&MINIMAL.replace("8086", "80860"));
+        assert_eq!("db.port", ControllerConfig::new(&config).unwrap_err().key());
+        for key in [
+            "station.poll_interval",
+            "channel.frame_timeout",
+            "supervisor.backoff.max",
+        ] {
+            let config = Config::parse(&format!("{}{}=1e19\n", MINIMAL, key));
+            let e = ControllerConfig::new(&config).unwrap_err();
+            assert_eq!((key, ErrorKind::Invalid), (e.key(), *e.kind()));
+        }
+        let config = Config::parse(&format!("{}channel.frame_timeout=1d\n", MINIMAL));
+        assert!(ControllerConfig::new(&config).is_ok());
+        let config = Config::parse(&format!("{}supervisor.backoff.initial=2m\n", MINIMAL));
+        assert_eq!(
+            "supervisor.backoff.initial",
+            ControllerConfig::new(&config).unwrap_err().key()
+        );
+
+        let config = Config::parse(
+            &MINIMAL
+                .replace("/dev/ttyUSB0", "tcp://ser2net:3000")
+                .replace("115200", "auto"),
+        );
+        let e = ControllerConfig::new(&config).unwrap_err();
+        assert_eq!(("serial.baud", ErrorKind::Invalid), (e.key(), *e.kind()));
+        let config = Config::parse(
+            &MINIMAL
+                .replace("/dev/ttyUSB0", "bt://AA:BB:CC:DD:EE:FF/1")
+                .replace("115200", "auto"),
+        );
+        assert_eq!(
+            "serial.baud",
+            ControllerConfig::new(&config).unwrap_err().key()
+        );
+    }
+
+    #[test]
+    fn test_secrets() {
+        let dir = std::env::temp_dir().join(format!("tw_ctrl_settings_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("influx");
+        std::fs::write(&path, "from-file\n").unwrap();
+        let without_key = MINIMAL.replace("db.api.key=token\n", "");
+        // The credentials directory is passed in so whatever the tests
+        // run under doesn't matter
+        let read = |config: &Config| ControllerConfig::read(config, None);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let config = Config::parse(&format!(
+            "{}db.api.key_file={}\n",
+            without_key,
+            path.display()
+        ));
+        let settings = read(&config).unwrap();
+        assert_eq!("from-file", settings.db.api_key.expose());
+        assert!(!format!("{:?}", settings).contains("from-file"));
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let e = read(&config).unwrap_err();
+        assert_eq!(ErrorKind::Insecure, *e.kind());
+
+        let config = Config::parse(&format!("{}db.api.key_file={}\n", MINIMAL, path.display()));
+        assert_eq!(ErrorKind::Invalid, *read(&config).unwrap_err().kind());
+        let config = Config::parse(&without_key);
+        assert_eq!(ErrorKind::Missing, *read(&config).unwrap_err().kind());
+
+        // A systemd credential named after the key
+        let credential = dir.join("db.api.key");
+        std::fs::write(&credential, "from-credential\n").unwrap();
+        std::fs::set_permissions(&credential, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let settings = ControllerConfig::read(&config, Some(dir.clone().into_os_string())).unwrap();
+        assert_eq!("from-credential", settings.db.api_key.expose());
+
+        let config = Config::parse(&format!("{}channel.auth.key=abcdefgh\n", MINIMAL));
+        let e = read(&config).unwrap_err();
+        assert!(!e.to_string().contains("abcdefgh"), "{}", e);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let config = Config::parse(&format!("serail.baud=9600\n{}db.prot=1\n", MINIMAL));
+        assert_eq!(vec!["serail.baud", "db.prot"], unknown_keys(&config));
+    }
+}
@@ -0,0 +1,228 @@
+//! This module keeps track of the connection to the station.
+//!
+//! When the device is unplugged or the Bluetooth link drops the controller
+//! closes the channel, waits and tries again. The wait doubles after every
+//! failed attempt, up to a maximum, and goes back to the initial wait once
+//! the station is reached again.
+//!
+//! Every state transition is logged. The current state can also be exported
+//! to a file so other tools (monitoring, status pages) can pick it up. The
+//! file uses the same key=value layout as the config file:
+//!
+//! state=connected
+//! since=2024-01-01T12:00:00+00:00
+//! reconnects=2
+//! reason=Heartbeat confirmed
+use crate::log;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+const BACKOFF_INITIAL_DEFAULT: Duration = Duration::from_secs(1);
+const BACKOFF_MAX_DEFAULT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    /// Opening the device and waiting on a heartbeat
+    Connecting,
+    /// Polling the station
+    Connected,
+    /// The connection was lost
+    Disconnected,
+    /// Waiting before the next attempt
+    Backoff,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+            State::Disconnected => "disconnected",
+            State::Backoff => "backoff",
+        })
+    }
+}
+
+/// Exponential backoff between connection attempts
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    pub fn initial(&self) -> Duration {
+        self.initial
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Return the wait before the next attempt and double it
+    /// for the one after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next.checked_mul(2).unwrap_or(self.max), self.max);
+        delay
+    }
+
+    /// Go back to the initial wait.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(BACKOFF_INITIAL_DEFAULT, BACKOFF_MAX_DEFAULT)
+    }
+}
+
+pub struct Supervisor {
+    state: State,
+    backoff: Backoff,
+    reconnects: u32,
+    connected_once: bool,
+    state_file: Option<String>,
+}
+
+impl Supervisor {
+    pub fn new(backoff: Backoff) -> Supervisor {
+        Supervisor {
+            state: State::Disconnected,
+            backoff,
+            reconnects: 0,
+            connected_once: false,
+            state_file: None,
+        }
+    }
+
+    /// Export every state transition to the file at path.
+    pub fn set_state_file(&mut self, path: &str) {
+        self.state_file = Some(path.to_string());
+    }
+
+    /// Stop exporting state transitions.
+    pub fn clear_state_file(&mut self) {
+        self.state_file = None;
+    }
+
+    /// Replace the backoff, starting again from its initial delay.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Number of times the connection was re-established
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    /// Move to a new state, logging and exporting the transition.
+    pub fn transition(&mut self, state: State, reason: &str) {
+        let msg = format!("Connection {} -> {}: {}", self.state, state, reason);
+        match state {
+            State::Disconnected => log::warn(&msg),
+            _ => log::info(&msg),
+        }
+        self.state = state;
+        if let Err(e) = self.export(reason) {
+            log::error(&format!("Could not export connection state: {}", e));
+        }
+    }
+
+    /// The station was reached.
+    pub fn connected(&mut self) {
+        if self.connected_once {
+            self.reconnects += 1;
+        }
+        self.connected_once = true;
+        self.backoff.reset();
+        self.transition(State::Connected, "Heartbeat confirmed");
+    }
+
+    /// Enter backoff, returning how long to wait before the next attempt.
+    pub fn backoff(&mut self) -> Duration {
+        let delay = self.backoff.next_delay();
+        self.transition(
+            State::Backoff,
+            &format!("Retrying in {}s", delay.as_secs_f32()),
+        );
+        delay
+    }
+
+    fn export(&self, reason: &str) -> io::Result<()> {
+        let path = match &self.state_file {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let contents = format!(
+            "state={}\nsince={}\nreconnects={}\nreason={}\n",
+            self.state,
+            chrono::Local::now().to_rfc3339(),
+            self.reconnects,
+            reason.replace('\n', " ")
+        );
+        // Write then rename so readers never see a partial file
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 5, 5], delays);
+        backoff.reset();
+        assert_eq!(Duration::from_secs(1), backoff.next_delay());
+
+        // Doubling past what a Duration holds stops at the max
+        let mut backoff = Backoff::new(Duration::from_secs(u64::MAX / 2 + 1), Duration::MAX);
+        backoff.next_delay();
+        assert_eq!(Duration::MAX, backoff.next_delay());
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir()
+            .join(format!("tw_ctrl_state_{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut supervisor = Supervisor::new(Backoff::default());
+        supervisor.set_state_file(&path);
+
+        supervisor.connected();
+        supervisor.transition(State::Disconnected, "Input/output error");
+        assert_eq!(Duration::from_secs(1), supervisor.backoff());
+        supervisor.transition(State::Connecting, "Reopening");
+        supervisor.connected();
+        assert_eq!(State::Connected, supervisor.state());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(contents.starts_with("state=connected\n"));
+        assert!(contents.contains("reconnects=1\n"));
+        assert!(contents.contains("reason=Heartbeat confirmed\n"));
+    }
+}
@@ -0,0 +1,224 @@
+//! Module providing a raw TCP transport, for stations attached to a
+//! terminal server such as ser2net.
+//!
+//! Bytes are passed through untouched. The line settings (baud rate,
+//! parity, ...) are whatever the terminal server has configured for
+//! the port; use RFC 2217 to set them from here.
+use crate::serialport::{Error, ErrorKind, Rate, Result};
+use crate::transport::{from_io, Transport};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TcpTransport {
+    path: String,
+    stream: Option<TcpStream>,
+}
+
+impl TcpTransport {
+    /// Create a transport to `tcp://host:port` (the scheme is optional)
+    pub fn new(path: &str) -> TcpTransport {
+        TcpTransport {
+            path: path.to_string(),
+            stream: None,
+        }
+    }
+
+    /// host:port part of the path
+    pub(crate) fn address(&self) -> &str {
+        let addr = self.path.split("://").last().unwrap_or("");
+        addr.trim_end_matches('/')
+    }
+
+    pub(crate) fn stream(&self) -> Result<&TcpStream> {
+        match &self.stream {
+            Some(s) => Ok(s),
+            None => Err(Error::new(ErrorKind::PortClosed, "Connection is not open")),
+        }
+    }
+
+    /// Connect to the first address the host resolves to that answers.
+    pub(crate) fn connect(&mut self) -> Result<()> {
+        let addrs = self
+            .address()
+            .to_socket_addrs()
+            .map_err(|e| Error::new(ErrorKind::Unknown, &format!("{}: {}", self.path, e)))?;
+        let mut last = Error::new(ErrorKind::Unknown, &format!("{}: no address", self.path));
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    // Frames are small, don't hold them back
+                    stream.set_nodelay(true).map_err(from_io)?;
+                    self.stream = Some(stream);
+                    return Ok(());
+                }
+                Err(e) => last = from_io(e),
+            }
+        }
+        Err(last)
+    }
+
+    /// Pass everything already recieved to f without waiting for more.
+    pub(crate) fn read_available(&self, mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        let mut stream = self.stream()?;
+        stream.set_nonblocking(true).map_err(from_io)?;
+        let mut buf = [0; 256];
+        let result = loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break Err(Error::new(ErrorKind::Disconnected, "Connection closed")),
+                Ok(n) => {
+                    if let Err(e) = f(&buf[..n]) {
+                        break Err(e);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(from_io(e)),
+            }
+        };
+        stream.set_nonblocking(false).map_err(from_io)?;
+        result
+    }
+}
+
+impl Transport for TcpTransport {
+    fn open(&mut self) -> Result<()> {
+        self.connect()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.stream.take() {
+            Some(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::PortClosed, "Connection is not open")),
+        }
+    }
+
+    fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize> {
+        let mut stream = self.stream()?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(Error::new(
+                ErrorKind::Timeout,
+                "Timeout reached. No bytes read",
+            ));
+        }
+        stream.set_read_timeout(Some(remaining)).map_err(from_io)?;
+        loop {
+            match stream.read(arr) {
+                Ok(0) => return Err(Error::new(ErrorKind::Disconnected, "Connection closed")),
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(from_io(e)),
+            }
+        }
+    }
+
+    fn write(&self, arr: &[u8]) -> Result<usize> {
+        let mut stream = self.stream()?;
+        stream.write_all(arr).map_err(from_io)?;
+        Ok(arr.len())
+    }
+
+    /// Only the input can be discarded, anything written is
+    /// already on its way.
+    fn flush(&self) -> Result<()> {
+        self.read_available(|_| Ok(()))
+    }
+
+    fn drain(&self) -> Result<()> {
+        let mut stream = self.stream()?;
+        stream.flush().map_err(from_io)
+    }
+
+    /// The rate is set on the terminal server, so can't be changed
+    fn set_baud(&mut self, _baud: Rate) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "The rate of a raw TCP port is set on the terminal server, use rfc2217:// to set it",
+        ))
+    }
+
+    fn set_path(&mut self, path: &str) {
+        self.path = path.to_string();
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Channel;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_read_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("tcp://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0; 3];
+            conn.read_exact(&mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+            // Hold the connection open past the client's deadline
+            std::thread::sleep(Duration::from_millis(150));
+        });
+
+        let mut transport = TcpTransport::new(&path);
+        assert_eq!(path.trim_start_matches("tcp://"), transport.address());
+        transport.open().unwrap();
+        assert_eq!(3, transport.write(&[1, 2, 3]).unwrap());
+        let e = transport.set_baud(Rate::from(9600)).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Unsupported));
+        let mut buf = [0; 3];
+        transport
+            .read_exact_until_deadline(&mut buf, Instant::now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!([1, 2, 3], buf);
+
+        let e = transport
+            .read_until_deadline(&mut buf, Instant::now() + Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Timeout));
+
+        server.join().unwrap();
+        let e = transport
+            .read_until_deadline(&mut buf, Instant::now() + Duration::from_secs(1))
+            .unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Disconnected));
+        transport.close().unwrap();
+    }
+
+    // A station that echoes everything answers heartbeats
+    #[test]
+    fn test_channel_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = format!("tcp://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0; 64];
+            while let Ok(n) = conn.read(&mut buf) {
+                if n == 0 || conn.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut channel = Channel::new(TcpTransport::new(&path), 3);
+        channel.set_timeout(Duration::from_millis(200));
+        channel.open().unwrap();
+        channel.close().unwrap();
+    }
+}
@@ -1,14 +1,16 @@
 //! Module providing some convience functions for using termios
-use crate::serialport::{Error, Result};
+use crate::serialport::Result;
 
-use nix::sys::termios::{self, tcgetattr, tcsetattr, SetArg, Termios};
+use nix::libc;
+use nix::sys::termios::{
+    cfgetispeed, cfgetospeed, tcgetattr, tcsetattr, ControlFlags, SetArg, SpecialCharacterIndices,
+    Termios,
+};
+use std::fmt;
 use std::os::unix::io::RawFd;
 
 pub fn get_termios(fd: &RawFd) -> Result<Termios> {
-    let termios = match tcgetattr(*fd) {
-        Ok(t) => t,
-        Err(e) => return Err(e.into()),
-    };
+    let termios = tcgetattr(*fd)?;
 
     Ok(termios)
 }
@@ -19,3 +21,171 @@ pub fn set_termios(fd: &mut RawFd, termios: &Termios) -> Result<()> {
         Err(e) => Err(e.into()),
     }
 }
+
+/// Control characters shown when printing and diffing
+const CONTROL_CHARS: [(SpecialCharacterIndices, &str); 10] = [
+    (SpecialCharacterIndices::VINTR, "VINTR"),
+    (SpecialCharacterIndices::VQUIT, "VQUIT"),
+    (SpecialCharacterIndices::VERASE, "VERASE"),
+    (SpecialCharacterIndices::VKILL, "VKILL"),
+    (SpecialCharacterIndices::VEOF, "VEOF"),
+    (SpecialCharacterIndices::VSTART, "VSTART"),
+    (SpecialCharacterIndices::VSTOP, "VSTOP"),
+    (SpecialCharacterIndices::VSUSP, "VSUSP"),
+    (SpecialCharacterIndices::VMIN, "VMIN"),
+    (SpecialCharacterIndices::VTIME, "VTIME"),
+];
+
+/// Copy of the termios settings of a device at one point in time.
+///
+/// Kept as the raw libc struct so it can be held by a port shared
+/// between threads (nix's `Termios` is not `Sync`).
+#[derive(Copy, Clone)]
+pub struct Snapshot {
+    raw: libc::termios,
+}
+
+impl Snapshot {
+    /// Put the settings back on the device.
+    pub fn restore(&self, fd: RawFd) -> Result<()> {
+        let mut fd = fd;
+        set_termios(&mut fd, &self.termios())
+    }
+
+    pub fn termios(&self) -> Termios {
+        Termios::from(self.raw)
+    }
+
+    /// Changes needed to go from this snapshot to other, one
+    /// line per setting.
+    pub fn diff(&self, other: &Snapshot) -> Vec<String> {
+        let (a, b) = (self.termios(), other.termios());
+        // The speed bits live in cflag but are reported on their own
+        let speed_bits = ControlFlags::CBAUD | ControlFlags::CBAUDEX;
+        let (a_cflag, b_cflag) = (a.control_flags & !speed_bits, b.control_flags & !speed_bits);
+        let mut changes = Vec::new();
+        let speeds = |t: &Termios| (cfgetispeed(t), cfgetospeed(t));
+        if speeds(&a) != speeds(&b) {
+            changes.push(format!("speed: {} -> {}", speed_desc(&a), speed_desc(&b)));
+        }
+        macro_rules! flags {
+            ($name:expr, $a:expr, $b:expr) => {
+                let added = $b & !$a;
+                let removed = $a & !$b;
+                if !added.is_empty() || !removed.is_empty() {
+                    let mut change = format!("{}:", $name);
+                    if !added.is_empty() {
+                        change.push_str(&format!(" +({:?})", added));
+                    }
+                    if !removed.is_empty() {
+                        change.push_str(&format!(" -({:?})", removed));
+                    }
+                    changes.push(change);
+                }
+            };
+        }
+        flags!("iflag", a.input_flags, b.input_flags);
+        flags!("oflag", a.output_flags, b.output_flags);
+        flags!("cflag", a_cflag, b_cflag);
+        flags!("lflag", a.local_flags, b.local_flags);
+        for (index, name) in CONTROL_CHARS.iter() {
+            let (before, after) = (
+                a.control_chars[*index as usize],
+                b.control_chars[*index as usize],
+            );
+            if before != after {
+                changes.push(format!("{}: {} -> {}", name, before, after));
+            }
+        }
+        changes
+    }
+}
+
+impl From<&Termios> for Snapshot {
+    fn from(termios: &Termios) -> Snapshot {
+        // nix only syncs the public fields into the libc struct
+        // when handing it to a libc call, so copy them over here
+        let mut raw: libc::termios = termios.clone().into();
+        raw.c_iflag = termios.input_flags.bits();
+        raw.c_oflag = termios.output_flags.bits();
+        raw.c_cflag = termios.control_flags.bits();
+        raw.c_lflag = termios.local_flags.bits();
+        raw.c_cc = termios.control_chars;
+        Snapshot { raw }
+    }
+}
+
+fn speed_desc(termios: &Termios) -> String {
+    let (ispeed, ospeed) = (cfgetispeed(termios), cfgetospeed(termios));
+    if ispeed == ospeed {
+        format!("{:?}", ospeed)
+    } else {
+        format!("{:?} (in {:?})", ospeed, ispeed)
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let t = self.termios();
+        writeln!(fmt, "speed: {}", speed_desc(&t))?;
+        writeln!(fmt, "iflag: {:?}", t.input_flags)?;
+        writeln!(fmt, "oflag: {:?}", t.output_flags)?;
+        writeln!(fmt, "cflag: {:?}", t.control_flags)?;
+        writeln!(fmt, "lflag: {:?}", t.local_flags)?;
+        let chars: Vec<String> = CONTROL_CHARS
+            .iter()
+            .map(|(index, name)| format!("{}={}", name, t.control_chars[*index as usize]))
+            .collect();
+        write!(fmt, "cc: {}", chars.join(" "))
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, fmt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::termios::{cfmakeraw, cfsetspeed, BaudRate, LocalFlags};
+
+    #[test]
+    fn test_diff() {
+        let (master, path) = crate::serialport::open_pty();
+        let fd = nix::fcntl::open(
+            path.as_str(),
+            nix::fcntl::OFlag::O_RDWR | nix::fcntl::OFlag::O_NOCTTY,
+            nix::sys::stat::Mode::empty(),
+        )
+        .unwrap();
+        let before = Snapshot::from(&get_termios(&fd).unwrap());
+        assert!(before.diff(&before).is_empty());
+
+        let mut raw = before.termios();
+        cfmakeraw(&mut raw);
+        cfsetspeed(&mut raw, BaudRate::B115200).unwrap();
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 3;
+        let after = Snapshot::from(&raw);
+        let changes = after.diff(&before);
+        assert!(changes.iter().any(|c| c.starts_with("speed: B115200 -> ")));
+        assert!(changes
+            .iter()
+            .any(|c| c.starts_with("lflag: +(") && c.contains("ICANON")));
+        assert!(!changes.iter().any(|c| c.starts_with("cflag")));
+        assert!(changes.iter().any(|c| c.starts_with("VMIN: 3 -> ")));
+        assert!(!raw.local_flags.contains(LocalFlags::ICANON));
+
+        let printed = after.to_string();
+        assert!(printed.starts_with("speed: B115200\n"));
+        assert!(printed.contains("VMIN=3"));
+
+        after.restore(fd).unwrap();
+        assert!(Snapshot::from(&get_termios(&fd).unwrap())
+            .diff(&after)
+            .is_empty());
+        nix::unistd::close(fd).unwrap();
+        drop(master);
+    }
+}
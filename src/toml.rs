@@ -0,0 +1,756 @@
+//! Module reading and writing config files in TOML.
+//!
+//! Tables and dotted keys are flattened onto the dot notation keys used
+//! everywhere else, so
+//!
+//! [serial]
+//! baud = 9600
+//! match = { usb_vid = "0403" }
+//!
+//! sets `serial.baud` and `serial.match.usb_vid`. Values are kept as the
+//! text they were written as: numbers, booleans and dates as they appear
+//! (less any `_` digit separators), strings unquoted and unescaped, and
+//! arrays as their items joined with `, `. Arrays of tables have no dot
+//! notation equivalent and are not supported.
+
+/// A key set in a TOML file, or to be written to one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Full dot notation key
+    pub key: String,
+    pub value: String,
+    /// From a literal ('single quoted') string, not to be expanded
+    pub literal: bool,
+    /// Items when the value is an array
+    pub items: Vec<String>,
+    /// Line the key is on, from 1
+    pub line: usize,
+}
+
+/// Something wrong with a TOML file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub line: usize,
+    pub column: usize,
+    /// Full key of the value the problem is in, if it got that far
+    pub key: Option<String>,
+    pub message: String,
+}
+
+enum Value {
+    Str(String, bool),
+    /// Numbers, booleans and dates
+    Raw(String),
+    Array(Vec<Value>),
+    Table(Vec<(Vec<String>, Value)>),
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    /// Position the current line starts at
+    line_start: usize,
+    /// Full key of the value being read
+    key: Option<String>,
+    entries: Vec<Entry>,
+}
+
+/// Parse TOML text into flattened entries, skipping lines with problems.
+pub fn parse(text: &str) -> (Vec<Entry>, Vec<Problem>) {
+    let mut p = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+        line_start: 0,
+        key: None,
+        entries: Vec::new(),
+    };
+    let mut problems = Vec::new();
+    let mut table: Vec<String> = Vec::new();
+    loop {
+        p.skip_blank_lines();
+        if p.peek().is_none() {
+            break;
+        }
+        let line = p.line;
+        p.key = None;
+        let res = match p.peek() {
+            Some('[') => p.table_header().map(|t| table = t),
+            _ => p.key_value(&table, line),
+        };
+        if let Err(message) = res.and_then(|_| p.line_end()) {
+            problems.push(Problem {
+                line: p.line,
+                column: p.pos - p.line_start + 1,
+                key: p.key.take(),
+                message,
+            });
+            p.skip_line();
+        }
+    }
+    (p.entries, problems)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.chars.get(self.pos + n).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.line_start = self.pos;
+        }
+        Some(c)
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        match self.peek() {
+            Some(found) if found == c => {
+                self.bump();
+                Ok(())
+            }
+            Some(found) => Err(format!("expected '{}', found '{}'", c, found)),
+            None => Err(format!("expected '{}', found the end of the file", c)),
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, comments and newlines, e.g. between array items
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_space();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.bump() {
+            if c == '\n' {
+                return;
+            }
+        }
+    }
+
+    /// Only a comment can follow a table header or key value pair
+    fn line_end(&mut self) -> Result<()> {
+        self.skip_space();
+        self.skip_comment();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.peek_at(1) == Some('\n') => Ok(()),
+            Some(c) => Err(format!("expected the end of the line, found '{}'", c)),
+        }
+    }
+
+    fn table_header(&mut self) -> Result<Vec<String>> {
+        self.expect('[')?;
+        if self.peek() == Some('[') {
+            return Err("arrays of tables are not supported".to_string());
+        }
+        self.skip_space();
+        let key = self.key()?;
+        self.skip_space();
+        self.expect(']')?;
+        Ok(key)
+    }
+
+    fn key_value(&mut self, table: &[String], line: usize) -> Result<()> {
+        let mut path = table.to_vec();
+        path.extend(self.key()?);
+        self.key = Some(path.join("."));
+        let value = self.assignment()?;
+        self.emit(path, value, line)
+    }
+
+    fn pair(&mut self) -> Result<(Vec<String>, Value)> {
+        let key = self.key()?;
+        Ok((key, self.assignment()?))
+    }
+
+    /// The `= value` following a key
+    fn assignment(&mut self) -> Result<Value> {
+        self.skip_space();
+        self.expect('=')?;
+        self.skip_space();
+        self.value()
+    }
+
+    /// Flatten a value onto dot notation keys
+    fn emit(&mut self, path: Vec<String>, value: Value, line: usize) -> Result<()> {
+        let (value, literal, items) = match value {
+            Value::Str(s, literal) => (s, literal, Vec::new()),
+            Value::Raw(s) => (s, false, Vec::new()),
+            Value::Array(values) => {
+                let mut items = Vec::new();
+                let mut literal = true;
+                for v in values {
+                    match v {
+                        Value::Str(s, l) => {
+                            literal &= l;
+                            items.push(s);
+                        }
+                        Value::Raw(s) => {
+                            literal = false;
+                            items.push(s);
+                        }
+                        _ => return Err("arrays can only hold strings and numbers".to_string()),
+                    }
+                }
+                (items.join(", "), literal && !items.is_empty(), items)
+            }
+            Value::Table(pairs) => {
+                for (key, value) in pairs {
+                    let mut sub = path.clone();
+                    sub.extend(key);
+                    self.emit(sub, value, line)?;
+                }
+                return Ok(());
+            }
+        };
+        self.entries.push(Entry {
+            key: path.join("."),
+            value,
+            literal,
+            items,
+            line,
+        });
+        Ok(())
+    }
+
+    /// A dotted key, e.g. `api.key` or `"api".'key'`
+    fn key(&mut self) -> Result<Vec<String>> {
+        let mut parts = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    self.basic_string()?
+                }
+                Some('\'') => {
+                    self.bump();
+                    self.literal_string()?
+                }
+                _ => {
+                    let start = self.pos;
+                    while let Some(c) = self.peek() {
+                        if !is_bare(c) {
+                            break;
+                        }
+                        self.bump();
+                    }
+                    if start == self.pos {
+                        return Err(match self.peek() {
+                            Some(c) => format!("expected a key, found '{}'", c),
+                            None => "expected a key".to_string(),
+                        });
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            parts.push(part);
+            self.skip_space();
+            if self.peek() != Some('.') {
+                return Ok(parts);
+            }
+            self.bump();
+            self.skip_space();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                self.pos += 3;
+                Ok(Value::Str(self.multiline_basic_string()?, false))
+            }
+            Some('"') => {
+                self.bump();
+                Ok(Value::Str(self.basic_string()?, false))
+            }
+            Some('\'') if self.starts_with("'''") => {
+                self.pos += 3;
+                Ok(Value::Str(self.multiline_literal_string()?, true))
+            }
+            Some('\'') => {
+                self.bump();
+                Ok(Value::Str(self.literal_string()?, true))
+            }
+            Some('[') => {
+                self.bump();
+                self.array()
+            }
+            Some('{') => {
+                self.bump();
+                self.inline_table()
+            }
+            Some(_) => self.raw(),
+            None => Err("expected a value".to_string()),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank_lines();
+            match self.peek() {
+                Some(',') => {
+                    self.bump();
+                }
+                Some(']') => (),
+                _ => return Err("expected ',' or ']' in array".to_string()),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value> {
+        let mut pairs = Vec::new();
+        self.skip_space();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Table(pairs));
+        }
+        loop {
+            self.skip_space();
+            pairs.push(self.pair()?);
+            self.skip_space();
+            match self.bump() {
+                Some(',') => (),
+                Some('}') => return Ok(Value::Table(pairs)),
+                _ => return Err("expected ',' or '}' in inline table".to_string()),
+            }
+        }
+    }
+
+    /// A number, boolean or date, up to whatever ends the value
+    fn raw(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if matches!(c, ',' | ']' | '}' | '#' | '\n' | '\r') {
+                break;
+            }
+            self.bump();
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        let text = text.trim_end();
+        if text == "true" || text == "false" || is_date(text) {
+            return Ok(Value::Raw(text.to_string()));
+        }
+        let number = text.replace('_', "");
+        if is_number(&number) && !text.starts_with('_') && !text.ends_with('_') {
+            return Ok(Value::Raw(number));
+        }
+        self.pos = start;
+        Err(format!("invalid value '{}'", text))
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err("missing closing quote".to_string()),
+                Some('"') => {
+                    self.bump();
+                    return Ok(s);
+                }
+                Some('\\') => {
+                    self.bump();
+                    s.push(self.escape()?);
+                }
+                Some(c) => {
+                    self.bump();
+                    s.push(c);
+                }
+            }
+        }
+    }
+
+    fn multiline_basic_string(&mut self) -> Result<String> {
+        self.skip_first_newline();
+        let mut s = String::new();
+        loop {
+            if self.starts_with("\"\"\"") {
+                self.pos += 3;
+                return Ok(s);
+            }
+            match self.bump() {
+                // A backslash at the end of a line joins it to the next
+                Some('\\') if matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) => {
+                    while let Some(' ' | '\t' | '\r' | '\n') = self.peek() {
+                        self.bump();
+                    }
+                }
+                Some('\\') => s.push(self.escape()?),
+                Some(c) => s.push(c),
+                None => return Err("missing closing \"\"\"".to_string()),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => return Err("missing closing quote".to_string()),
+                Some('\'') => {
+                    self.bump();
+                    return Ok(s);
+                }
+                Some(c) => {
+                    self.bump();
+                    s.push(c);
+                }
+            }
+        }
+    }
+
+    fn multiline_literal_string(&mut self) -> Result<String> {
+        self.skip_first_newline();
+        let mut s = String::new();
+        loop {
+            if self.starts_with("'''") {
+                self.pos += 3;
+                return Ok(s);
+            }
+            match self.bump() {
+                Some(c) => s.push(c),
+                None => return Err("missing closing '''".to_string()),
+            }
+        }
+    }
+
+    /// A newline straight after the opening quotes isn't part of the string
+    fn skip_first_newline(&mut self) {
+        if self.starts_with("\r\n") {
+            self.pos += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.bump();
+        }
+    }
+
+    /// The character an escape sequence stands for, after the backslash
+    fn escape(&mut self) -> Result<char> {
+        let unicode = |p: &mut Parser, len: usize| {
+            let hex: String = (0..len).filter_map(|_| p.bump()).collect();
+            u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("invalid unicode escape '{}'", hex))
+        };
+        if matches!(self.peek(), None | Some('\n')) {
+            return Err("missing closing quote".to_string());
+        }
+        match self.bump() {
+            Some('b') => Ok('\u{8}'),
+            Some('t') => Ok('\t'),
+            Some('n') => Ok('\n'),
+            Some('f') => Ok('\u{c}'),
+            Some('r') => Ok('\r'),
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('u') => unicode(self, 4),
+            Some('U') => unicode(self, 8),
+            Some(c) => Err(format!("invalid escape '\\{}'", c)),
+            None => Err("missing closing quote".to_string()),
+        }
+    }
+}
+
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn is_number(s: &str) -> bool {
+    let unsigned = s.strip_prefix(['+', '-']).unwrap_or(s);
+    if ["inf", "nan"].contains(&unsigned) {
+        return true;
+    }
+    let radix = |prefix: &str, radix: u32| {
+        s.strip_prefix(prefix)
+            .is_some_and(|d| !d.is_empty() && d.chars().all(|c| c.is_digit(radix)))
+    };
+    if radix("0x", 16) || radix("0o", 8) || radix("0b", 2) {
+        return true;
+    }
+    // Decimal integers and floats
+    !unsigned.is_empty()
+        && unsigned.starts_with(|c: char| c.is_ascii_digit())
+        && unsigned.parse::<f64>().is_ok()
+}
+
+/// Dates, times and date-times, e.g. `1979-05-27T07:32:00Z`
+fn is_date(s: &str) -> bool {
+    let digits = |r: std::ops::Range<usize>| {
+        s.get(r)
+            .is_some_and(|d| d.chars().all(|c| c.is_ascii_digit()))
+    };
+    (digits(0..4) && s.get(4..5) == Some("-")) || (digits(0..2) && s.get(2..3) == Some(":"))
+}
+
+/// Write entries as TOML. Keys with no dot go at the top, the rest are
+/// grouped into a table for the first part of their key. Values that
+/// read back the same unquoted, integers and booleans, are left bare.
+pub fn write(entries: &[Entry]) -> String {
+    let leaves: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+    let mut top = Vec::new();
+    let mut tables: Vec<(&str, Vec<&Entry>)> = Vec::new();
+    for entry in entries {
+        match entry.key.split_once('.') {
+            // A key that is also a table can't be written as one
+            Some((table, _)) if !leaves.contains(&table) => {
+                match tables.iter_mut().find(|(t, _)| *t == table) {
+                    Some((_, group)) => group.push(entry),
+                    None => tables.push((table, vec![entry])),
+                }
+            }
+            _ => top.push(entry),
+        }
+    }
+
+    let mut out = String::new();
+    for entry in &top {
+        out.push_str(&format!(
+            "{} = {}\n",
+            write_key(&entry.key, &leaves, ""),
+            write_value(entry)
+        ));
+    }
+    for (table, group) in tables {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&format!("[{}]\n", write_part(table)));
+        let prefix = format!("{}.", table);
+        for entry in group {
+            let rest = &entry.key[prefix.len()..];
+            out.push_str(&format!(
+                "{} = {}\n",
+                write_key(rest, &leaves, &prefix),
+                write_value(entry)
+            ));
+        }
+    }
+    out
+}
+
+/// Write a key as dotted parts, or as one quoted part when a shorter
+/// key is already set to a value, e.g. `baud` and `baud.candidates`.
+fn write_key(key: &str, leaves: &[&str], prefix: &str) -> String {
+    let parts: Vec<&str> = key.split('.').collect();
+    let shadowed = (1..parts.len())
+        .any(|n| leaves.contains(&format!("{}{}", prefix, parts[..n].join(".")).as_str()));
+    match shadowed {
+        true => quote(key),
+        false => parts
+            .iter()
+            .map(|p| write_part(p))
+            .collect::<Vec<String>>()
+            .join("."),
+    }
+}
+
+fn write_part(part: &str) -> String {
+    match !part.is_empty() && part.chars().all(is_bare) {
+        true => part.to_string(),
+        false => quote(part),
+    }
+}
+
+fn write_value(entry: &Entry) -> String {
+    if entry.items.len() > 1 {
+        let items: Vec<String> = entry.items.iter().map(|i| quote(i)).collect();
+        return format!("[{}]", items.join(", "));
+    }
+    let v = &entry.value;
+    let integer = v.strip_prefix('-').unwrap_or(v);
+    let bare = v == "true"
+        || v == "false"
+        || (integer == "0"
+            || (!integer.is_empty()
+                && !integer.starts_with('0')
+                && integer.chars().all(|c| c.is_ascii_digit())));
+    if bare && !entry.literal {
+        v.clone()
+    } else if !entry.literal {
+        quote(v)
+    } else if !v.contains(['\'', '\n', '\r']) {
+        format!("'{}'", v)
+    } else {
+        // Escaped so nothing is expanded when read back
+        quote(&v.replace('$', "$$"))
+    }
+}
+
+/// A basic string with anything that needs it escaped
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(text: &str) -> Vec<(String, String)> {
+        let (entries, problems) = parse(text);
+        assert!(problems.is_empty(), "{:?}", problems);
+        entries.into_iter().map(|e| (e.key, e.value)).collect()
+    }
+
+    fn pair(k: &str, v: &str) -> (String, String) {
+        (k.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+# Station
+title = "Garden"   # trailing comment
+[serial]
+baud = 115_200
+device = '/dev/ttyUSB0'
+match = { usb_vid = 0x0403, "usb_pid" = "6001" }
+"baud.candidates" = [9600,
+  19200, # slow
+]
+
+[db.api]
+key = "a\"b\\cé"
+endpoint = """
+/api/v2/write\
+  ?org=home"""
+[ station . "garden" ]
+started = 1979-05-27T07:32:00Z
+ratio = -1.5e3
+"#;
+        assert_eq!(
+            vec![
+                pair("title", "Garden"),
+                pair("serial.baud", "115200"),
+                pair("serial.device", "/dev/ttyUSB0"),
+                pair("serial.match.usb_vid", "0x0403"),
+                pair("serial.match.usb_pid", "6001"),
+                pair("serial.baud.candidates", "9600, 19200"),
+                pair("db.api.key", "a\"b\\cé"),
+                pair("db.api.endpoint", "/api/v2/write?org=home"),
+                pair("station.garden.started", "1979-05-27T07:32:00Z"),
+                pair("station.garden.ratio", "-1.5e3"),
+            ],
+            flat(text)
+        );
+        let (entries, _) = parse(text);
+        assert!(entries[2].literal);
+        assert!(!entries[1].literal);
+        assert_eq!(vec!["9600", "19200"], entries[5].items);
+        assert_eq!(8, entries[5].line);
+    }
+
+    #[test]
+    fn test_problems() {
+        let text = "a = 1\nb = \"open\nc = what\n[[servers]]\nd = 2 3\ne = 4\n";
+        let (entries, problems) = parse(text);
+        let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(vec!["a", "e"], keys);
+        let found: Vec<(usize, usize)> = problems.iter().map(|p| (p.line, p.column)).collect();
+        assert_eq!(vec![(2, 10), (3, 5), (4, 2), (5, 5)], found);
+        assert_eq!("arrays of tables are not supported", problems[2].message);
+        assert_eq!(Some("b".to_string()), problems[0].key);
+        assert_eq!(None, problems[2].key);
+    }
+
+    #[test]
+    fn test_write() {
+        let entry = |key: &str, value: &str, literal| Entry {
+            key: key.to_string(),
+            value: value.to_string(),
+            literal,
+            items: Vec::new(),
+            line: 0,
+        };
+        let entries = vec![
+            entry("include", "site.conf", false),
+            entry("serial.baud", "9600", false),
+            entry("serial.baud.candidates", "9600, 19200", false),
+            entry("serial.match.usb_vid", "0403", false),
+            entry("db.api.key", "${TOKEN}", false),
+            entry("db.api.endpoint", "/write?a=1#x", true),
+            entry("log.level ", "debug", false),
+        ];
+        let text = write(&entries);
+        assert_eq!(
+            "include = \"site.conf\"
+
+[serial]
+baud = 9600
+\"baud.candidates\" = \"9600, 19200\"
+match.usb_vid = \"0403\"
+
+[db]
+api.key = \"${TOKEN}\"
+api.endpoint = '/write?a=1#x'
+
+[log]
+\"level \" = \"debug\"
+",
+            text
+        );
+        let (read, problems) = parse(&text);
+        assert!(problems.is_empty(), "{:?}", problems);
+        for (e, r) in entries.iter().zip(read.iter()) {
+            assert_eq!((&e.key, &e.value, e.literal), (&r.key, &r.value, r.literal));
+        }
+    }
+}
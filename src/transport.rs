@@ -0,0 +1,184 @@
+//! Module defining what the channel needs from the link to the station.
+//!
+//! A `Transport` is anything bytes can be written to and read back from
+//! with a deadline: a local serial port, a raw TCP socket to a terminal
+//! server (ser2net) or an RFC 2217 session. They share the error type of
+//! `serialport` so the channel handles them all the same way.
+//!
+//! The transport is picked from the device string:
+//!
+//! /dev/ttyUSB0           - local serial port
+//! tcp://host:port        - raw TCP
+//! rfc2217://host:port    - Telnet COM Port Control (RFC 2217)
+//! bt://MAC/channel       - Bluetooth RFCOMM socket
+use crate::serialport::{Error, ErrorKind, ModemStatus, Rate, Result, SerialPort};
+use std::io;
+use std::time::{Duration, Instant};
+
+pub const SCHEME_TCP: &str = "tcp://";
+pub const SCHEME_RFC2217: &str = "rfc2217://";
+pub const SCHEME_BLUETOOTH: &str = "bt://";
+
+pub trait Transport: Send {
+    /// Open the link. Can be called again after `close`.
+    fn open(&mut self) -> Result<()>;
+
+    fn close(&mut self) -> Result<()>;
+
+    /// Read whatever is available into the supplied array, waiting
+    /// until the deadline for at least one byte to arrive.
+    fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize>;
+
+    /// Write bytes from arr to the link
+    fn write(&self, arr: &[u8]) -> Result<usize>;
+
+    /// Discard anything recieved but not yet read, and anything
+    /// queued for sending where the link allows it.
+    fn flush(&self) -> Result<()>;
+
+    /// Discard anything recieved but not yet read, leaving queued
+    /// output to go out.
+    fn flush_input(&self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Wait until everything written has gone out.
+    fn drain(&self) -> Result<()>;
+
+    /// Set the line rate. Takes effect immediately when open.
+    fn set_baud(&mut self, baud: Rate) -> Result<()>;
+
+    /// Set the device. Takes effect the next time the link is opened.
+    fn set_path(&mut self, path: &str);
+
+    fn path(&self) -> &str;
+
+    /// Read the state of the modem control lines.
+    fn modem_status(&self) -> Result<ModemStatus> {
+        Err(unsupported("Modem lines"))
+    }
+
+    /// Clear DTR for the given time then assert it again.
+    fn pulse_dtr(&self, _duration: Duration) -> Result<()> {
+        Err(unsupported("DTR"))
+    }
+
+    /// Fill the supplied array, failing with a timeout if it
+    /// isn't full by the deadline.
+    fn read_exact_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<()> {
+        let mut nbytes = 0;
+        while nbytes < arr.len() {
+            nbytes += self.read_until_deadline(&mut arr[nbytes..], deadline)?;
+        }
+        Ok(())
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Errno(nix::errno::Errno::ENOTTY),
+        &format!("{} not supported by this transport", what),
+    )
+}
+
+/// True if the device names a network transport rather than a tty.
+pub fn is_url(device: &str) -> bool {
+    [SCHEME_TCP, SCHEME_RFC2217, SCHEME_BLUETOOTH]
+        .iter()
+        .any(|scheme| device.starts_with(scheme))
+}
+
+/// Map a socket error onto the serial port errors.
+pub(crate) fn from_io(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            Error::new(ErrorKind::Timeout, "Timeout reached. No bytes read")
+        }
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => Error::new(ErrorKind::Disconnected, &e.to_string()),
+        _ => match e.raw_os_error() {
+            Some(n) => nix::errno::Errno::from_i32(n).into(),
+            None => Error::new(ErrorKind::Unknown, &e.to_string()),
+        },
+    }
+}
+
+impl Transport for SerialPort {
+    fn open(&mut self) -> Result<()> {
+        SerialPort::open(self)
+    }
+    fn close(&mut self) -> Result<()> {
+        SerialPort::close(self)
+    }
+    fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize> {
+        SerialPort::read_until_deadline(self, arr, deadline)
+    }
+    fn write(&self, arr: &[u8]) -> Result<usize> {
+        SerialPort::write(self, arr)
+    }
+    fn flush(&self) -> Result<()> {
+        SerialPort::flush(self)
+    }
+    fn flush_input(&self) -> Result<()> {
+        SerialPort::flush_input(self)
+    }
+    fn drain(&self) -> Result<()> {
+        SerialPort::drain(self)
+    }
+    fn set_baud(&mut self, baud: Rate) -> Result<()> {
+        SerialPort::set_baud(self, baud)
+    }
+    fn set_path(&mut self, path: &str) {
+        SerialPort::set_path(self, path)
+    }
+    fn path(&self) -> &str {
+        SerialPort::path(self)
+    }
+    fn modem_status(&self) -> Result<ModemStatus> {
+        SerialPort::modem_status(self)
+    }
+    fn pulse_dtr(&self, duration: Duration) -> Result<()> {
+        SerialPort::pulse_dtr(self, duration)
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn open(&mut self) -> Result<()> {
+        (**self).open()
+    }
+    fn close(&mut self) -> Result<()> {
+        (**self).close()
+    }
+    fn read_until_deadline(&self, arr: &mut [u8], deadline: Instant) -> Result<usize> {
+        (**self).read_until_deadline(arr, deadline)
+    }
+    fn write(&self, arr: &[u8]) -> Result<usize> {
+        (**self).write(arr)
+    }
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+    fn flush_input(&self) -> Result<()> {
+        (**self).flush_input()
+    }
+    fn drain(&self) -> Result<()> {
+        (**self).drain()
+    }
+    fn set_baud(&mut self, baud: Rate) -> Result<()> {
+        (**self).set_baud(baud)
+    }
+    fn set_path(&mut self, path: &str) {
+        (**self).set_path(path)
+    }
+    fn path(&self) -> &str {
+        (**self).path()
+    }
+    fn modem_status(&self) -> Result<ModemStatus> {
+        (**self).modem_status()
+    }
+    fn pulse_dtr(&self, duration: Duration) -> Result<()> {
+        (**self).pulse_dtr(duration)
+    }
+}